@@ -0,0 +1,996 @@
+use std::collections::{ BTreeMap, HashMap, VecDeque };
+use std::error::Error;
+use std::fmt;
+
+const ROM_ADDR: usize = 0x200;
+const MAX_ADDR: usize = 0x10000;
+
+// Macros expanded within each other, deeper is taken as a macro expanding itself.
+const MAX_MACRO_DEPTH: usize = 16;
+
+/*
+ * Assembler for the Octo language. Besides the instructions it supports
+ * labels, :const, :alias, :macro, :calc, :byte, :org, :call, :unpack,
+ * :next, :breakpoint and the structured if/else/end and loop/while/again.
+ *
+ * As in Octo, if a `main` label is defined after other code, a jump to it
+ * is placed at 0x200.
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AssembleError {}
+
+/// An assembled ROM, loaded at 0x200, with the symbols of its source.
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    pub rom: Vec<u8>,
+    pub labels: BTreeMap<String, u16>,
+    pub breakpoints: Vec<(u16, String)>,    // Address and name of every :breakpoint
+    pub source_map: BTreeMap<u16, usize>,   // Source line of the instruction at each address
+}
+
+impl Program {
+    /// The address of the first instruction assembled from a source line.
+    pub fn line_address(&self, line: usize) -> Option<u16> {
+        self.source_map.iter()
+            .filter(|&(_, &l)| l == line)
+            .map(|(&addr, _)| addr)
+            .next()
+    }
+
+    /// The source line of the instruction at an address.
+    pub fn address_line(&self, addr: u16) -> Option<usize> {
+        self.source_map.get(&addr).cloned()
+    }
+
+    /// The label at an address, if any.
+    pub fn label_at(&self, addr: u16) -> Option<&str> {
+        self.labels.iter()
+            .find(|&(_, &a)| a == addr)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Program, AssembleError> {
+    Assembler::new(source).run()
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+    depth: usize,                           // Macro expansions it comes from
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (number, line) in source.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut start = None;
+        for (column, c) in line.char_indices().chain(Some((line.len(), ' '))) {
+            match (c.is_whitespace(), start) {
+                (true, Some(s)) => {
+                    tokens.push_back(Token {
+                        text: line[s..column].to_string(),
+                        line: number + 1,
+                        column: s + 1,
+                        depth: 0,
+                    });
+                    start = None;
+                }
+                (false, None) => start = Some(column),
+                _ => {}
+            }
+        }
+    }
+    tokens
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+#[derive(Clone, Copy)]
+enum FixupKind {
+    Addr,           // 12 bit address of an nnn opcode
+    Long,           // 16 bit word after F000
+    Unpack(u8),     // 6xkk pair with a nibble and a 12 bit address
+}
+
+struct Fixup {
+    addr: usize,
+    kind: FixupKind,
+    name: Token,
+}
+
+enum Frame {
+    If { jump: usize, token: Token },
+    Loop { start: usize, breaks: Vec<usize>, token: Token },
+}
+
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+#[derive(Clone, Copy)]
+enum Cond {
+    Eq(u8, u8, bool),   // v[x] == (register or byte)
+    Ne(u8, u8, bool),   // v[x] != (register or byte)
+    Key(u8),
+    NotKey(u8),
+}
+
+impl Cond {
+    fn negate(self) -> Self {
+        match self {
+            Cond::Eq(x, y, reg) => Cond::Ne(x, y, reg),
+            Cond::Ne(x, y, reg) => Cond::Eq(x, y, reg),
+            Cond::Key(x) => Cond::NotKey(x),
+            Cond::NotKey(x) => Cond::Key(x),
+        }
+    }
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    last: Token,
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, i64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    frames: Vec<Frame>,
+    next_label: Option<Token>,
+    main_jump: Option<Token>,
+    breakpoints: Vec<(u16, String)>,
+    source_map: BTreeMap<u16, usize>,
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) =>
+            digit.to_digit(16).map(|digit| digit as u8),
+        _ => None,
+    }
+}
+
+const KEYWORDS: [&str; 39] = [
+    ":", ":=", "+=", "-=", "=-", "|=", "&=", "^=", ">>=", "<<=", "==", "!=", "<", ">",
+    "<=", ">=", "key", "-key", "clear", "return", ";", "exit", "lores", "hires", "jump",
+    "jump0", "if", "then", "begin", "else", "end", "loop", "again", "while", "i",
+    "sprite", "random", "delay", "buzzer",
+];
+
+impl Assembler {
+    fn new(source: &str) -> Self {
+        Assembler {
+            tokens: tokenize(source),
+            last: Token { text: String::new(), line: 1, column: 1, depth: 0 },
+            rom: Vec::new(),
+            here: ROM_ADDR,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            frames: Vec::new(),
+            next_label: None,
+            main_jump: None,
+            breakpoints: Vec::new(),
+            source_map: BTreeMap::new(),
+        }
+    }
+
+    fn run(mut self) -> Result<Program, AssembleError> {
+        // Jump to main if anything is emitted before it.
+        let tokens: Vec<&Token> = self.tokens.iter().collect();
+        self.main_jump = tokens.windows(2)
+            .find(|w| w[0].text == ":" && w[1].text == "main")
+            .map(|w| w[1].clone());
+
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+
+        if let Some(frame) = self.frames.pop() {
+            let token = match frame {
+                Frame::If { token, .. } => token,
+                Frame::Loop { token, .. } => token,
+            };
+            return Err(error(&token, format!("`{}` is never closed", token.text)));
+        }
+        if let Some(token) = self.next_label.take() {
+            return Err(error(&token, "`:next` is not followed by an instruction".to_string()));
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            let target = match self.labels.get(&fixup.name.text) {
+                Some(&target) => target as usize,
+                None => return Err(error(&fixup.name, format!("undefined label `{}`", fixup.name.text))),
+            };
+            match fixup.kind {
+                FixupKind::Addr => {
+                    if target > 0xFFF {
+                        return Err(error(&fixup.name, format!("label `{}` is out of the 12 bit range", fixup.name.text)));
+                    }
+                    self.patch(fixup.addr, target);
+                }
+                FixupKind::Long => {
+                    self.rom[fixup.addr - ROM_ADDR] = (target >> 8) as u8;
+                    self.rom[fixup.addr - ROM_ADDR + 1] = target as u8;
+                }
+                FixupKind::Unpack(nibble) => {
+                    self.rom[fixup.addr - ROM_ADDR + 1] = (nibble << 4) | (target >> 8) as u8 & 0x0F;
+                    self.rom[fixup.addr - ROM_ADDR + 3] = target as u8;
+                }
+            }
+        }
+
+        Ok(Program {
+            rom: self.rom,
+            labels: self.labels.into_iter().collect(),
+            breakpoints: self.breakpoints,
+            source_map: self.source_map,
+        })
+    }
+
+    fn next(&mut self) -> Result<Token, AssembleError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.last = token.clone();
+                Ok(token)
+            }
+            None => Err(error(&self.last, "unexpected end of file".to_string())),
+        }
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().map(|token| token.text == text).unwrap_or(false)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AssembleError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(error(&token, format!("expected `{}`, found `{}`", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn main_prologue(&mut self) {
+        if let Some(name) = self.main_jump.take() {
+            self.fixups.push(Fixup { addr: self.here, kind: FixupKind::Addr, name });
+            self.rom.extend_from_slice(&[0x10, 0x00]);
+            self.here += 2;
+        }
+    }
+
+    fn emit(&mut self, byte: u8, token: &Token) -> Result<(), AssembleError> {
+        self.main_prologue();
+        if self.here >= MAX_ADDR {
+            return Err(error(token, "program does not fit in 64 KiB".to_string()));
+        }
+        let offset = self.here - ROM_ADDR;
+        if offset >= self.rom.len() {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit_op(&mut self, opcode: u16, line: usize) {
+        self.main_prologue();
+        if let Some(name) = self.next_label.take() {
+            self.labels.insert(name.text, (self.here + 1) as u16);
+        }
+        if line > 0 {
+            self.source_map.insert(self.here as u16, line);
+        }
+        let offset = self.here - ROM_ADDR;
+        if offset + 2 > self.rom.len() {
+            self.rom.resize(offset + 2, 0);
+        }
+        self.rom[offset] = (opcode >> 8) as u8;
+        self.rom[offset + 1] = opcode as u8;
+        self.here += 2;
+    }
+
+    fn op(&mut self, opcode: u16, token: &Token) -> Result<(), AssembleError> {
+        if self.here + 2 > MAX_ADDR {
+            return Err(error(token, "program does not fit in 64 KiB".to_string()));
+        }
+        self.emit_op(opcode, token.line);
+        Ok(())
+    }
+
+    // Writes the 12 bit address of the nnn opcode at addr.
+    fn patch(&mut self, addr: usize, target: usize) {
+        let offset = addr - ROM_ADDR;
+        self.rom[offset] = (self.rom[offset] & 0xF0) | (target >> 8) as u8 & 0x0F;
+        self.rom[offset + 1] = target as u8;
+    }
+
+    fn define_label(&mut self, name: &Token, value: u16) -> Result<(), AssembleError> {
+        self.check_name(name)?;
+        if self.labels.contains_key(&name.text) {
+            return Err(error(name, format!("label `{}` is already defined", name.text)));
+        }
+        self.labels.insert(name.text.clone(), value);
+        Ok(())
+    }
+
+    fn check_name(&self, name: &Token) -> Result<(), AssembleError> {
+        let text = name.text.as_str();
+        if KEYWORDS.contains(&text) || text.starts_with(':') || parse_register(text).is_some()
+            || parse_number(text).is_some() || text == "{" || text == "}" {
+            return Err(error(name, format!("`{}` cannot be used as a name", text)));
+        }
+        Ok(())
+    }
+
+    fn register(&mut self) -> Result<u8, AssembleError> {
+        let token = self.next()?;
+        self.register_of(&token)
+    }
+
+    fn register_of(&self, token: &Token) -> Result<u8, AssembleError> {
+        match parse_register(&token.text).or_else(|| self.aliases.get(&token.text).cloned()) {
+            Some(register) => Ok(register),
+            None => Err(error(token, format!("expected a register, found `{}`", token.text))),
+        }
+    }
+
+    fn is_register(&self, token: &Token) -> bool {
+        parse_register(&token.text).is_some() || self.aliases.contains_key(&token.text)
+    }
+
+    // A number, a constant or an already defined label.
+    fn number(&mut self, token: &Token) -> Result<i64, AssembleError> {
+        if let Some(value) = parse_number(&token.text) {
+            return Ok(value);
+        }
+        if let Some(&value) = self.constants.get(&token.text) {
+            return Ok(value);
+        }
+        if let Some(&value) = self.labels.get(&token.text) {
+            return Ok(value as i64);
+        }
+        Err(error(token, format!("expected a number, found `{}`", token.text)))
+    }
+
+    fn byte(&mut self) -> Result<u8, AssembleError> {
+        let token = self.next()?;
+        let value = self.number(&token)?;
+        if !(-128..=255).contains(&value) {
+            return Err(error(&token, format!("{} does not fit in a byte", value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AssembleError> {
+        let token = self.next()?;
+        let value = self.number(&token)?;
+        if !(0..=15).contains(&value) {
+            return Err(error(&token, format!("{} does not fit in a nibble", value)));
+        }
+        Ok(value as u8)
+    }
+
+    // Emits an nnn opcode, resolving labels defined later.
+    fn addr_op(&mut self, base: u16, token: &Token) -> Result<(), AssembleError> {
+        let target = self.next()?;
+        let addr = match parse_number(&target.text).or_else(|| self.constants.get(&target.text).cloned()) {
+            Some(addr) => addr,
+            None => match self.labels.get(&target.text) {
+                Some(&addr) => addr as i64,
+                None => {
+                    self.check_name(&target)?;
+                    self.fixups.push(Fixup { addr: self.here, kind: FixupKind::Addr, name: target.clone() });
+                    0
+                }
+            },
+        };
+        if !(0..=0xFFF).contains(&addr) {
+            return Err(error(&target, format!("address {} is out of the 12 bit range", addr)));
+        }
+        self.op(base | addr as u16, token)
+    }
+
+    fn statement(&mut self) -> Result<(), AssembleError> {
+        let token = self.next()?;
+
+        if let Some(body) = self.expand_macro(&token)? {
+            for token in body.into_iter().rev() {
+                self.tokens.push_front(token);
+            }
+            return Ok(());
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next()?;
+                if name.text == "main" {
+                    self.main_jump = None;
+                }
+                self.main_prologue();
+                self.define_label(&name, self.here as u16)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                let value = self.next()?;
+                let value = self.number(&value)?;
+                self.constants.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                let register = self.register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":macro" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                let mut params = Vec::new();
+                while !self.peek_is("{") {
+                    params.push(self.next()?.text);
+                }
+                let body = self.block()?;
+                self.macros.insert(name.text, Macro { params, body });
+            }
+            ":calc" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                let body = self.block()?;
+                let value = self.calc(&body, &token)?;
+                self.constants.insert(name.text, value);
+            }
+            ":byte" => {
+                let value = if self.peek_is("{") {
+                    let body = self.block()?;
+                    self.calc(&body, &token)?
+                } else {
+                    let value = self.next()?;
+                    self.number(&value)?
+                };
+                if !(-128..=255).contains(&value) {
+                    return Err(error(&token, format!("{} does not fit in a byte", value)));
+                }
+                self.emit(value as u8, &token)?;
+            }
+            ":org" => {
+                let value = self.next()?;
+                let addr = self.number(&value)?;
+                // Code moved away from 0x200 still starts with the jump to main.
+                if addr != self.here as i64 {
+                    self.main_prologue();
+                }
+                if addr < self.here as i64 || addr >= MAX_ADDR as i64 {
+                    return Err(error(&value, format!("cannot move the origin back to {}", addr)));
+                }
+                self.here = addr as usize;
+            }
+            ":call" => {
+                self.addr_op(0x2000, &token)?;
+            }
+            ":unpack" => {
+                let nibble = self.nibble()?;
+                let name = self.next()?;
+                let target = match self.labels.get(&name.text).cloned() {
+                    Some(target) => target,
+                    None => {
+                        self.check_name(&name)?;
+                        self.fixups.push(Fixup { addr: self.here, kind: FixupKind::Unpack(nibble), name });
+                        0
+                    }
+                };
+                self.op(0x6000 | (nibble as u16) << 4 | target >> 8 & 0x0F, &token)?;
+                self.op(0x6100 | target & 0xFF, &token)?;
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                if self.labels.contains_key(&name.text) {
+                    return Err(error(&name, format!("label `{}` is already defined", name.text)));
+                }
+                self.next_label = Some(name);
+            }
+            ":breakpoint" => {
+                let name = self.next()?;
+                self.breakpoints.push((self.here as u16, name.text));
+            }
+            "clear" => self.op(0x00E0, &token)?,
+            "return" | ";" => self.op(0x00EE, &token)?,
+            "exit" => self.op(0x00FD, &token)?,
+            "lores" => self.op(0x00FE, &token)?,
+            "hires" => self.op(0x00FF, &token)?,
+            "scroll-left" => self.op(0x00FC, &token)?,
+            "scroll-right" => self.op(0x00FB, &token)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.op(0x00C0 | n as u16, &token)?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.op(0x00D0 | n as u16, &token)?;
+            }
+            "jump" => self.addr_op(0x1000, &token)?,
+            "jump0" => self.addr_op(0xB000, &token)?,
+            "audio" => self.op(0xF002, &token)?,
+            "plane" => {
+                let n = self.nibble()?;
+                if n > 3 {
+                    return Err(error(&token, format!("plane {} does not exist", n)));
+                }
+                self.op(0xF001 | (n as u16) << 8, &token)?;
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.op(0xD000 | (x as u16) << 8 | (y as u16) << 4 | n as u16, &token)?;
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.op(0xF033 | (x as u16) << 8, &token)?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let save = token.text == "save";
+                if self.peek_is("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    let base = if save { 0x5002 } else { 0x5003 };
+                    self.op(base | (x as u16) << 8 | (y as u16) << 4, &token)?;
+                } else {
+                    let base = if save { 0xF055 } else { 0xF065 };
+                    self.op(base | (x as u16) << 8, &token)?;
+                }
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.op(0xF075 | (x as u16) << 8, &token)?;
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.op(0xF085 | (x as u16) << 8, &token)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let low = match token.text.as_str() {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A,
+                };
+                self.op(0xF000 | (x as u16) << 8 | low, &token)?;
+            }
+            "i" => self.index_statement(&token)?,
+            "if" => self.if_statement(&token)?,
+            "else" => {
+                match self.frames.pop() {
+                    Some(Frame::If { jump, token: begin }) => {
+                        let next = self.here;
+                        self.op(0x1000, &token)?;
+                        self.patch(jump, self.here);
+                        self.frames.push(Frame::If { jump: next, token: begin });
+                    }
+                    _ => return Err(error(&token, "`else` without `if ... begin`".to_string())),
+                }
+            }
+            "end" => {
+                match self.frames.pop() {
+                    Some(Frame::If { jump, .. }) => self.patch(jump, self.here),
+                    _ => return Err(error(&token, "`end` without `if ... begin`".to_string())),
+                }
+            }
+            "loop" => {
+                self.frames.push(Frame::Loop { start: self.here, breaks: Vec::new(), token: token.clone() });
+            }
+            "while" => {
+                let cond = self.condition()?;
+                self.skip_when(cond, &token)?;
+                let jump = self.here;
+                self.op(0x1000, &token)?;
+                match self.frames.last_mut() {
+                    Some(Frame::Loop { breaks, .. }) => breaks.push(jump),
+                    _ => return Err(error(&token, "`while` outside of a `loop`".to_string())),
+                }
+            }
+            "again" => {
+                match self.frames.pop() {
+                    Some(Frame::Loop { start, breaks, .. }) => {
+                        self.op(0x1000 | start as u16, &token)?;
+                        for jump in breaks {
+                            self.patch(jump, self.here);
+                        }
+                    }
+                    _ => return Err(error(&token, "`again` without `loop`".to_string())),
+                }
+            }
+            _ if self.is_register(&token) => self.register_statement(&token)?,
+            _ if parse_number(&token.text).is_some() => {
+                let value = parse_number(&token.text).unwrap_or(0);
+                if !(-128..=255).contains(&value) {
+                    return Err(error(&token, format!("{} does not fit in a byte", value)));
+                }
+                self.emit(value as u8, &token)?;
+            }
+            // Any other name calls the subroutine with that label.
+            _ => {
+                self.tokens.push_front(token.clone());
+                self.addr_op(0x2000, &token)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tokens between `{` and the matching `}`.
+    fn block(&mut self) -> Result<Vec<Token>, AssembleError> {
+        self.expect("{")?;
+        let mut depth = 0;
+        let mut body = Vec::new();
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(body),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    fn expand_macro(&mut self, token: &Token) -> Result<Option<Vec<Token>>, AssembleError> {
+        let count = match self.macros.get(&token.text) {
+            Some(m) => m.params.len(),
+            None => return Ok(None),
+        };
+        if token.depth >= MAX_MACRO_DEPTH {
+            return Err(error(token, format!("macro `{}` is expanded more than {} levels deep", token.text, MAX_MACRO_DEPTH)));
+        }
+        let mut args = Vec::new();
+        for _ in 0..count {
+            args.push(self.next()?);
+        }
+        let m = &self.macros[&token.text];
+        let depth = token.depth + 1;
+        let body = m.body.iter().map(|t| {
+            match m.params.iter().position(|param| *param == t.text) {
+                Some(i) => Token { text: args[i].text.clone(), depth, ..t.clone() },
+                None => Token { depth, ..t.clone() },
+            }
+        }).collect();
+        Ok(Some(body))
+    }
+
+    fn index_statement(&mut self, token: &Token) -> Result<(), AssembleError> {
+        let op = self.next()?;
+        match op.text.as_str() {
+            "+=" => {
+                let x = self.register()?;
+                self.op(0xF01E | (x as u16) << 8, token)
+            }
+            ":=" => {
+                if self.peek_is("hex") || self.peek_is("bighex") {
+                    let font = self.next()?;
+                    let x = self.register()?;
+                    let low = if font.text == "hex" { 0x29 } else { 0x30 };
+                    return self.op(0xF000 | (x as u16) << 8 | low, token);
+                }
+                if self.peek_is("long") {
+                    self.next()?;
+                    self.op(0xF000, token)?;
+                    let target = self.next()?;
+                    let addr = match parse_number(&target.text).or_else(|| self.constants.get(&target.text).cloned()) {
+                        Some(addr) => addr,
+                        None => match self.labels.get(&target.text) {
+                            Some(&addr) => addr as i64,
+                            None => {
+                                self.check_name(&target)?;
+                                self.fixups.push(Fixup { addr: self.here, kind: FixupKind::Long, name: target });
+                                0
+                            }
+                        },
+                    };
+                    if !(0..=0xFFFF).contains(&addr) {
+                        return Err(error(token, format!("address {} is out of the 16 bit range", addr)));
+                    }
+                    self.emit((addr >> 8) as u8, token)?;
+                    return self.emit(addr as u8, token);
+                }
+                self.addr_op(0xA000, token)
+            }
+            _ => Err(error(&op, format!("expected `:=` or `+=`, found `{}`", op.text))),
+        }
+    }
+
+    fn register_statement(&mut self, token: &Token) -> Result<(), AssembleError> {
+        let x = self.register_of(token)? as u16;
+        let op = self.next()?;
+        let alu = |low: u16, y: u8| 0x8000 | x << 8 | (y as u16) << 4 | low;
+        match op.text.as_str() {
+            ":=" => {
+                if self.peek_is("random") {
+                    self.next()?;
+                    let kk = self.byte()?;
+                    return self.op(0xC000 | x << 8 | kk as u16, token);
+                }
+                if self.peek_is("delay") {
+                    self.next()?;
+                    return self.op(0xF007 | x << 8, token);
+                }
+                if self.peek_is("key") {
+                    self.next()?;
+                    return self.op(0xF00A | x << 8, token);
+                }
+                match self.operand()? {
+                    Operand::Register(y) => self.op(alu(0x0, y), token),
+                    Operand::Byte(kk) => self.op(0x6000 | x << 8 | kk as u16, token),
+                }
+            }
+            "+=" => match self.operand()? {
+                Operand::Register(y) => self.op(alu(0x4, y), token),
+                Operand::Byte(kk) => self.op(0x7000 | x << 8 | kk as u16, token),
+            },
+            "-=" => match self.operand()? {
+                Operand::Register(y) => self.op(alu(0x5, y), token),
+                Operand::Byte(kk) => self.op(0x7000 | x << 8 | (kk as u16).wrapping_neg() & 0xFF, token),
+            },
+            "|=" | "&=" | "^=" | "=-" | ">>=" | "<<=" => {
+                let y = self.register()?;
+                let low = match op.text.as_str() {
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    "=-" => 0x7,
+                    ">>=" => 0x6,
+                    _ => 0xE,
+                };
+                self.op(alu(low, y), token)
+            }
+            _ => Err(error(&op, format!("unknown operator `{}`", op.text))),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, AssembleError> {
+        let token = self.next()?;
+        if self.is_register(&token) {
+            return Ok(Operand::Register(self.register_of(&token)?));
+        }
+        self.tokens.push_front(token);
+        Ok(Operand::Byte(self.byte()?))
+    }
+
+    fn if_statement(&mut self, token: &Token) -> Result<(), AssembleError> {
+        let cond = self.condition()?;
+        let mode = self.next()?;
+        match mode.text.as_str() {
+            // Skip the next statement unless the condition holds.
+            "then" => self.skip_when(cond.negate(), token),
+            // Jump to `else` or `end` unless the condition holds.
+            "begin" => {
+                self.skip_when(cond, token)?;
+                let jump = self.here;
+                self.op(0x1000, token)?;
+                self.frames.push(Frame::If { jump, token: token.clone() });
+                Ok(())
+            }
+            _ => Err(error(&mode, format!("expected `then` or `begin`, found `{}`", mode.text))),
+        }
+    }
+
+    /*
+     * Parses a condition. The relational operators are computed in vf
+     * with a subtraction, whose flag is 1 when there is no borrow.
+     */
+    fn condition(&mut self) -> Result<Cond, AssembleError> {
+        let x = self.register()?;
+        let op = self.next()?;
+        match op.text.as_str() {
+            "key" => return Ok(Cond::Key(x)),
+            "-key" => return Ok(Cond::NotKey(x)),
+            _ => {}
+        }
+        let (y, reg) = match self.operand()? {
+            Operand::Register(y) => (y, true),
+            Operand::Byte(kk) => (kk, false),
+        };
+        let (subn, flag) = match op.text.as_str() {
+            "==" => return Ok(Cond::Eq(x, y, reg)),
+            "!=" => return Ok(Cond::Ne(x, y, reg)),
+            // vf := y, vf -= x: flag = y >= x
+            ">" => (false, 0),
+            "<=" => (false, 1),
+            // vf := y, vf =- x: flag = x >= y
+            "<" => (true, 0),
+            ">=" => (true, 1),
+            _ => return Err(error(&op, format!("unknown comparison `{}`", op.text))),
+        };
+        if reg {
+            self.op(0x8F00 | (y as u16) << 4, &op)?;
+        } else {
+            self.op(0x6F00 | y as u16, &op)?;
+        }
+        self.op(0x8F00 | (x as u16) << 4 | if subn { 0x7 } else { 0x5 }, &op)?;
+        Ok(Cond::Eq(0xF, flag, false))
+    }
+
+    // Emits the instruction that skips the next one when cond holds.
+    fn skip_when(&mut self, cond: Cond, token: &Token) -> Result<(), AssembleError> {
+        let opcode = match cond {
+            Cond::Eq(x, y, true) => 0x5000 | (x as u16) << 8 | (y as u16) << 4,
+            Cond::Eq(x, kk, false) => 0x3000 | (x as u16) << 8 | kk as u16,
+            Cond::Ne(x, y, true) => 0x9000 | (x as u16) << 8 | (y as u16) << 4,
+            Cond::Ne(x, kk, false) => 0x4000 | (x as u16) << 8 | kk as u16,
+            Cond::Key(x) => 0xE09E | (x as u16) << 8,
+            Cond::NotKey(x) => 0xE0A1 | (x as u16) << 8,
+        };
+        self.op(opcode, token)
+    }
+
+    /*
+     * Evaluates a :calc expression. As in Octo, binary operators have no
+     * precedence and are evaluated right to left, use parentheses to group.
+     */
+    fn calc(&self, tokens: &[Token], at: &Token) -> Result<i64, AssembleError> {
+        let mut position = 0;
+        let value = self.calc_expression(tokens, &mut position, at)?;
+        if position < tokens.len() {
+            return Err(error(&tokens[position], format!("unexpected `{}`", tokens[position].text)));
+        }
+        Ok(value)
+    }
+
+    fn calc_expression(&self, tokens: &[Token], position: &mut usize, at: &Token) -> Result<i64, AssembleError> {
+        let left = self.calc_term(tokens, position, at)?;
+        let op = match tokens.get(*position) {
+            Some(op) if op.text != ")" => op,
+            _ => return Ok(left),
+        };
+        *position += 1;
+        let right = self.calc_expression(tokens, position, at)?;
+        let value = match op.text.as_str() {
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => return Err(error(op, "division by zero".to_string())),
+            "/" => left / right,
+            "%" => left % right,
+            "&" => left & right,
+            "|" => left | right,
+            "^" => left ^ right,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as i64,
+            ">" => (left > right) as i64,
+            "<=" => (left <= right) as i64,
+            ">=" => (left >= right) as i64,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            _ => return Err(error(op, format!("unknown operator `{}`", op.text))),
+        };
+        Ok(value)
+    }
+
+    fn calc_term(&self, tokens: &[Token], position: &mut usize, at: &Token) -> Result<i64, AssembleError> {
+        let token = match tokens.get(*position) {
+            Some(token) => token,
+            None => return Err(error(tokens.last().unwrap_or(at), "incomplete expression".to_string())),
+        };
+        *position += 1;
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expression(tokens, position, at)?;
+                match tokens.get(*position) {
+                    Some(close) if close.text == ")" => {
+                        *position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(error(token, "unbalanced parentheses".to_string())),
+                }
+            }
+            "-" => Ok(-self.calc_term(tokens, position, at)?),
+            "~" => Ok(!self.calc_term(tokens, position, at)?),
+            "!" => Ok((self.calc_term(tokens, position, at)? == 0) as i64),
+            "HERE" => Ok(self.here as i64),
+            _ => {
+                if let Some(value) = parse_number(&token.text) {
+                    return Ok(value);
+                }
+                if let Some(&value) = self.constants.get(&token.text) {
+                    return Ok(value);
+                }
+                if let Some(&value) = self.labels.get(&token.text) {
+                    return Ok(value as i64);
+                }
+                Err(error(token, format!("`{}` is not defined", token.text)))
+            }
+        }
+    }
+}
+
+fn error(token: &Token, message: String) -> AssembleError {
+    AssembleError { line: token.line, column: token.column, message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instructions() {
+        let program = assemble("v0 := 0x12 i := 0x300 sprite v0 v1 5 v2 += v3 delay := v0 return").unwrap();
+        assert_eq!(program.rom, [0x60, 0x12, 0xA3, 0x00, 0xD0, 0x15, 0x82, 0x34, 0xF0, 0x15, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn main_jump_stays_at_0x200() {
+        let program = assemble(": helper return :org 0x300 : main jump helper").unwrap();
+        assert_eq!(&program.rom[..4], &[0x13, 0x00, 0x00, 0xEE]);
+        assert_eq!(&program.rom[0x100..], &[0x12, 0x02]);
+
+        let program = assemble(":org 0x240 : main v0 := 1").unwrap();
+        assert_eq!(&program.rom[..2], &[0x12, 0x40]);
+        assert_eq!(&program.rom[0x40..], &[0x60, 0x01]);
+    }
+
+    #[test]
+    fn macros() {
+        let program = assemble(":macro set r value { r := value }\nset v1 7 set v2 8").unwrap();
+        assert_eq!(program.rom, [0x61, 0x07, 0x62, 0x08]);
+
+        let e = assemble(":macro forever { forever }\nforever").unwrap_err();
+        assert_eq!((e.line, e.column), (1, 18));
+        assert!(e.message.contains("levels deep"), "{}", e.message);
+    }
+
+    #[test]
+    fn errors_have_a_position() {
+        let e = assemble("v0 := 1\n  jump nowhere").unwrap_err();
+        assert_eq!((e.line, e.column), (2, 8));
+        assert_eq!(e.to_string(), "2:8: undefined label `nowhere`");
+    }
+}
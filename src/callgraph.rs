@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::io::{ self, Write };
+
+use crate::instruction::Instruction;
+use crate::json::Json;
+use crate::trace::TraceRecord;
+
+// The caller of the subroutines called outside any other.
+const MAIN: usize = usize::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallKind {
+    Call,
+    Return,
+}
+
+/// A CALL or RET run, `entry` is the address of the subroutine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallEvent {
+    pub kind: CallKind,
+    pub entry: usize,
+    pub site: usize,                        // Address of the CALL or RET
+    pub cycle: u64,
+    pub frame: u64,
+}
+
+/// Totals of a subroutine, in instructions run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallNode {
+    pub calls: u64,
+    pub inclusive: u64,                     // Its instructions and those of its callees
+    pub exclusive: u64,                     // Its own instructions
+}
+
+// A subroutine being run.
+struct Active {
+    entry: usize,
+    instructions: u64,                      // Instruction count when it was called
+    callees: u64,                           // Instructions run by its callees
+}
+
+/*
+ * Call graph built from the CALL and RET instructions of the trace records,
+ * see `Machine::set_tracing`. The call stack is followed from the records,
+ * so a RET without a recorded CALL, after loading a state for instance, is
+ * ignored. Recursive subroutines count their inner calls in their inclusive
+ * totals more than once.
+ */
+#[derive(Default)]
+pub struct CallGraph {
+    stack: Vec<Active>,
+    instructions: u64,
+    main_callees: u64,                      // Instructions run by the subroutines called from MAIN
+    last_cycle: u64,
+    last_frame: u64,
+    pub events: Vec<CallEvent>,
+    pub nodes: BTreeMap<usize, CallNode>,   // Subroutines by entry address
+    pub edges: BTreeMap<(usize, usize), u64>,  // Calls from a caller entry to a callee, MAIN for none
+}
+
+impl CallGraph {
+    pub fn new() -> Self {
+        CallGraph::default()
+    }
+
+    pub fn record(&mut self, records: &[TraceRecord]) {
+        for record in records {
+            self.instructions += 1;
+            self.last_cycle = record.cycle;
+            self.last_frame = record.frame;
+
+            match record.instruction() {
+                Some(Instruction::Call(nnn)) => {
+                    let entry = nnn as usize;
+                    let caller = self.stack.last().map_or(MAIN, |active| active.entry);
+                    *self.edges.entry((caller, entry)).or_insert(0) += 1;
+                    self.stack.push(Active { entry, instructions: self.instructions, callees: 0 });
+                    self.events.push(CallEvent { kind: CallKind::Call, entry, site: record.pc, cycle: record.cycle, frame: record.frame });
+                }
+                Some(Instruction::Ret) => {
+                    if let Some(active) = self.stack.pop() {
+                        let inclusive = self.instructions - active.instructions;
+                        let node = self.nodes.entry(active.entry).or_default();
+                        node.calls += 1;
+                        node.inclusive += inclusive;
+                        node.exclusive += inclusive - active.callees;
+                        match self.stack.last_mut() {
+                            Some(caller) => caller.callees += inclusive,
+                            None => self.main_callees += inclusive,
+                        }
+                        self.events.push(CallEvent { kind: CallKind::Return, entry: active.entry, site: record.pc, cycle: record.cycle, frame: record.frame });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Instructions recorded, the inclusive total of the whole program.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Instructions run outside any subroutine, those of subroutines still running excluded.
+    pub fn main_exclusive(&self) -> u64 {
+        let running = self.stack.first().map_or(0, |active| self.instructions - active.instructions);
+        self.instructions - self.main_callees - running
+    }
+
+    /*
+     * Graphviz graph with a node per subroutine, labelled with its calls
+     * and instruction totals, and an edge per caller labelled with the calls.
+     * Subroutines still running are not counted.
+     */
+    pub fn write_dot(&self, out: &mut dyn Write, symbolize: &dyn Fn(usize) -> Option<String>) -> io::Result<()> {
+        let name = |entry: usize| node_name(entry, symbolize);
+
+        writeln!(out, "digraph calls {{")?;
+        writeln!(out, "    node [shape=box];")?;
+        writeln!(out, "    \"main\" [label=\"main\\ninclusive {}\\nexclusive {}\"];",
+            self.instructions, self.main_exclusive())?;
+        for (&entry, node) in &self.nodes {
+            writeln!(out, "    \"{}\" [label=\"{}\\n{} calls\\ninclusive {}\\nexclusive {}\"];",
+                name(entry), name(entry), node.calls, node.inclusive, node.exclusive)?;
+        }
+        for (&(caller, callee), &calls) in &self.edges {
+            writeln!(out, "    \"{}\" -> \"{}\" [label=\"{}\"];", name(caller), name(callee), calls)?;
+        }
+        writeln!(out, "}}")
+    }
+
+    /*
+     * Chrome trace events, for chrome://tracing or Perfetto: a slice per
+     * call, from its CALL to its RET. Timestamps are machine cycles shown as
+     * microseconds. Subroutines still running end at the last cycle recorded.
+     */
+    pub fn write_chrome_trace(&self, out: &mut dyn Write, symbolize: &dyn Fn(usize) -> Option<String>) -> io::Result<()> {
+        let event = |phase: &str, entry: usize, cycle: u64, args: Json| Json::object(vec![
+            ("name", node_name(entry, symbolize).into()),
+            ("cat", "call".into()),
+            ("ph", phase.into()),
+            ("ts", cycle.into()),
+            ("pid", 1u64.into()),
+            ("tid", 1u64.into()),
+            ("args", args),
+        ]);
+
+        writeln!(out, "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+        let mut open = Vec::new();
+        let mut first = true;
+        for call in &self.events {
+            let phase = match call.kind {
+                CallKind::Call => {
+                    open.push(call.entry);
+                    "B"
+                }
+                CallKind::Return => {
+                    open.pop();
+                    "E"
+                }
+            };
+            let args = Json::object(vec![
+                ("site", format!("{:03X}", call.site).into()),
+                ("frame", call.frame.into()),
+            ]);
+            writeln!(out, "{}{}", if first { "" } else { "," }, event(phase, call.entry, call.cycle, args))?;
+            first = false;
+        }
+        while let Some(entry) = open.pop() {
+            let args = Json::object(vec![("frame", self.last_frame.into())]);
+            writeln!(out, "{}{}", if first { "" } else { "," }, event("E", entry, self.last_cycle, args))?;
+            first = false;
+        }
+        writeln!(out, "]}}")
+    }
+}
+
+fn node_name(entry: usize, symbolize: &dyn Fn(usize) -> Option<String>) -> String {
+    if entry == MAIN {
+        return "main".to_string();
+    }
+    match symbolize(entry) {
+        Some(label) => label,
+        None => format!("sub_{:03X}", entry),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::quirks::Platform;
+
+    // main calls 208, which calls 20E, then loops at 206.
+    const PROGRAM: [u8; 18] = [
+        0x60, 0x01, 0x22, 0x08, 0x70, 0x01, 0x12, 0x06,
+        0x71, 0x01, 0x22, 0x0E, 0x00, 0xEE,
+        0x72, 0x01, 0x00, 0xEE,
+    ];
+
+    fn records(instructions: usize) -> Vec<TraceRecord> {
+        let mut machine = Machine::new(Platform::CosmacVip, Platform::CosmacVip.quirks());
+        machine.load(&PROGRAM).unwrap();
+        machine.set_tracing(true);
+        for _ in 0..instructions {
+            machine.step().unwrap();
+        }
+        machine.take_trace()
+    }
+
+    #[test]
+    fn main_exclusive_excludes_callees() {
+        let mut calls = CallGraph::new();
+        calls.record(&records(10));
+        assert_eq!(calls.instructions(), 10);
+        assert_eq!(calls.main_exclusive(), 5);
+        assert_eq!(calls.nodes.get(&0x208), Some(&CallNode { calls: 1, inclusive: 5, exclusive: 3 }));
+        assert_eq!(calls.nodes.get(&0x20E), Some(&CallNode { calls: 1, inclusive: 2, exclusive: 2 }));
+        assert_eq!(calls.edges.keys().cloned().collect::<Vec<_>>(), vec![(0x208, 0x20E), (MAIN, 0x208)]);
+
+        // Inside 20E, both subroutines are still running.
+        let mut calls = CallGraph::new();
+        calls.record(&records(5));
+        assert_eq!(calls.main_exclusive(), 2);
+        assert!(calls.nodes.is_empty());
+    }
+
+    #[test]
+    fn chrome_trace_closes_running_calls() {
+        let mut calls = CallGraph::new();
+        calls.record(&records(5));
+        let mut out = Vec::new();
+        calls.write_chrome_trace(&mut out, &|_| None).unwrap();
+        let trace = Json::parse(&String::from_utf8(out).unwrap()).unwrap();
+        let phases: Vec<&str> = trace.get("traceEvents").as_array().iter().map(|event| event.get("ph").as_str().unwrap()).collect();
+        assert_eq!(phases, vec!["B", "B", "E", "E"]);
+    }
+}
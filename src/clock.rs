@@ -0,0 +1,63 @@
+use std::thread;
+use std::time::{ Duration, Instant };
+
+pub const FRAMES_PER_SECOND: u32 = 60;
+
+// Beyond this delay the clock gives up catching up, e.g. after the window was dragged.
+const MAX_LAG_FRAMES: u32 = 5;
+
+/*
+ * Paces emulated frames at 60 Hz. Each deadline is the previous deadline
+ * plus one frame, not the wake up time plus one frame, so the time lost to
+ * sleep jitter is made up on the next frames instead of accumulating.
+ */
+pub struct FrameClock {
+    frame: Duration,
+    deadline: Instant,
+    speed: f64,
+}
+
+impl Default for FrameClock {
+    fn default() -> Self {
+        FrameClock::new()
+    }
+}
+
+impl FrameClock {
+    pub fn new() -> Self {
+        FrameClock {
+            frame: Duration::from_secs(1) / FRAMES_PER_SECOND,
+            deadline: Instant::now(),
+            speed: 1.0,
+        }
+    }
+
+    /// Runs frames `speed` times faster than 60 Hz, below 1 for slow motion.
+    pub fn set_speed(&mut self, speed: f64) {
+        if speed > 0.0 && speed != self.speed {
+            self.speed = speed;
+            self.frame = Duration::from_secs_f64(1.0 / (FRAMES_PER_SECOND as f64 * speed));
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Restarts the schedule from now, after the emulation was stopped.
+    pub fn reset(&mut self) {
+        self.deadline = Instant::now();
+    }
+
+    /// Sleeps until the next frame is due.
+    pub fn wait(&mut self) {
+        self.deadline += self.frame;
+
+        let now = Instant::now();
+        if self.deadline > now {
+            thread::sleep(self.deadline - now);
+        } else if now - self.deadline > self.frame * MAX_LAG_FRAMES {
+            self.deadline = now;
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::instruction::Instruction;
+use crate::machine::{ Machine, MachineFault, Register };
+
+/*
+ * Reference traces of another emulator, one line per instruction with the
+ * state before running it:
+ *
+ *   PC:0200 OP:6201 V0:00 V1:00 ... VF:00 I:0000
+ *
+ * Fields are `key:value` or `key=value`, separated by spaces or commas, with
+ * hexadecimal values, optionally prefixed by 0x or $. Keys are PC, OP (or
+ * OPCODE), V0 to VF and I, in any case and order. Missing fields are not
+ * compared and other keys, such as cycle counts or timers, are ignored.
+ * Blank lines and lines starting with # are skipped.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReferenceStep {
+    pub line: usize,                        // In the reference file, 0 for emu8 states
+    pub pc: Option<usize>,
+    pub opcode: Option<u16>,
+    pub v: [Option<u8>; 16],
+    pub i: Option<usize>,
+}
+
+impl ReferenceStep {
+    /// The state of the machine before running its next instruction.
+    pub fn of(machine: &Machine) -> Self {
+        let memory = machine.memory();
+        let pc = machine.register(Register::Pc);
+        let mut v = [None; 16];
+        for (x, value) in v.iter_mut().enumerate() {
+            *value = Some(machine.register(Register::V(x as u8)) as u8);
+        }
+        ReferenceStep {
+            line: 0,
+            pc: Some(pc),
+            opcode: memory.get(pc..pc + 2).map(|bytes| (bytes[0] as u16) << 8 | bytes[1] as u16),
+            v,
+            i: Some(machine.register(Register::I)),
+        }
+    }
+
+    /// Parses a line, `None` for a blank line or a comment.
+    pub fn parse(line: usize, text: &str) -> Result<Option<Self>, ReferenceError> {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') {
+            return Ok(None);
+        }
+
+        let mut step = ReferenceStep { line, ..ReferenceStep::default() };
+        for field in text.split(|c: char| c.is_whitespace() || c == ',').filter(|field| !field.is_empty()) {
+            let (key, value) = match field.find([':', '=']) {
+                Some(n) => (field[..n].to_ascii_uppercase(), &field[n + 1..]),
+                None => continue,
+            };
+            let digits = value.trim_start_matches("0x").trim_start_matches("0X").trim_start_matches('$');
+            let number = || usize::from_str_radix(digits, 16)
+                .map_err(|_| ReferenceError::Syntax(line, format!("{} is not a hexadecimal number", value)));
+
+            match &key[..] {
+                "PC" => step.pc = Some(number()?),
+                "OP" | "OPCODE" => step.opcode = Some(number()? as u16),
+                "I" => step.i = Some(number()?),
+                _ => if let Some(Register::V(x)) = Register::from_name(&key) {
+                    step.v[x as usize] = Some(number()? as u8);
+                },
+            }
+        }
+        if step.pc.is_none() && step.opcode.is_none() && step.i.is_none() && step.v.iter().all(Option::is_none) {
+            return Err(ReferenceError::Syntax(line, "no PC, OP, V0-VF or I field".to_string()));
+        }
+        Ok(Some(step))
+    }
+
+    /// The fields known to both steps that differ.
+    pub fn differences(&self, other: &ReferenceStep) -> Vec<String> {
+        fn differ<T: PartialEq>(a: Option<T>, b: Option<T>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => a != b,
+                _ => false,
+            }
+        }
+
+        let mut fields = Vec::new();
+        if differ(self.pc, other.pc) {
+            fields.push("PC".to_string());
+        }
+        if differ(self.opcode, other.opcode) {
+            fields.push("OP".to_string());
+        }
+        for x in 0..16 {
+            if differ(self.v[x], other.v[x]) {
+                fields.push(format!("V{:X}", x));
+            }
+        }
+        if differ(self.i, other.i) {
+            fields.push("I".to_string());
+        }
+        fields
+    }
+}
+
+impl fmt::Display for ReferenceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut fields = Vec::new();
+        if let Some(pc) = self.pc {
+            fields.push(format!("PC:{:04X}", pc));
+        }
+        if let Some(opcode) = self.opcode {
+            fields.push(format!("OP:{:04X}", opcode));
+        }
+        for (x, value) in self.v.iter().enumerate() {
+            if let Some(value) = value {
+                fields.push(format!("V{:X}:{:02X}", x, value));
+            }
+        }
+        if let Some(i) = self.i {
+            fields.push(format!("I:{:04X}", i));
+        }
+        write!(f, "{}", fields.join(" "))
+    }
+}
+
+#[derive(Debug)]
+pub enum ReferenceError {
+    Io(io::Error),
+    Syntax(usize, String),
+}
+
+impl fmt::Display for ReferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReferenceError::Io(ref e) => write!(f, "{}", e),
+            ReferenceError::Syntax(line, ref message) => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Error for ReferenceError {}
+
+impl From<io::Error> for ReferenceError {
+    fn from(e: io::Error) -> Self {
+        ReferenceError::Io(e)
+    }
+}
+
+pub fn load_reference(path: &Path) -> Result<Vec<ReferenceStep>, ReferenceError> {
+    let text = fs::read_to_string(path)?;
+    let mut steps = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        if let Some(step) = ReferenceStep::parse(n + 1, line)? {
+            steps.push(step);
+        }
+    }
+    Ok(steps)
+}
+
+/// Why the machine and the reference went apart.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Divergence {
+    Mismatch(Vec<String>),                  // The fields that differ
+    Fault(MachineFault),
+    WaitingKey,                             // Fx0A cannot be satisfied without input
+    Halted,
+}
+
+/// The first step where the machine left the reference.
+#[derive(Clone, Debug)]
+pub struct Difference {
+    pub step: usize,                        // Instructions matched before it
+    pub frame: u64,
+    pub cycle: u64,
+    pub divergence: Divergence,
+    pub expected: ReferenceStep,
+    pub actual: ReferenceStep,
+    pub history: Vec<ReferenceStep>,        // States of the machine before it, oldest first
+    pub upcoming: Vec<ReferenceStep>,       // Reference steps after it
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.divergence {
+            Divergence::Mismatch(ref fields) => format!("{} differ", fields.join(", ")),
+            Divergence::Fault(ref fault) => format!("emu8 faulted: {}", fault),
+            Divergence::WaitingKey => "emu8 waits for a key".to_string(),
+            Divergence::Halted => "emu8 halted".to_string(),
+        };
+        writeln!(f, "Diverged at step {} (reference line {}), frame {}, cycle {}: {}.",
+            self.step, self.expected.line, self.frame, self.cycle, reason)?;
+
+        for step in &self.history {
+            writeln!(f, "      {}  {}", step, mnemonic(step))?;
+        }
+        writeln!(f, "emu8  {}  {}", self.actual, mnemonic(&self.actual))?;
+        writeln!(f, "ref   {}  {}", self.expected, mnemonic(&self.expected))?;
+        for step in &self.upcoming {
+            writeln!(f, "ref   {}  {}", step, mnemonic(step))?;
+        }
+
+        // The state before a step is the result of the previous instruction.
+        if let (Divergence::Mismatch(_), Some(last)) = (&self.divergence, self.history.last()) {
+            writeln!(f, "The last instruction run was {} at {:03X}.",
+                mnemonic(last), last.pc.unwrap_or(0))?;
+        }
+        Ok(())
+    }
+}
+
+fn mnemonic(step: &ReferenceStep) -> String {
+    match step.opcode.and_then(Instruction::decode) {
+        Some(instruction) => instruction.to_string(),
+        None => "??".to_string(),
+    }
+}
+
+/*
+ * Runs the machine in lockstep with the reference, comparing its state
+ * before every instruction, and returns the number of matched steps or the
+ * first difference with `context` steps around it. Random numbers cannot
+ * match another emulator, so the register set by RND is taken from the
+ * reference. Frames without input are run while the machine waits for the
+ * display.
+ */
+pub fn compare(machine: &mut Machine, reference: &[ReferenceStep], context: usize) -> Result<usize, Box<Difference>> {
+    let tracing = machine.tracing();
+    machine.set_tracing(true);
+    let result = lockstep(machine, reference, context);
+    machine.set_tracing(tracing);
+    result
+}
+
+fn lockstep(machine: &mut Machine, reference: &[ReferenceStep], context: usize) -> Result<usize, Box<Difference>> {
+    let mut history: VecDeque<ReferenceStep> = VecDeque::with_capacity(context + 1);
+
+    for (step, expected) in reference.iter().enumerate() {
+        let actual = ReferenceStep::of(machine);
+        let divergence = if machine.halted() {
+            Some(Divergence::Halted)
+        } else {
+            let fields = actual.differences(expected);
+            if fields.is_empty() { None } else { Some(Divergence::Mismatch(fields)) }
+        };
+        let divergence = divergence.or_else(|| run_instruction(machine).err());
+
+        if let Some(divergence) = divergence {
+            return Err(Box::new(Difference {
+                step,
+                frame: machine.frames(),
+                cycle: machine.cycles(),
+                divergence,
+                expected: expected.clone(),
+                actual,
+                history: history.into_iter().collect(),
+                upcoming: reference[step + 1..].iter().take(context).cloned().collect(),
+            }));
+        }
+
+        if let (Some(Instruction::Rnd { x, .. }), Some(next)) = (actual.opcode.and_then(Instruction::decode), reference.get(step + 1)) {
+            if let Some(value) = next.v[x as usize] {
+                machine.set_register(Register::V(x), value as usize);
+            }
+        }
+
+        if context > 0 {
+            if history.len() == context {
+                history.pop_front();
+            }
+            history.push_back(actual);
+        }
+    }
+    Ok(reference.len())
+}
+
+// Steps until an instruction runs.
+fn run_instruction(machine: &mut Machine) -> Result<(), Divergence> {
+    loop {
+        machine.step().map_err(Divergence::Fault)?;
+        if !machine.take_trace().is_empty() {
+            return Ok(());
+        }
+        if machine.halted() {
+            return Err(Divergence::Halted);
+        }
+        if machine.waiting_key() {
+            return Err(Divergence::WaitingKey);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Platform;
+
+    // V0 = 5, V1 = random, V2 += V1, V0 += 1, then loops.
+    const PROGRAM: [u8; 10] = [0x60, 0x05, 0xC1, 0xFF, 0x82, 0x14, 0x70, 0x01, 0x12, 0x08];
+
+    const REFERENCE: &str = "
+        # Another emulator, which drew 42 for RND.
+        PC:0200 OP:6005 V0:00 V1:00 V2:00 I:0000
+        PC:0202 OP:C1FF V0:05
+        pc=0x204, op=0x8214, v1=$42, cycles=3
+
+        PC:0206 OP:7001 V2:42
+        PC:0208 OP:1208 V0:06
+    ";
+
+    fn reference(text: &str) -> Vec<ReferenceStep> {
+        text.lines().enumerate().filter_map(|(n, line)| ReferenceStep::parse(n + 1, line).unwrap()).collect()
+    }
+
+    fn machine() -> Machine {
+        let mut machine = Machine::new(Platform::CosmacVip, Platform::CosmacVip.quirks());
+        machine.load(&PROGRAM).unwrap();
+        machine.set_seed(1);
+        machine
+    }
+
+    #[test]
+    fn reference_lines() {
+        let steps = reference(REFERENCE);
+        assert_eq!(steps.len(), 5);
+        assert_eq!(steps[0].to_string(), "PC:0200 OP:6005 V0:00 V1:00 V2:00 I:0000");
+        assert_eq!(steps[2], ReferenceStep { line: 5, pc: Some(0x204), opcode: Some(0x8214), v: {
+            let mut v = [None; 16];
+            v[1] = Some(0x42);
+            v
+        }, i: None });
+
+        assert!(matches!(ReferenceStep::parse(1, "PC:02G0"), Err(ReferenceError::Syntax(1, _))));
+        assert!(matches!(ReferenceStep::parse(2, "cycles:3 DT:0"), Err(ReferenceError::Syntax(2, _))));
+    }
+
+    #[test]
+    fn random_numbers_come_from_the_reference() {
+        assert_eq!(compare(&mut machine(), &reference(REFERENCE), 2).map_err(|difference| difference.to_string()), Ok(5));
+    }
+
+    #[test]
+    fn first_difference() {
+        let text = REFERENCE.replace("V0:06", "V0:07 V2:41");
+        let difference = compare(&mut machine(), &reference(&text), 2).unwrap_err();
+        assert_eq!(difference.step, 4);
+        assert_eq!(difference.divergence, Divergence::Mismatch(vec!["V0".to_string(), "V2".to_string()]));
+        assert_eq!(difference.history.iter().map(|step| step.pc).collect::<Vec<_>>(), vec![Some(0x204), Some(0x206)]);
+        assert!(difference.upcoming.is_empty());
+        assert!(difference.to_string().contains("The last instruction run was ADD V0, 01 at 206."));
+
+        let text = format!("{}\nPC:020A", REFERENCE);
+        let difference = compare(&mut machine(), &reference(&text), 0).unwrap_err();
+        assert_eq!(difference.step, 5);
+        assert_eq!(difference.divergence, Divergence::Mismatch(vec!["PC".to_string()]));
+        assert!(difference.history.is_empty());
+    }
+}
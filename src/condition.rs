@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::debugger::parse_number;
+use crate::machine::{ Machine, Register };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    BitAnd,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Expr {
+    Number(usize),
+    Register(Register),
+    Memory(Box<Expr>),                      // The byte at an address
+    Not(Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+}
+
+/*
+ * A breakpoint condition, such as `v3 == 0x10 && i > 0x300`. Operands are
+ * numbers, registers, labels and `[addr]` for the byte at an address, and
+ * the operators, from the loosest to the tightest, are `||`, `&&`, the
+ * comparisons, `+ -`, `&` and `!`, with parentheses to group. Like in C,
+ * a value is true when it is not zero.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+impl Condition {
+    /// Parses a condition, labels are taken from `symbols`.
+    pub fn parse(text: &str, symbols: &BTreeMap<String, u16>) -> Result<Self, String> {
+        let tokens = tokenize(text, symbols)?;
+        let mut parser = Parser { tokens: &tokens, at: 0, symbols };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.at) {
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Ok(Condition { text: text.trim().to_string(), expr }),
+        }
+    }
+
+    pub fn eval(&self, machine: &Machine) -> bool {
+        eval(&self.expr, machine) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn eval(expr: &Expr, machine: &Machine) -> usize {
+    match *expr {
+        Expr::Number(n) => n,
+        Expr::Register(register) => machine.register(register),
+        Expr::Memory(ref addr) => machine.memory().get(eval(addr, machine)).map_or(0, |&byte| byte as usize),
+        Expr::Not(ref operand) => (eval(operand, machine) == 0) as usize,
+        Expr::Binary(Operator::Or, ref a, ref b) => (eval(a, machine) != 0 || eval(b, machine) != 0) as usize,
+        Expr::Binary(Operator::And, ref a, ref b) => (eval(a, machine) != 0 && eval(b, machine) != 0) as usize,
+        Expr::Binary(operator, ref a, ref b) => {
+            let (a, b) = (eval(a, machine), eval(b, machine));
+            match operator {
+                Operator::Eq => (a == b) as usize,
+                Operator::Ne => (a != b) as usize,
+                Operator::Lt => (a < b) as usize,
+                Operator::Le => (a <= b) as usize,
+                Operator::Gt => (a > b) as usize,
+                Operator::Ge => (a >= b) as usize,
+                Operator::Add => a.wrapping_add(b),
+                Operator::Sub => a.wrapping_sub(b),
+                Operator::BitAnd => a & b,
+                Operator::Or | Operator::And => unreachable!(),
+            }
+        }
+    }
+}
+
+// Words, numbers and operators, longest operators first. Octo labels may
+// contain dashes, which are kept in a word when it names a label.
+fn tokenize(text: &str, symbols: &BTreeMap<String, u16>) -> Result<Vec<String>, String> {
+    const OPERATORS: [&str; 16] = ["||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "&", "!", "(", ")", "[", "]"];
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let length = if let Some(operator) = OPERATORS.iter().find(|&&operator| rest.starts_with(operator)) {
+            operator.len()
+        } else {
+            let end = rest.find(|c: char| !is_word(c)).unwrap_or(rest.len());
+            if end == 0 {
+                return Err(format!("unexpected {}", &rest[..rest.chars().next().map_or(1, char::len_utf8)]));
+            }
+            let label = rest.find(|c: char| !(is_word(c) || c == '-')).unwrap_or(rest.len());
+            (end..=label).rev()
+                .find(|&n| symbols.contains_key(&rest[..n]))
+                .unwrap_or(end)
+        };
+        tokens.push(rest[..length].to_string());
+        rest = rest[length..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    at: usize,
+    symbols: &'a BTreeMap<String, u16>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.at).map(|token| token.as_str())
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.peek() == Some(token) {
+            self.at += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn binary(&mut self, operators: &[(&str, Operator)], operand: fn(&mut Self) -> Result<Expr, String>) -> Result<Expr, String> {
+        let mut expr = operand(self)?;
+        'outer: loop {
+            for &(token, operator) in operators {
+                if self.eat(token) {
+                    expr = Expr::Binary(operator, Box::new(expr), Box::new(operand(self)?));
+                    continue 'outer;
+                }
+            }
+            return Ok(expr);
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        self.binary(&[("||", Operator::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        self.binary(&[("&&", Operator::And)], Parser::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        self.binary(&[
+            ("==", Operator::Eq), ("!=", Operator::Ne),
+            ("<=", Operator::Le), (">=", Operator::Ge),
+            ("<", Operator::Lt), (">", Operator::Gt),
+        ], Parser::sum)
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        self.binary(&[("+", Operator::Add), ("-", Operator::Sub)], Parser::mask)
+    }
+
+    fn mask(&mut self) -> Result<Expr, String> {
+        self.binary(&[("&", Operator::BitAnd)], Parser::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.or()?;
+            return if self.eat(")") { Ok(expr) } else { Err("missing )".to_string()) };
+        }
+        if self.eat("[") {
+            let expr = self.or()?;
+            return if self.eat("]") { Ok(Expr::Memory(Box::new(expr))) } else { Err("missing ]".to_string()) };
+        }
+
+        let token = match self.peek() {
+            Some(token) => token.to_string(),
+            None => return Err("unexpected end of the condition".to_string()),
+        };
+        self.at += 1;
+        if let Some(register) = Register::from_name(&token) {
+            return Ok(Expr::Register(register));
+        }
+        if let Some(&addr) = self.symbols.get(&token) {
+            return Ok(Expr::Number(addr as usize));
+        }
+        match parse_number(&token) {
+            Some(n) => Ok(Expr::Number(n)),
+            None => Err(format!("unknown value {}", token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Platform;
+
+    fn check(text: &str, machine: &Machine) -> bool {
+        let symbols = [("draw".to_string(), 0x300), ("hi-score".to_string(), 0x310)].iter().cloned().collect();
+        Condition::parse(text, &symbols).unwrap_or_else(|e| panic!("{}: {}", text, e)).eval(machine)
+    }
+
+    #[test]
+    fn expressions() {
+        let mut machine = Machine::new(Platform::default(), Platform::default().quirks());
+        machine.set_register(Register::V(3), 0x10);
+        machine.set_register(Register::I, 0x310);
+        machine.poke(0x310, 7).unwrap();
+
+        assert!(check("v3 == 0x10 && i > 0x300", &machine));
+        assert!(!check("v3 != 16", &machine));
+        assert!(check("i == hi-score && [hi-score] == 7", &machine));
+        assert!(check("[i + 0] >= 7 && [i] <= 7 && [i] < 8", &machine));
+        assert!(check("i - draw == 0x10", &machine));
+        assert!(check("v3 & 0x30 == 0x10", &machine));
+        assert!(check("v0 || v3", &machine));
+        assert!(check("!v0 && !(v3 == 0)", &machine));
+        // && binds tighter than ||.
+        assert!(check("v3 == 16 || v0 == 1 && v1 == 1", &machine));
+        assert!(!check("(v3 == 16 || v0 == 1) && v1 == 1", &machine));
+    }
+
+    #[test]
+    fn errors() {
+        let symbols = BTreeMap::new();
+        for text in ["", "v3 ==", "(v3 == 1", "[i", "v3 == 1 )", "v3 $ 1", "nowhere == 1"].iter() {
+            assert!(Condition::parse(text, &symbols).is_err(), "{}", text);
+        }
+        assert_eq!(Condition::parse("  v1 == 2 ", &symbols).map(|condition| condition.to_string()), Ok("v1 == 2".to_string()));
+    }
+}
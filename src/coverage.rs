@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::disasm::Disassembly;
+use crate::quirks::Platform;
+use crate::trace::TraceRecord;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoverageFormat {
+    Listing,                                // Disassembly annotated with the counts
+    Lcov,
+}
+
+impl CoverageFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "listing" | "disasm" => Some(CoverageFormat::Listing),
+            "lcov" | "info" => Some(CoverageFormat::Lcov),
+            _ => None,
+        }
+    }
+}
+
+/*
+ * Instructions run and skip directions taken during a run, collected from
+ * the trace records of the machine, see `Machine::set_tracing`. The ROM is
+ * disassembled when the collector is created so the report shows the code
+ * as loaded, even if the program modifies itself.
+ */
+pub struct Coverage {
+    disassembly: Disassembly,
+    source: Option<(String, BTreeMap<u16, usize>)>,  // Octo source and the line of each address
+    hits: BTreeMap<usize, u64>,             // Times each address was run
+    skips: BTreeMap<usize, (u64, u64)>,     // Times each skip fell through and skipped
+}
+
+impl Coverage {
+    pub fn new(rom: &[u8], platform: Platform) -> Self {
+        Coverage {
+            disassembly: Disassembly::new(rom, platform),
+            source: None,
+            hits: BTreeMap::new(),
+            skips: BTreeMap::new(),
+        }
+    }
+
+    /// Reports lcov lines of an Octo source instead of addresses.
+    pub fn set_source(&mut self, file: &str, source_map: &BTreeMap<u16, usize>) {
+        self.source = Some((file.to_string(), source_map.clone()));
+    }
+
+    pub fn record(&mut self, records: &[TraceRecord]) {
+        for record in records {
+            *self.hits.entry(record.pc).or_insert(0) += 1;
+            if record.instruction().is_some_and(|instruction| instruction.is_skip()) {
+                let skip = self.skips.entry(record.pc).or_insert((0, 0));
+                if record.next_pc == record.pc + 2 {
+                    skip.0 += 1;
+                } else {
+                    skip.1 += 1;
+                }
+            }
+        }
+    }
+
+    /// Times the instruction at an address was run.
+    pub fn hits(&self, addr: usize) -> u64 {
+        self.hits.get(&addr).cloned().unwrap_or(0)
+    }
+
+    /// Times the skip at an address fell through and skipped.
+    pub fn skips(&self, addr: usize) -> (u64, u64) {
+        self.skips.get(&addr).cloned().unwrap_or((0, 0))
+    }
+
+    /// Instructions of the ROM run at least once, and their count.
+    pub fn instructions(&self) -> (usize, usize) {
+        let total = self.disassembly.instructions().count();
+        let run = self.disassembly.instructions().filter(|&(addr, _)| self.hits(addr) > 0).count();
+        (run, total)
+    }
+
+    /// Skip directions taken at least once, and their count.
+    pub fn branches(&self) -> (usize, usize) {
+        let skips: Vec<usize> = self.disassembly.instructions()
+            .filter(|&(_, instruction)| instruction.is_skip())
+            .map(|(addr, _)| addr)
+            .collect();
+        let taken = skips.iter().map(|&addr| {
+            let (through, skipped) = self.skips(addr);
+            (through > 0) as usize + (skipped > 0) as usize
+        }).sum();
+        (taken, skips.len() * 2)
+    }
+
+    /// The listing of the ROM with the count of every instruction and skip direction.
+    pub fn listing(&self) -> String {
+        let mut out = String::new();
+        let _ = self.disassembly.annotate(&mut out, &|start, end| {
+            match self.disassembly.instruction_at(start) {
+                Some(instruction) if instruction.is_skip() && self.hits(start) > 0 => {
+                    let (through, skipped) = self.skips(start);
+                    Some(format!("{}x, fell through {}x, skipped {}x", self.hits(start), through, skipped))
+                }
+                Some(_) => match self.hits(start) {
+                    0 => Some("never run".to_string()),
+                    hits => Some(format!("{}x", hits)),
+                },
+                // Code the static disassembly could not reach, a computed jump for instance.
+                None => {
+                    let hits: u64 = (start..end).map(|addr| self.hits(addr)).sum();
+                    if hits > 0 { Some(format!("data run {}x", hits)) } else { None }
+                }
+            }
+        });
+        out
+    }
+
+    /*
+     * lcov tracefile of the run. Lines are those of the Octo source when
+     * set, or else the addresses of the instructions. Every skip is a block
+     * with two branches: 0 falls through and 1 skips.
+     */
+    pub fn lcov(&self, rom_file: &str) -> String {
+        let (file, lines): (&str, BTreeMap<usize, usize>) = match self.source {
+            Some((ref file, ref source_map)) => (file, self.disassembly.instructions()
+                .filter_map(|(addr, _)| source_map.get(&(addr as u16)).map(|&line| (addr, line)))
+                .collect()),
+            None => (rom_file, self.disassembly.instructions().map(|(addr, _)| (addr, addr)).collect()),
+        };
+
+        let mut out = String::new();
+        let _ = writeln!(out, "TN:\nSF:{}", file);
+
+        let mut branches = (0, 0);
+        let skips = self.disassembly.instructions().filter(|&(_, instruction)| instruction.is_skip());
+        for addr in skips.map(|(addr, _)| addr) {
+            let line = match lines.get(&addr) {
+                Some(&line) => line,
+                None => continue,
+            };
+            let (through, skipped) = self.skips(addr);
+            for (branch, &count) in [through, skipped].iter().enumerate() {
+                if self.hits(addr) == 0 {
+                    let _ = writeln!(out, "BRDA:{},{},{},-", line, addr, branch);
+                } else {
+                    let _ = writeln!(out, "BRDA:{},{},{},{}", line, addr, branch, count);
+                }
+                branches.0 += 1;
+                branches.1 += (count > 0) as usize;
+            }
+        }
+        let _ = writeln!(out, "BRF:{}\nBRH:{}", branches.0, branches.1);
+
+        // A line with several instructions counts as its busiest one.
+        let mut counts: BTreeMap<usize, u64> = BTreeMap::new();
+        for (&addr, &line) in &lines {
+            let count = counts.entry(line).or_insert(0);
+            *count = (*count).max(self.hits(addr));
+        }
+        for (line, count) in &counts {
+            let _ = writeln!(out, "DA:{},{}", line, count);
+        }
+        let _ = writeln!(out, "LF:{}\nLH:{}", counts.len(), counts.values().filter(|&&count| count > 0).count());
+        let _ = writeln!(out, "end_of_record");
+        out
+    }
+
+    pub fn save(&self, path: &Path, format: CoverageFormat, rom_file: &str) -> io::Result<()> {
+        match format {
+            CoverageFormat::Listing => fs::write(path, self.listing()),
+            CoverageFormat::Lcov => fs::write(path, self.lcov(rom_file)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+
+    // V0 += 1, SE V0, 2, JP 200, then loops: the skip falls through once and skips once.
+    const PROGRAM: [u8; 8] = [0x70, 0x01, 0x30, 0x02, 0x12, 0x00, 0x12, 0x06];
+
+    fn coverage() -> Coverage {
+        let mut machine = Machine::new(Platform::CosmacVip, Platform::CosmacVip.quirks());
+        machine.load(&PROGRAM).unwrap();
+        machine.set_tracing(true);
+        for _ in 0..8 {
+            machine.step().unwrap();
+        }
+        let mut coverage = Coverage::new(&PROGRAM, Platform::CosmacVip);
+        coverage.record(&machine.take_trace());
+        coverage
+    }
+
+    #[test]
+    fn skips_in_both_directions() {
+        let coverage = coverage();
+        assert_eq!(coverage.skips(0x202), (1, 1));
+        assert_eq!(coverage.instructions(), (4, 4));
+        assert_eq!(coverage.branches(), (2, 2));
+        assert_eq!(coverage.lcov("game.ch8"), "\
+TN:
+SF:game.ch8
+BRDA:514,514,0,1
+BRDA:514,514,1,1
+BRF:2
+BRH:2
+DA:512,2
+DA:514,2
+DA:516,1
+DA:518,3
+LF:4
+LH:4
+end_of_record
+");
+    }
+
+    #[test]
+    fn source_lines() {
+        let mut coverage = coverage();
+        let source_map = [(0x200, 3), (0x202, 4), (0x204, 4), (0x206, 6)].iter().cloned().collect();
+        coverage.set_source("game.8o", &source_map);
+        let lcov = coverage.lcov("game.ch8");
+        assert!(lcov.starts_with("TN:\nSF:game.8o\nBRDA:4,514,0,1\nBRDA:4,514,1,1\n"));
+        assert!(lcov.contains("DA:3,2\nDA:4,2\nDA:6,3\nLF:3\nLH:3\n"));
+    }
+}
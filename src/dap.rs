@@ -0,0 +1,699 @@
+use std::fs;
+use std::io::{ self, BufRead, BufReader, Read, Write };
+use std::net::{ TcpListener, ToSocketAddrs };
+use std::path::{ Path, PathBuf };
+use std::sync::mpsc::{ self, Receiver, Sender, TryRecvError };
+use std::thread;
+
+use crate::assembler::Program;
+use crate::condition::Condition;
+use crate::debugger::{ parse_number, Action, Debugger, Remote, Stop };
+use crate::json::Json;
+use crate::machine::{ Access, Machine, Register };
+
+// The only thread of the program.
+const THREAD_ID: u64 = 1;
+
+// variablesReference of the scopes.
+const REGISTERS: u64 = 1;
+const TIMERS: u64 = 2;
+const STACK: u64 = 3;
+const MEMORY: u64 = 4;
+
+// Bytes shown in the memory scope, from I.
+const MEMORY_BYTES: usize = 32;
+
+/// The arguments of the launch request.
+#[derive(Clone, Debug, Default)]
+pub struct Launch {
+    pub program: String,            // ROM or .8o source
+    pub platform: Option<String>,
+    pub stop_on_entry: bool,
+}
+
+/*
+ * Debug Adapter Protocol server, for the debuggers of the editors. Requests
+ * are read on their own thread and handled when the frontend polls the
+ * server, so the window keeps running while the editor is idle.
+ *
+ * The session starts with `wait_launch`, which answers `initialize` and
+ * returns the program of the `launch` request. Once the frontend loaded it,
+ * `launched` hands over its symbols, and the editor sets its breakpoints:
+ * source lines of a .8o file are mapped to addresses with the source map of
+ * the assembler, and instruction breakpoints take addresses directly. The
+ * program starts after `configurationDone`.
+ */
+pub struct DapServer {
+    requests: Receiver<Json>,
+    output: Box<dyn Write + Send>,
+    seq: u64,
+    launch_seq: u64,                // Answered once the program is loaded
+    debugger: Debugger,
+    program: Option<Program>,
+    source: Option<PathBuf>,        // The .8o file of the program
+    line_breakpoints: Vec<(usize, Option<Condition>)>,  // Addresses and conditions of the source breakpoints
+    instruction_breakpoints: Vec<usize>,
+    configured: bool,               // configurationDone received
+    stop_on_entry: bool,
+    ended: bool,
+}
+
+impl DapServer {
+    /// Talks to the editor over stdin and stdout, nothing else may be printed to stdout.
+    pub fn stdio() -> Self {
+        DapServer::new(Box::new(io::stdin()), Box::new(io::stdout()))
+    }
+
+    /// Waits for the editor to connect to a TCP address.
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(DapServer::new(Box::new(stream.try_clone()?), Box::new(stream)))
+    }
+
+    fn new(input: Box<dyn Read + Send>, output: Box<dyn Write + Send>) -> Self {
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || read_messages(input, sender));
+
+        let mut debugger = Debugger::new();
+        debugger.interrupt();
+
+        DapServer {
+            requests,
+            output,
+            seq: 1,
+            launch_seq: 0,
+            debugger,
+            program: None,
+            source: None,
+            line_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+            configured: false,
+            stop_on_entry: false,
+            ended: false,
+        }
+    }
+
+    /// Answers the requests until the launch request, `None` if the editor left first.
+    pub fn wait_launch(&mut self) -> io::Result<Option<Launch>> {
+        while let Ok(request) = self.requests.recv() {
+            let command = request.get("command").as_str().unwrap_or("").to_string();
+            let args = request.get("arguments");
+            match command.as_str() {
+                "initialize" => {
+                    let capabilities = Json::object(vec![
+                        ("supportsConfigurationDoneRequest", true.into()),
+                        ("supportsSetVariable", true.into()),
+                        ("supportsEvaluateForHovers", true.into()),
+                        ("supportsReadMemoryRequest", true.into()),
+                        ("supportsInstructionBreakpoints", true.into()),
+                        ("supportsConditionalBreakpoints", true.into()),
+                        ("supportsStepBack", true.into()),
+                        ("supportsTerminateRequest", true.into()),
+                    ]);
+                    self.respond(&request, capabilities)?;
+                }
+                "launch" => {
+                    let program = match args.get("program").as_str() {
+                        Some(program) => program.to_string(),
+                        None => {
+                            self.respond_error(&request, "launch needs a program")?;
+                            continue;
+                        }
+                    };
+                    self.launch_seq = request.get("seq").as_u64().unwrap_or(0);
+                    self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+                    return Ok(Some(Launch {
+                        program,
+                        platform: args.get("platform").as_str().map(String::from),
+                        stop_on_entry: self.stop_on_entry,
+                    }));
+                }
+                "disconnect" => {
+                    self.respond(&request, Json::Null)?;
+                    return Ok(None);
+                }
+                _ => self.respond_error(&request, "no program launched")?,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Answers the launch request once the program is loaded, with its symbols for an assembled source.
+    pub fn launched(&mut self, program: Option<Program>, source: Option<&Path>) -> io::Result<()> {
+        if let Some(ref program) = program {
+            self.debugger.add_symbols(&program.labels);
+        }
+        self.program = program;
+        self.source = source.map(canonical);
+        self.restore_breakpoints();
+
+        let response = self.response(self.launch_seq, "launch", true, None, Json::Null);
+        self.send(response)?;
+        self.send_event("initialized", Json::Null)
+    }
+
+    /// Fails the launch request.
+    pub fn launch_failed(&mut self, message: &str) -> io::Result<()> {
+        let response = self.response(self.launch_seq, "launch", false, Some(message), Json::Null);
+        self.send(response)?;
+        self.send_event("terminated", Json::Null)
+    }
+
+    fn handle(&mut self, machine: &mut Machine, request: &Json) -> io::Result<()> {
+        let command = request.get("command").as_str().unwrap_or("");
+        let args = request.get("arguments");
+
+        match command {
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(args);
+                self.respond(request, body)
+            }
+            "setInstructionBreakpoints" => {
+                for addr in self.instruction_breakpoints.drain(..) {
+                    self.debugger.remove_breakpoint(addr);
+                }
+                let mut breakpoints = Vec::new();
+                for breakpoint in args.get("breakpoints").as_array() {
+                    let addr = breakpoint.get("instructionReference").as_str().and_then(parse_number);
+                    let offset = breakpoint.get("offset").as_u64().unwrap_or(0) as usize;
+                    breakpoints.push(match addr {
+                        Some(addr) => {
+                            self.instruction_breakpoints.push(addr + offset);
+                            Json::object(vec![("verified", true.into())])
+                        }
+                        None => Json::object(vec![("verified", false.into())]),
+                    });
+                }
+                self.restore_breakpoints();
+                self.respond(request, Json::object(vec![("breakpoints", breakpoints.into())]))
+            }
+            "configurationDone" => {
+                self.respond(request, Json::Null)?;
+                self.configured = true;
+                if self.stop_on_entry {
+                    self.send_stopped("entry", None)
+                } else {
+                    self.debugger.resume();
+                    Ok(())
+                }
+            }
+            "threads" => {
+                let thread = Json::object(vec![("id", THREAD_ID.into()), ("name", "CHIP8".into())]);
+                self.respond(request, Json::object(vec![("threads", vec![thread].into())]))
+            }
+            "stackTrace" => {
+                let body = self.stack_trace(machine);
+                self.respond(request, body)
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: u64| Json::object(vec![
+                    ("name", name.into()),
+                    ("variablesReference", reference.into()),
+                    ("expensive", false.into()),
+                ]);
+                let scopes = vec![
+                    scope("Registers", REGISTERS),
+                    scope("Timers", TIMERS),
+                    scope("Stack", STACK),
+                    scope("Memory at I", MEMORY),
+                ];
+                self.respond(request, Json::object(vec![("scopes", scopes.into())]))
+            }
+            "variables" => {
+                let reference = args.get("variablesReference").as_u64().unwrap_or(0);
+                let variables = variables(machine, reference);
+                self.respond(request, Json::object(vec![("variables", variables.into())]))
+            }
+            "setVariable" => {
+                let register = args.get("name").as_str().and_then(Register::from_name);
+                let value = args.get("value").as_str().and_then(parse_number);
+                match (register, value) {
+                    (Some(register), Some(value)) => {
+                        machine.set_register(register, value);
+                        self.debugger.clear_history();
+                        let value = format_register(machine, register);
+                        self.respond(request, Json::object(vec![("value", value.into())]))
+                    }
+                    _ => self.respond_error(request, "expected a register and a number"),
+                }
+            }
+            "continue" => {
+                self.debugger.resume();
+                self.respond(request, Json::object(vec![("allThreadsContinued", true.into())]))
+            }
+            "next" => {
+                self.respond(request, Json::Null)?;
+                match self.debugger.next(machine) {
+                    Some(stop) => self.send_stop(stop),
+                    None => Ok(()),
+                }
+            }
+            "stepIn" => {
+                self.respond(request, Json::Null)?;
+                let stop = self.debugger.step(machine);
+                self.send_stop(stop)
+            }
+            "stepBack" | "reverseContinue" if self.debugger.reverse_disabled().is_some() => {
+                let message = format!("cannot go back {}", self.debugger.reverse_disabled().unwrap_or(""));
+                self.respond_error(request, &message)
+            }
+            "stepBack" | "reverseContinue" => {
+                self.respond(request, Json::Null)?;
+                let stop = if command == "stepBack" {
+                    self.debugger.step_back(machine)
+                } else {
+                    self.debugger.reverse_continue(machine)
+                };
+                match stop {
+                    Some(stop) => self.send_stop(stop),
+                    None => self.send_stopped("step", Some("start of the history")),
+                }
+            }
+            "stepOut" => {
+                self.respond(request, Json::Null)?;
+                // Outside of a subroutine, step out runs the program.
+                if !self.debugger.finish(machine) {
+                    self.debugger.resume();
+                }
+                Ok(())
+            }
+            "pause" => {
+                self.respond(request, Json::Null)?;
+                self.interrupt()
+            }
+            "evaluate" => self.evaluate(machine, request),
+            "readMemory" => {
+                let addr = args.get("memoryReference").as_str().and_then(parse_number);
+                let offset = args.get("offset").as_i64().unwrap_or(0);
+                let count = args.get("count").as_u64().unwrap_or(0);
+                match addr {
+                    Some(addr) => {
+                        let memory = machine.memory();
+                        let (start, end, unreadable) = memory_range(memory.len(), addr, offset, count);
+                        let body = Json::object(vec![
+                            ("address", format!("0x{:X}", start).into()),
+                            ("data", base64(&memory[start..end]).into()),
+                            ("unreadableBytes", unreadable.into()),
+                        ]);
+                        self.respond(request, body)
+                    }
+                    None => self.respond_error(request, "invalid memory reference"),
+                }
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Json::Null)?;
+                self.ended = true;
+                Ok(())
+            }
+            _ => self.respond_error(request, "unsupported request"),
+        }
+    }
+
+    // Maps the lines of the .8o source to the address of their first instruction.
+    fn set_breakpoints(&mut self, args: &Json) -> Json {
+        for (addr, _) in self.line_breakpoints.drain(..) {
+            self.debugger.remove_breakpoint(addr);
+        }
+
+        let same_source = match (args.get("source").get("path").as_str(), self.source.as_ref()) {
+            (Some(path), Some(source)) => canonical(Path::new(path)) == *source,
+            _ => false,
+        };
+
+        let mut breakpoints = Vec::new();
+        for breakpoint in args.get("breakpoints").as_array() {
+            let line = breakpoint.get("line").as_u64().unwrap_or(0) as usize;
+            // A line without code breaks at the next line with some.
+            let location = match self.program {
+                Some(ref program) if same_source => program.source_map.iter()
+                    .filter(|&(_, &l)| l >= line)
+                    .min_by_key(|&(&addr, &l)| (l, addr))
+                    .map(|(&addr, &l)| (addr as usize, l)),
+                _ => None,
+            };
+            let condition = match breakpoint.get("condition").as_str() {
+                Some(text) if !text.trim().is_empty() => Some(self.debugger.parse_condition(text)),
+                _ => None,
+            };
+            breakpoints.push(match (location, condition) {
+                (Some(_), Some(Err(e))) => Json::object(vec![
+                    ("verified", false.into()),
+                    ("message", format!("invalid condition: {}", e).into()),
+                ]),
+                (Some((addr, line)), condition) => {
+                    self.line_breakpoints.push((addr, condition.and_then(Result::ok)));
+                    Json::object(vec![("verified", true.into()), ("line", line.into())])
+                }
+                (None, _) => Json::object(vec![
+                    ("verified", false.into()),
+                    ("message", "no code at this line".into()),
+                ]),
+            });
+        }
+        self.restore_breakpoints();
+
+        Json::object(vec![("breakpoints", breakpoints.into())])
+    }
+
+    // Breakpoints of several kinds may be at the same address, the :breakpoint entries of the source stay.
+    // An unconditional breakpoint wins over a conditional one.
+    fn restore_breakpoints(&mut self) {
+        for (addr, condition) in &self.line_breakpoints {
+            self.debugger.set_conditional_breakpoint(*addr, condition.clone());
+        }
+        for &addr in &self.instruction_breakpoints {
+            self.debugger.set_conditional_breakpoint(addr, None);
+        }
+        if let Some(ref program) = self.program {
+            for &(addr, _) in &program.breakpoints {
+                self.debugger.set_conditional_breakpoint(addr as usize, None);
+            }
+        }
+    }
+
+    // The instruction at pc, then the call of every active subroutine.
+    fn stack_trace(&self, machine: &Machine) -> Json {
+        let mut addrs = vec![machine.register(Register::Pc)];
+        addrs.extend(machine.stack().iter().rev().map(|&ret| ret.saturating_sub(2)));
+
+        let frames: Vec<Json> = addrs.iter().enumerate().map(|(id, &addr)| {
+            let name = self.debugger.symbolize(addr).unwrap_or_else(|| format!("{:03X}", addr));
+            let line = self.program.as_ref().and_then(|program| program.address_line(addr as u16));
+            let mut frame = vec![
+                ("id", id.into()),
+                ("name", name.into()),
+                ("line", line.unwrap_or(0).into()),
+                ("column", 1usize.into()),
+                ("instructionPointerReference", format!("0x{:X}", addr).into()),
+            ];
+            if let (Some(_), Some(source)) = (line, self.source.as_ref()) {
+                frame.push(("source", Json::object(vec![("path", source.to_string_lossy().into_owned().into())])));
+            }
+            Json::object(frame)
+        }).collect();
+
+        let total = frames.len();
+        Json::object(vec![("stackFrames", frames.into()), ("totalFrames", total.into())])
+    }
+
+    // A register, a label, or a debugger command in the debug console.
+    fn evaluate(&mut self, machine: &mut Machine, request: &Json) -> io::Result<()> {
+        let args = request.get("arguments");
+        let expression = args.get("expression").as_str().unwrap_or("").trim().to_string();
+
+        if let Some(register) = Register::from_name(&expression) {
+            let result = format_register(machine, register);
+            return self.respond(request, Json::object(vec![("result", result.into()), ("variablesReference", 0usize.into())]));
+        }
+        if args.get("context").as_str() != Some("repl") {
+            return self.respond_error(request, "not a register");
+        }
+
+        let cycles = machine.cycles();
+        let mut output = Vec::new();
+        let action = self.debugger.command(machine, &expression, &mut output)?;
+        let result = String::from_utf8_lossy(&output).trim_end().to_string();
+        self.respond(request, Json::object(vec![("result", result.into()), ("variablesReference", 0usize.into())]))?;
+
+        match action {
+            Some(Action::Resume) => self.send_event("continued", Json::object(vec![("threadId", THREAD_ID.into())])),
+            Some(Action::Quit) => {
+                self.ended = true;
+                self.send_event("terminated", Json::Null)
+            }
+            // The editor refreshes its views after a step from the console.
+            None if machine.cycles() != cycles => self.send_stopped("step", None),
+            None => Ok(()),
+        }
+    }
+
+    fn send_stop(&mut self, stop: Stop) -> io::Result<()> {
+        match stop {
+            Stop::Breakpoint(_) => self.send_stopped("breakpoint", None),
+            Stop::Watchpoint { addr, access } => {
+                let text = match access {
+                    Access::Read => format!("read of {:03X}", addr),
+                    Access::Write => format!("write to {:03X}", addr),
+                };
+                self.send_stopped("data breakpoint", Some(&text))
+            }
+            Stop::RegisterWatchpoint { register, old, new } => {
+                let text = format!("{} changed from 0x{:X} to 0x{:X}", register, old, new);
+                self.send_stopped("data breakpoint", Some(&text))
+            }
+            Stop::Step => self.send_stopped("step", None),
+            Stop::Interrupted => self.send_stopped("pause", None),
+            Stop::Fault(fault) => self.send_stopped("exception", Some(&fault.to_string())),
+            Stop::Halted => {
+                self.send_event("exited", Json::object(vec![("exitCode", 0usize.into())]))?;
+                self.send_event("terminated", Json::Null)
+            }
+        }
+    }
+
+    fn send_stopped(&mut self, reason: &str, text: Option<&str>) -> io::Result<()> {
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(text) = text {
+            body.push(("text", text.into()));
+        }
+        self.send_event("stopped", Json::object(body))
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        let response = self.response(request.get("seq").as_u64().unwrap_or(0), request.get("command").as_str().unwrap_or(""), true, None, body);
+        self.send(response)
+    }
+
+    fn respond_error(&mut self, request: &Json, message: &str) -> io::Result<()> {
+        let response = self.response(request.get("seq").as_u64().unwrap_or(0), request.get("command").as_str().unwrap_or(""), false, Some(message), Json::Null);
+        self.send(response)
+    }
+
+    fn response(&self, request_seq: u64, command: &str, success: bool, message: Option<&str>, body: Json) -> Json {
+        let mut response = vec![
+            ("type", "response".into()),
+            ("request_seq", request_seq.into()),
+            ("success", success.into()),
+            ("command", command.into()),
+        ];
+        if let Some(message) = message {
+            response.push(("message", message.into()));
+        }
+        if body != Json::Null {
+            response.push(("body", body));
+        }
+        Json::object(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        let mut message = vec![("type", "event".into()), ("event", event.into())];
+        if body != Json::Null {
+            message.push(("body", body));
+        }
+        self.send(Json::object(message))
+    }
+
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        let mut message = message;
+        if let Json::Object(ref mut pairs) = message {
+            pairs.insert(0, ("seq".to_string(), self.seq.into()));
+        }
+        self.seq += 1;
+
+        let text = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+        self.output.flush()
+    }
+}
+
+impl Remote for DapServer {
+    fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    fn poll(&mut self, machine: &mut Machine) -> io::Result<bool> {
+        loop {
+            match self.requests.try_recv() {
+                Ok(request) => self.handle(machine, &request)?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(false),
+            }
+        }
+        Ok(!self.ended)
+    }
+
+    // The program waits for the breakpoints before starting.
+    fn is_stopped(&self) -> bool {
+        !self.configured || self.debugger.stopped().is_some()
+    }
+
+    fn interrupt(&mut self) -> io::Result<()> {
+        if self.is_stopped() {
+            return Ok(());
+        }
+        self.debugger.interrupt();
+        self.send_stopped("pause", None)
+    }
+
+    fn run_frame(&mut self, machine: &mut Machine) -> io::Result<()> {
+        if self.is_stopped() {
+            return Ok(());
+        }
+        if let Some(stop) = self.debugger.run_frame(machine) {
+            self.send_stop(stop)?;
+        }
+        Ok(())
+    }
+}
+
+fn variables(machine: &Machine, reference: u64) -> Vec<Json> {
+    let variable = |name: String, value: String| Json::object(vec![
+        ("name", name.into()),
+        ("value", value.into()),
+        ("variablesReference", 0usize.into()),
+    ]);
+
+    match reference {
+        REGISTERS => {
+            let mut registers: Vec<Register> = (0..16).map(Register::V).collect();
+            registers.extend_from_slice(&[Register::I, Register::Pc, Register::Sp]);
+            registers.into_iter()
+                .map(|register| variable(register.to_string(), format_register(machine, register)))
+                .collect()
+        }
+        TIMERS => [Register::Dt, Register::St].iter()
+            .map(|&register| variable(register.to_string(), format_register(machine, register)))
+            .collect(),
+        STACK => machine.stack().iter().enumerate().rev()
+            .map(|(depth, &ret)| variable(format!("#{}", depth), format!("0x{:03X}", ret)))
+            .collect(),
+        MEMORY => {
+            let memory = machine.memory();
+            let start = machine.register(Register::I).min(memory.len());
+            let end = (start + MEMORY_BYTES).min(memory.len());
+            (start..end).step_by(8).map(|addr| {
+                let bytes: Vec<String> = memory[addr..(addr + 8).min(end)].iter().map(|byte| format!("{:02X}", byte)).collect();
+                variable(format!("0x{:03X}", addr), bytes.join(" "))
+            }).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn format_register(machine: &Machine, register: Register) -> String {
+    let value = machine.register(register);
+    match register {
+        Register::V(_) => format!("0x{:02X} ({})", value, value),
+        Register::I | Register::Pc => format!("0x{:03X}", value),
+        Register::Sp | Register::Dt | Register::St => value.to_string(),
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Reads the Content-Length framed messages until the editor closes the stream.
+fn read_messages(input: Box<dyn Read + Send>, sender: Sender<Json>) {
+    let mut reader = BufReader::new(input);
+    loop {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+
+        let length = match length {
+            Some(length) => length,
+            None => continue,
+        };
+        let mut content = vec![0; length];
+        if reader.read_exact(&mut content).is_err() {
+            return;
+        }
+        let message = match std::str::from_utf8(&content).ok().and_then(Json::parse) {
+            Some(message) => message,
+            None => continue,
+        };
+        if sender.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+/*
+ * The bytes of a memory of `len` bytes read by readMemory, from `offset`
+ * bytes after `addr`, which may be negative, as start..end, with the count
+ * of requested bytes past the end of memory. Bytes requested before address
+ * 0 are not returned.
+ */
+fn memory_range(len: usize, addr: usize, offset: i64, count: u64) -> (usize, usize, u64) {
+    let first = addr as i128 + offset as i128;
+    let last = first + count as i128;
+    let clamp = |at: i128| at.max(0).min(len as i128) as usize;
+    let (start, end) = (clamp(first), clamp(last));
+    let unreadable = (last - first.max(0)).max(0) as u64 - (end - start) as u64;
+    (start, end, unreadable)
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let word = (chunk[0] as u32) << 16
+            | (chunk.get(1).cloned().unwrap_or(0) as u32) << 8
+            | chunk.get(2).cloned().unwrap_or(0) as u32;
+        for n in 0..4 {
+            if n <= chunk.len() {
+                encoded.push(ALPHABET[(word >> (18 - 6 * n)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_ranges() {
+        assert_eq!(memory_range(4096, 0x200, 0, 16), (0x200, 0x210, 0));
+        assert_eq!(memory_range(4096, 0x200, -16, 16), (0x1F0, 0x200, 0));
+        assert_eq!(memory_range(4096, 0xFF8, 0, 16), (0xFF8, 0x1000, 8));
+        assert_eq!(memory_range(4096, 0x200, 0, u64::MAX), (0x200, 0x1000, u64::MAX - 0xE00));
+        assert_eq!(memory_range(4096, 0x200, i64::MAX, 16), (0x1000, 0x1000, 16));
+        assert_eq!(memory_range(4096, usize::MAX, 0, 16), (0x1000, 0x1000, 16));
+        // Bytes before address 0 are skipped.
+        assert_eq!(memory_range(4096, 4, -8, 16), (0, 12, 0));
+        assert_eq!(memory_range(4096, 4, i64::MIN, 16), (0, 0, 0));
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(&[0xFF, 0xEE, 0xDD, 0xCC]), "/+7dzA==");
+    }
+}
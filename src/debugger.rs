@@ -0,0 +1,902 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{ self, BufRead, Write };
+
+use crate::condition::Condition;
+use crate::history::History;
+use crate::instruction::Instruction;
+use crate::machine::{ Access, Machine, MachineFault, Register, Watchpoint };
+
+const HELP: &str = "\
+break, b <addr> [if <cond>]   stop before running the instruction at <addr>,
+                              when <cond> holds, such as v3 == 0x10 && i > 0x300
+delete, d [addr]              remove a breakpoint, or all of them
+info breakpoints, info b      list the breakpoints
+watch <addr> [length]         stop after an instruction writes to memory
+rwatch, awatch <addr> [length]
+                              stop after a read, or any access
+watch <reg>                   stop after an instruction changes a register
+unwatch [addr | reg]          remove the watchpoints at <addr> or on <reg>, or all
+info watchpoints, info w      list the watchpoints
+continue, c                   resume the program
+step, s [count]               run one instruction, or <count>
+next, n                       run one instruction, stepping over calls
+finish, fin                   run until the current subroutine returns
+step-back, sb [count]         go back one instruction, or <count>
+reverse-continue, rc          go back to the last breakpoint or watchpoint hit
+last-write, lw <addr>         go back to the last instruction writing to <addr>
+registers, regs, info r       show the registers
+print, p <reg>                show a register
+set <reg> <value>             change a register
+x <addr> [count]              show <count> bytes of memory, 16 by default
+poke <addr> <byte>...         write bytes to memory
+disassemble, disas [addr] [count]
+                              disassemble around pc, or from <addr>
+backtrace, bt                 show the call stack
+quit, q                       exit emu8
+Addresses and values are decimal, 0x hexadecimal, a register or a label.
+Conditions compare values with == != < <= > >=, combined with && || !,
+and [addr] is the byte at an address.
+Going back works within the last 30 seconds, setting registers or memory
+starts the history over.
+An empty line repeats the last command.
+";
+
+/// A debugger driven by a client over a connection. The frontend polls it
+/// every frame, and runs its frames through it while it is not stopped.
+pub trait Remote {
+    /// Handles the requests received since the last call, false once the client ended the session.
+    fn poll(&mut self, machine: &mut Machine) -> io::Result<bool>;
+
+    /// Whether the program is stopped by the client.
+    fn is_stopped(&self) -> bool;
+
+    /// Stops the program and tells the client.
+    fn interrupt(&mut self) -> io::Result<()>;
+
+    /// Runs the rest of the frame, and tells the client when the program stops.
+    fn run_frame(&mut self, machine: &mut Machine) -> io::Result<()>;
+
+    /// The debugger handling the requests of the client.
+    fn debugger(&mut self) -> &mut Debugger;
+}
+
+/// Why the program stopped and the debugger took over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(usize),
+    Watchpoint { addr: usize, access: Access },
+    RegisterWatchpoint { register: Register, old: usize, new: usize },
+    Step,
+    Interrupted,
+    Fault(MachineFault),
+    Halted,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stop::Breakpoint(addr) => write!(f, "Breakpoint at {:03X}", addr),
+            Stop::Watchpoint { addr, access: Access::Read } => write!(f, "Watchpoint, read of {:03X}", addr),
+            Stop::Watchpoint { addr, access: Access::Write } => write!(f, "Watchpoint, write to {:03X}", addr),
+            Stop::RegisterWatchpoint { register, old, new } =>
+                write!(f, "Watchpoint, {} changed from 0x{:X} to 0x{:X}", register, old, new),
+            Stop::Step => write!(f, "Stopped"),
+            Stop::Interrupted => write!(f, "Interrupted"),
+            Stop::Fault(fault) => write!(f, "FAULT: {}", fault),
+            Stop::Halted => write!(f, "Program exited"),
+        }
+    }
+}
+
+/// What the frontend does when the prompt returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Resume,
+    Quit,
+}
+
+/*
+ * A gdb-style debugger on top of Machine. The frontend runs its frames
+ * through Debugger::run_frame, which checks the breakpoints before every
+ * instruction and their conditions, and hands stdin to Debugger::prompt
+ * while it is stopped. Watchpoints are kept by the machine, which reports
+ * the accesses after the instruction doing them.
+ * `next` over a call and `finish` resume the program and stop once the
+ * call stack is back to the wanted depth. The machine runs through a
+ * History, which takes it back to earlier instructions by running again
+ * from a checkpoint.
+ */
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeMap<usize, Option<Condition>>,
+    symbols: BTreeMap<String, u16>,     // Labels of an assembled .8o source
+    stopped: Option<Stop>,
+    resuming: bool,                     // The breakpoint at pc was already reported
+    return_depth: Option<usize>,        // Stop when the stack is this deep, for next and finish
+    history: History,
+    no_reverse: Option<&'static str>,   // Why the program cannot go back, see disable_reverse
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+
+    /// Labels accepted as addresses and shown in the disassembly.
+    pub fn add_symbols(&mut self, symbols: &BTreeMap<String, u16>) {
+        self.symbols.extend(symbols.iter().map(|(name, &addr)| (name.clone(), addr)));
+    }
+
+    /// Sets a breakpoint, an existing one keeps its condition.
+    pub fn set_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.entry(addr).or_insert(None);
+    }
+
+    /// Sets a breakpoint stopping only when `condition` holds, or always without one.
+    pub fn set_conditional_breakpoint(&mut self, addr: usize, condition: Option<Condition>) {
+        self.breakpoints.insert(addr, condition);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.keys().cloned()
+    }
+
+    /// Parses a condition for `set_conditional_breakpoint`, with the labels of the program.
+    pub fn parse_condition(&self, text: &str) -> Result<Condition, String> {
+        Condition::parse(text, &self.symbols)
+    }
+
+    /// Keeps the program from going back, `reason` completes "Cannot go back ...".
+    pub fn disable_reverse(&mut self, reason: &'static str) {
+        self.no_reverse = Some(reason);
+        self.history.clear();
+    }
+
+    /// Why the program cannot go back, `None` when it can.
+    pub fn reverse_disabled(&self) -> Option<&'static str> {
+        self.no_reverse
+    }
+
+    /// Forgets the execution history, after the registers or the memory were changed.
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    /// Why the program is stopped, `None` while it runs.
+    pub fn stopped(&self) -> Option<Stop> {
+        self.stopped
+    }
+
+    /// Stops the program before its next instruction.
+    pub fn interrupt(&mut self) {
+        self.stop(Stop::Interrupted);
+    }
+
+    pub fn resume(&mut self) {
+        self.stopped = None;
+        self.resuming = true;
+    }
+
+    fn stop(&mut self, reason: Stop) {
+        self.stopped = Some(reason);
+        self.return_depth = None;
+    }
+
+    /*
+     * Runs the rest of the frame, instruction by instruction while there is
+     * something to stop at. Returns the reason when the program stopped
+     * before the end of the frame.
+     */
+    pub fn run_frame(&mut self, machine: &mut Machine) -> Option<Stop> {
+        if self.stopped.is_some() {
+            return self.stopped;
+        }
+
+        if self.breakpoints.is_empty() && self.return_depth.is_none()
+            && machine.watchpoints().is_empty() && machine.register_watchpoints().is_empty() {
+            if let Err(fault) = self.history.run_frame(machine) {
+                self.stop(Stop::Fault(fault));
+            } else if machine.halted() {
+                self.stop(Stop::Halted);
+            }
+            return self.stopped;
+        }
+
+        loop {
+            let pc = machine.register(Register::Pc);
+            if !machine.blocked() {
+                if self.resuming {
+                    self.resuming = false;
+                } else if let Some(condition) = self.breakpoints.get(&pc) {
+                    if condition.as_ref().is_none_or(|condition| condition.eval(machine)) {
+                        self.stop(Stop::Breakpoint(pc));
+                        return self.stopped;
+                    }
+                }
+            }
+
+            if let Err(fault) = self.history.step(machine) {
+                self.stop(Stop::Fault(fault));
+                return self.stopped;
+            }
+            if machine.halted() {
+                self.stop(Stop::Halted);
+                return self.stopped;
+            }
+            if let Some(stop) = watch_stop(machine) {
+                self.stop(stop);
+                return self.stopped;
+            }
+            if let Some(depth) = self.return_depth {
+                if machine.stack().len() <= depth {
+                    self.stop(Stop::Step);
+                    return self.stopped;
+                }
+            }
+            if machine.frame_cycle() == 0 {
+                return None;
+            }
+        }
+    }
+
+    /// Reads and runs commands until the program is resumed or the user quits.
+    pub fn prompt(&mut self, machine: &mut Machine, input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<Action> {
+        if let Some(reason) = self.stopped {
+            writeln!(out, "{} (frame {}, cycle {}).", reason, machine.frames(), machine.cycles())?;
+            self.print_location(machine, out)?;
+        }
+
+        loop {
+            write!(out, "(emu8) ")?;
+            out.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(out)?;
+                return Ok(Action::Quit);
+            }
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string(),
+            };
+            self.last_command = line.clone();
+
+            if let Some(action) = self.command(machine, &line, out)? {
+                return Ok(action);
+            }
+        }
+    }
+
+    /// Runs one command, returns the action when it resumes the program or quits.
+    pub fn command(&mut self, machine: &mut Machine, line: &str, out: &mut dyn Write) -> io::Result<Option<Action>> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(None),
+        };
+
+        match (command, args) {
+            ("break", [addr]) | ("b", [addr]) => match self.value(machine, addr) {
+                Some(addr) => {
+                    self.breakpoints.insert(addr, None);
+                    writeln!(out, "Breakpoint at {:03X}.", addr)?;
+                }
+                None => writeln!(out, "Unknown address {}.", addr)?,
+            },
+            ("break", [addr, "if", ..]) | ("b", [addr, "if", ..]) => {
+                let text = line.split_once(" if ").map_or("", |(_, text)| text);
+                match (self.value(machine, addr), self.parse_condition(text)) {
+                    (Some(addr), Ok(condition)) => {
+                        writeln!(out, "Breakpoint at {:03X} if {}.", addr, condition)?;
+                        self.breakpoints.insert(addr, Some(condition));
+                    }
+                    (None, _) => writeln!(out, "Unknown address {}.", addr)?,
+                    (_, Err(e)) => writeln!(out, "Invalid condition: {}.", e)?,
+                }
+            }
+            ("delete", []) | ("d", []) => {
+                self.breakpoints.clear();
+                writeln!(out, "Deleted all breakpoints.")?;
+            }
+            ("delete", [addr]) | ("d", [addr]) => match self.value(machine, addr) {
+                Some(addr) if self.remove_breakpoint(addr) => writeln!(out, "Deleted breakpoint at {:03X}.", addr)?,
+                _ => writeln!(out, "No breakpoint at {}.", addr)?,
+            },
+            ("info", ["breakpoints"]) | ("info", ["b"]) => {
+                if self.breakpoints.is_empty() {
+                    writeln!(out, "No breakpoints.")?;
+                }
+                for (&addr, condition) in &self.breakpoints {
+                    match condition {
+                        Some(condition) => writeln!(out, "{:03X}{} if {}", addr, self.label_suffix(addr), condition)?,
+                        None => writeln!(out, "{:03X}{}", addr, self.label_suffix(addr))?,
+                    }
+                }
+            }
+            ("watch", [name]) if Register::from_name(name).is_some() => {
+                let register = Register::from_name(name).unwrap_or(Register::I);
+                if register == Register::Pc {
+                    writeln!(out, "PC cannot be watched, use a breakpoint.")?;
+                } else {
+                    machine.add_register_watchpoint(register);
+                    writeln!(out, "Watchpoint on {}.", register)?;
+                }
+            }
+            ("watch", _) | ("rwatch", _) | ("awatch", _) if !args.is_empty() && args.len() <= 2 => {
+                let addr = self.value(machine, args[0]);
+                let length = args.get(1).map_or(Some(1), |length| parse_number(length));
+                match (addr, length) {
+                    (Some(start), Some(length)) if length > 0 => match start.checked_add(length) {
+                        Some(end) => {
+                            let (read, write, kind) = match command {
+                                "watch" => (false, true, "Write"),
+                                "rwatch" => (true, false, "Read"),
+                                _ => (true, true, "Access"),
+                            };
+                            machine.add_watchpoint(Watchpoint { start, end, read, write });
+                            writeln!(out, "{} watchpoint on {:03X}-{:03X}.", kind, start, end - 1)?;
+                        }
+                        None => writeln!(out, "Invalid range {:X} + {:X}.", start, length)?,
+                    },
+                    _ => writeln!(out, "Usage: {} <addr> [length].", command)?,
+                }
+            }
+            ("unwatch", []) => {
+                for watchpoint in machine.watchpoints().to_vec() {
+                    machine.remove_watchpoint(&watchpoint);
+                }
+                for register in machine.register_watchpoints().to_vec() {
+                    machine.remove_register_watchpoint(register);
+                }
+                writeln!(out, "Deleted all watchpoints.")?;
+            }
+            ("unwatch", [target]) => {
+                let removed = match Register::from_name(target) {
+                    Some(register) => machine.remove_register_watchpoint(register),
+                    None => match self.value(machine, target) {
+                        Some(addr) => {
+                            let watchpoints: Vec<Watchpoint> = machine.watchpoints().iter()
+                                .filter(|watchpoint| watchpoint.start == addr)
+                                .cloned()
+                                .collect();
+                            for watchpoint in &watchpoints {
+                                machine.remove_watchpoint(watchpoint);
+                            }
+                            !watchpoints.is_empty()
+                        }
+                        None => false,
+                    },
+                };
+                if removed {
+                    writeln!(out, "Deleted the watchpoints on {}.", target)?;
+                } else {
+                    writeln!(out, "No watchpoint on {}.", target)?;
+                }
+            }
+            ("info", ["watchpoints"]) | ("info", ["w"]) => {
+                if machine.watchpoints().is_empty() && machine.register_watchpoints().is_empty() {
+                    writeln!(out, "No watchpoints.")?;
+                }
+                for watchpoint in machine.watchpoints() {
+                    let kind = match (watchpoint.read, watchpoint.write) {
+                        (true, true) => "access",
+                        (true, false) => "read",
+                        _ => "write",
+                    };
+                    writeln!(out, "{:03X}-{:03X} {}{}", watchpoint.start, watchpoint.end - 1, kind, self.label_suffix(watchpoint.start))?;
+                }
+                for register in machine.register_watchpoints() {
+                    writeln!(out, "{} change", register)?;
+                }
+            }
+            ("continue", []) | ("c", []) => {
+                self.resume();
+                return Ok(Some(Action::Resume));
+            }
+            ("step", _) | ("s", _) => {
+                let count = match args.first() {
+                    Some(count) => match parse_number(count) {
+                        Some(count) => count,
+                        None => {
+                            writeln!(out, "Invalid count {}.", count)?;
+                            return Ok(None);
+                        }
+                    },
+                    None => 1,
+                };
+                for _ in 0..count {
+                    let stop = self.step(machine);
+                    if stop != Stop::Step {
+                        writeln!(out, "{}.", stop)?;
+                        break;
+                    }
+                }
+                self.print_location(machine, out)?;
+            }
+            ("next", []) | ("n", []) => match self.next(machine) {
+                Some(stop) => {
+                    if stop != Stop::Step {
+                        writeln!(out, "{}.", stop)?;
+                    }
+                    self.print_location(machine, out)?;
+                }
+                None => return Ok(Some(Action::Resume)),
+            },
+            ("finish", []) | ("fin", []) => {
+                if self.finish(machine) {
+                    return Ok(Some(Action::Resume));
+                }
+                writeln!(out, "Not inside a subroutine.")?;
+            }
+            ("step-back", _) | ("sb", _) | ("reverse-continue", []) | ("rc", []) | ("last-write", [_]) | ("lw", [_])
+                if self.no_reverse.is_some() => {
+                writeln!(out, "Cannot go back {}.", self.no_reverse.unwrap_or(""))?;
+            }
+            ("step-back", _) | ("sb", _) if args.len() <= 1 => {
+                let count = match args.first().map_or(Some(1), |count| parse_number(count)) {
+                    Some(count) => count,
+                    None => {
+                        writeln!(out, "Usage: step-back [count].")?;
+                        return Ok(None);
+                    }
+                };
+                for _ in 0..count {
+                    if self.step_back(machine).is_none() {
+                        writeln!(out, "No more history, at frame {}, cycle {}.", machine.frames(), machine.cycles())?;
+                        break;
+                    }
+                }
+                self.print_location(machine, out)?;
+            }
+            ("reverse-continue", []) | ("rc", []) => {
+                match self.reverse_continue(machine) {
+                    Some(stop) => writeln!(out, "{} (frame {}, cycle {}).", stop, machine.frames(), machine.cycles())?,
+                    None => writeln!(out, "No more history, at frame {}, cycle {}.", machine.frames(), machine.cycles())?,
+                }
+                self.print_location(machine, out)?;
+            }
+            ("last-write", [addr]) | ("lw", [addr]) => match self.value(machine, addr) {
+                Some(addr) if addr >= machine.memory().len() => writeln!(out, "Address {:X} is out of memory.", addr)?,
+                Some(addr) => {
+                    match self.last_write(machine, addr) {
+                        Some(stop) => writeln!(out, "{} (frame {}, cycle {}).", stop, machine.frames(), machine.cycles())?,
+                        None => writeln!(out, "No write to {:03X} in the history, at frame {}, cycle {}.", addr, machine.frames(), machine.cycles())?,
+                    }
+                    self.print_location(machine, out)?;
+                }
+                None => writeln!(out, "Unknown address {}.", addr)?,
+            },
+            ("registers", []) | ("regs", []) | ("info", ["registers"]) | ("info", ["r"]) => {
+                write!(out, "{}", machine.dump_registers())?;
+                writeln!(out, "DT: {:>3} | ST: {:>3} | frame {} | cycle {}",
+                    machine.register(Register::Dt), machine.register(Register::St), machine.frames(), machine.cycles())?;
+            }
+            ("print", [name]) | ("p", [name]) => match Register::from_name(name) {
+                Some(register) => {
+                    let value = machine.register(register);
+                    writeln!(out, "{} = 0x{:X} ({})", register, value, value)?;
+                }
+                None => writeln!(out, "Unknown register {}.", name)?,
+            },
+            ("set", [name, value]) | ("set", [name, "=", value]) => {
+                match (Register::from_name(name), self.value(machine, value)) {
+                    (Some(register), Some(value)) => {
+                        machine.set_register(register, value);
+                        self.history.clear();
+                        let value = machine.register(register);
+                        writeln!(out, "{} = 0x{:X} ({})", register, value, value)?;
+                    }
+                    (None, _) => writeln!(out, "Unknown register {}.", name)?,
+                    (_, None) => writeln!(out, "Invalid value {}.", value)?,
+                }
+            }
+            ("x", _) if !args.is_empty() && args.len() <= 2 => {
+                let addr = self.value(machine, args[0]);
+                let count = args.get(1).map_or(Some(16), |count| parse_number(count));
+                match (addr, count) {
+                    (Some(addr), Some(count)) => self.print_memory(machine, addr, count, out)?,
+                    _ => writeln!(out, "Usage: x <addr> [count].")?,
+                }
+            }
+            ("poke", _) if args.len() >= 2 => {
+                let addr = self.value(machine, args[0]);
+                let bytes: Option<Vec<usize>> = args[1..].iter().map(|byte| parse_number(byte)).collect();
+                match (addr, bytes) {
+                    (Some(addr), Some(bytes)) => {
+                        self.history.clear();
+                        for (offset, &byte) in bytes.iter().enumerate() {
+                            let poked = match addr.checked_add(offset) {
+                                Some(addr) => machine.poke(addr, byte as u8).map_err(|fault| format!("FAULT: {}.", fault)),
+                                None => Err(format!("Invalid range {:X} + {:X}.", addr, offset)),
+                            };
+                            if let Err(message) = poked {
+                                writeln!(out, "{}", message)?;
+                                break;
+                            }
+                        }
+                    }
+                    _ => writeln!(out, "Usage: poke <addr> <byte>...")?,
+                }
+            }
+            ("disassemble", _) | ("disas", _) if args.len() <= 2 => {
+                let pc = machine.register(Register::Pc);
+                let start = match args.first() {
+                    Some(addr) => self.value(machine, addr),
+                    // A few instructions before pc, assuming they are 2 bytes long.
+                    None => Some(pc.saturating_sub(8)),
+                };
+                let count = args.get(1).map_or(Some(10), |count| parse_number(count));
+                match (start, count) {
+                    (Some(start), Some(count)) => self.print_disassembly(machine, start, count, out)?,
+                    _ => writeln!(out, "Usage: disassemble [addr] [count].")?,
+                }
+            }
+            ("backtrace", []) | ("bt", []) => {
+                let pc = machine.register(Register::Pc);
+                writeln!(out, "#0  {:03X}{}", pc, self.label_suffix(pc))?;
+                // Each return address follows the call of the frame above.
+                for (depth, &ret) in machine.stack().iter().rev().enumerate() {
+                    let call = ret.saturating_sub(2);
+                    writeln!(out, "#{}  {:03X}{}", depth + 1, call, self.label_suffix(call))?;
+                }
+            }
+            ("help", []) | ("h", []) => write!(out, "{}", HELP)?,
+            ("quit", []) | ("q", []) => return Ok(Some(Action::Quit)),
+            _ => writeln!(out, "Unknown command {}, try help.", line)?,
+        }
+
+        Ok(None)
+    }
+
+    /// Runs one instruction and stays stopped, returns why it stopped.
+    pub fn step(&mut self, machine: &mut Machine) -> Stop {
+        let stop = match step_instruction(&mut self.history, machine) {
+            Err(fault) => Stop::Fault(fault),
+            Ok(()) if machine.halted() => Stop::Halted,
+            Ok(()) => watch_stop(machine).unwrap_or(Stop::Step),
+        };
+        self.stop(stop);
+        stop
+    }
+
+    /// Steps over a call by resuming until it returns, returns `None` then. Any
+    /// other instruction is stepped, and the stop is returned.
+    pub fn next(&mut self, machine: &mut Machine) -> Option<Stop> {
+        let pc = machine.register(Register::Pc);
+        match self.decode(machine, pc) {
+            Some(Instruction::Call(_)) if !machine.blocked() => {
+                self.resume();
+                self.return_depth = Some(machine.stack().len());
+                None
+            }
+            _ => Some(self.step(machine)),
+        }
+    }
+
+    /// Resumes until the current subroutine returns, false outside of a subroutine.
+    pub fn finish(&mut self, machine: &Machine) -> bool {
+        let depth = machine.stack().len();
+        if depth == 0 {
+            return false;
+        }
+        self.resume();
+        self.return_depth = Some(depth - 1);
+        true
+    }
+
+    /*
+     * Goes back to the previous instruction, and stays stopped before it.
+     * Returns `None` at the start of the history.
+     */
+    pub fn step_back(&mut self, machine: &mut Machine) -> Option<Stop> {
+        let found = self.history.seek_back(machine, &mut |_| true, &mut |_| false);
+        self.stop(Stop::Step);
+        found.map(|_| Stop::Step)
+    }
+
+    /*
+     * Goes back to the last instruction before the current one that hits
+     * a breakpoint or a watchpoint, and stays stopped before it, so the
+     * next instruction is the one that accessed the memory or changed the
+     * register. Returns the stop, or `None` at the start of the history.
+     */
+    pub fn reverse_continue(&mut self, machine: &mut Machine) -> Option<Stop> {
+        let breakpoints = &self.breakpoints;
+        let hit = Cell::new(None);
+        let found = self.history.seek_back(machine,
+            &mut |machine| {
+                let pc = machine.register(Register::Pc);
+                let stop = breakpoints.get(&pc)
+                    .filter(|condition| condition.as_ref().is_none_or(|condition| condition.eval(machine)))
+                    .map(|_| Stop::Breakpoint(pc));
+                hit.set(stop.or(hit.get()));
+                stop.is_some()
+            },
+            &mut |machine| {
+                let stop = watch_stop(machine);
+                hit.set(stop.or(hit.get()));
+                stop.is_some()
+            });
+        let stop = found.and(hit.get());
+        self.stop(stop.unwrap_or(Stop::Step));
+        stop
+    }
+
+    /// Goes back to the last instruction writing to `addr`, and stays stopped before it.
+    pub fn last_write(&mut self, machine: &mut Machine, addr: usize) -> Option<Stop> {
+        let watchpoints = machine.watchpoints().to_vec();
+        let registers = machine.register_watchpoints().to_vec();
+        for watchpoint in &watchpoints {
+            machine.remove_watchpoint(watchpoint);
+        }
+        for &register in &registers {
+            machine.remove_register_watchpoint(register);
+        }
+
+        let write = Watchpoint { start: addr, end: addr.saturating_add(1), read: false, write: true };
+        machine.add_watchpoint(write);
+        let found = self.history.seek_back(machine, &mut |_| false, &mut |machine| machine.take_watch_hit().is_some());
+        machine.remove_watchpoint(&write);
+
+        for watchpoint in watchpoints {
+            machine.add_watchpoint(watchpoint);
+        }
+        for register in registers {
+            machine.add_register_watchpoint(register);
+        }
+
+        let stop = Stop::Watchpoint { addr, access: Access::Write };
+        self.stop(if found.is_some() { stop } else { Stop::Step });
+        found.map(|_| stop)
+    }
+
+    fn value(&self, machine: &Machine, token: &str) -> Option<usize> {
+        if let Some(register) = Register::from_name(token) {
+            return Some(machine.register(register));
+        }
+        if let Some(&addr) = self.symbols.get(token) {
+            return Some(addr as usize);
+        }
+        parse_number(token)
+    }
+
+    fn decode(&self, machine: &Machine, addr: usize) -> Option<Instruction> {
+        Instruction::decode_at(machine.memory(), addr)
+            .filter(|instruction| instruction.supported_by(machine.platform()))
+    }
+
+    fn label_at(&self, addr: usize) -> Option<&str> {
+        self.symbols.iter()
+            .find(|&(_, &label_addr)| label_addr as usize == addr)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The address relative to the closest label at or before it, such as `draw+4`.
+    pub fn symbolize(&self, addr: usize) -> Option<String> {
+        match self.symbols.iter().filter(|&(_, &label)| label as usize <= addr).max_by_key(|&(_, &label)| label) {
+            Some((name, &label)) if label as usize == addr => Some(name.clone()),
+            Some((name, &label)) => Some(format!("{}+{}", name, addr - label as usize)),
+            None => None,
+        }
+    }
+
+    fn label_suffix(&self, addr: usize) -> String {
+        self.symbolize(addr).map_or(String::new(), |name| format!(" <{}>", name))
+    }
+
+    fn print_location(&self, machine: &Machine, out: &mut dyn Write) -> io::Result<()> {
+        let pc = machine.register(Register::Pc);
+        self.print_disassembly(machine, pc, 1, out)?;
+        if machine.waiting_key() {
+            writeln!(out, "Waiting for a key (Fx0A).")?;
+        }
+        Ok(())
+    }
+
+    fn print_disassembly(&self, machine: &Machine, start: usize, count: usize, out: &mut dyn Write) -> io::Result<()> {
+        let memory = machine.memory();
+        let pc = machine.register(Register::Pc);
+        let mut addr = start;
+        for _ in 0..count {
+            if addr >= memory.len() - 1 {
+                break;
+            }
+            if let Some(label) = self.label_at(addr) {
+                writeln!(out, "{}:", label)?;
+            }
+            let marker = if addr == pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains_key(&addr) { '*' } else { ' ' };
+            let opcode = (memory[addr] as u16) << 8 | memory[addr + 1] as u16;
+            match self.decode(machine, addr) {
+                Some(instruction @ Instruction::LdILong(Some(long))) => {
+                    writeln!(out, "{}{}{:03X}: {:04X}{:04X}  {}", marker, breakpoint, addr, opcode, long, instruction)?;
+                    addr += 4;
+                }
+                Some(instruction) => {
+                    writeln!(out, "{}{}{:03X}: {:04X}      {}", marker, breakpoint, addr, opcode, instruction)?;
+                    addr += 2;
+                }
+                None => {
+                    writeln!(out, "{}{}{:03X}: {:04X}      ??", marker, breakpoint, addr, opcode)?;
+                    addr += 2;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn print_memory(&self, machine: &Machine, start: usize, count: usize, out: &mut dyn Write) -> io::Result<()> {
+        let memory = machine.memory();
+        let end = start.saturating_add(count).min(memory.len());
+        let mut addr = start;
+        while addr < end {
+            let line_end = (addr + 8).min(end);
+            let bytes: Vec<String> = memory[addr..line_end].iter().map(|byte| format!("{:02X}", byte)).collect();
+            writeln!(out, "{:03X}: {}", addr, bytes.join(" "))?;
+            addr = line_end;
+        }
+        Ok(())
+    }
+}
+
+// The watchpoint hit by the last instructions run, memory first.
+fn watch_stop(machine: &mut Machine) -> Option<Stop> {
+    let memory = machine.take_watch_hit().map(|(addr, access)| Stop::Watchpoint { addr, access });
+    let register = machine.take_register_hit().map(|(register, old, new)| Stop::RegisterWatchpoint { register, old, new });
+    memory.or(register)
+}
+
+// Runs one instruction, waiting the end of the frame first if a draw is waiting for it.
+fn step_instruction(history: &mut History, machine: &mut Machine) -> Result<(), MachineFault> {
+    while machine.blocked() && !machine.waiting_key() && !machine.halted() {
+        history.step(machine)?;
+    }
+    history.step(machine)
+}
+
+/// Decimal, or hexadecimal with a 0x prefix.
+pub fn parse_number(token: &str) -> Option<usize> {
+    if token.starts_with("0x") || token.starts_with("0X") {
+        usize::from_str_radix(&token[2..], 16).ok()
+    } else {
+        token.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Platform;
+
+    fn machine(program: &[u8]) -> Machine {
+        let mut machine = Machine::new(Platform::CosmacVip, Platform::CosmacVip.quirks());
+        machine.load(program).unwrap();
+        machine.set_seed(1);
+        machine
+    }
+
+    // Runs a command, returns its action and what it printed.
+    fn command(debugger: &mut Debugger, machine: &mut Machine, line: &str) -> (Option<Action>, String) {
+        let mut out = Vec::new();
+        let action = debugger.command(machine, line, &mut out).unwrap();
+        (action, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn commands() {
+        let mut machine = machine(&[0x60, 0x05, 0x22, 0x06, 0x12, 0x04, 0x00, 0xEE]);
+        let mut debugger = Debugger::new();
+        let mut symbols = BTreeMap::new();
+        symbols.insert("sub".to_string(), 0x206);
+        debugger.add_symbols(&symbols);
+
+        assert_eq!(command(&mut debugger, &mut machine, "b sub").1, "Breakpoint at 206.\n");
+        assert_eq!(command(&mut debugger, &mut machine, "break 0x204 if v0 == 5").1, "Breakpoint at 204 if v0 == 5.\n");
+        assert_eq!(command(&mut debugger, &mut machine, "info b").1, "204 if v0 == 5\n206 <sub>\n");
+        assert_eq!(command(&mut debugger, &mut machine, "b nowhere").1, "Unknown address nowhere.\n");
+        assert!(command(&mut debugger, &mut machine, "b 0x300 if v0 ==").1.starts_with("Invalid condition"));
+        assert_eq!(command(&mut debugger, &mut machine, "d 0x300").1, "No breakpoint at 0x300.\n");
+
+        assert_eq!(command(&mut debugger, &mut machine, "s 2").1, "sub:\n=>*206: 00EE      RET\n");
+        assert_eq!(command(&mut debugger, &mut machine, "p v0").1, "V0 = 0x5 (5)\n");
+        assert_eq!(command(&mut debugger, &mut machine, "bt").1, "#0  206 <sub>\n#1  202\n");
+        assert_eq!(command(&mut debugger, &mut machine, "set i = sub").1, "I = 0x206 (518)\n");
+        assert_eq!(command(&mut debugger, &mut machine, "poke i 0xAB 1").1, "");
+        assert_eq!(command(&mut debugger, &mut machine, "x 0x206 3").1, "206: AB 01 00\n");
+        assert_eq!(command(&mut debugger, &mut machine, "x 0x206 many").1, "Usage: x <addr> [count].\n");
+        assert_eq!(command(&mut debugger, &mut machine, "poke 0xFFF 1 2").1, "FAULT: memory access out of bounds at 1000.\n");
+        assert_eq!(command(&mut debugger, &mut machine, "frobnicate 1").1, "Unknown command frobnicate 1, try help.\n");
+
+        assert_eq!(command(&mut debugger, &mut machine, "d").1, "Deleted all breakpoints.\n");
+        assert_eq!(command(&mut debugger, &mut machine, "c").0, Some(Action::Resume));
+        assert_eq!(command(&mut debugger, &mut machine, "q").0, Some(Action::Quit));
+        assert_eq!(command(&mut debugger, &mut machine, "").0, None);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number("0x2A"), Some(42));
+        assert_eq!(parse_number("0X2a"), Some(42));
+        assert_eq!(parse_number("2A"), None);
+        assert_eq!(parse_number("-1"), None);
+    }
+
+    // Counts to 50 in V0, then counts the rounds in V1 and stores V0 and V1 at 300.
+    const ROUNDS: [u8; 16] = [
+        0x70, 0x01, 0x30, 0x32, 0x12, 0x00,
+        0x71, 0x01, 0xA3, 0x00, 0xF1, 0x55, 0x60, 0x00, 0x12, 0x00,
+    ];
+
+    // Runs 70 frames through the debugger, returns the cycles where the store ran.
+    fn run_rounds(debugger: &mut Debugger, machine: &mut Machine) -> Vec<u64> {
+        let mut reference = self::machine(&ROUNDS);
+        let mut stores = Vec::new();
+        while reference.frames() < 70 {
+            if reference.register(Register::Pc) == 0x20A {
+                stores.push(reference.cycles());
+            }
+            reference.step().unwrap();
+        }
+        while machine.frames() < 70 {
+            assert_eq!(debugger.run_frame(machine), None);
+        }
+        stores
+    }
+
+    #[test]
+    fn reverse_continue_stops_at_the_latest_hit() {
+        let mut machine = machine(&ROUNDS);
+        let mut debugger = Debugger::new();
+        let stores = run_rounds(&mut debugger, &mut machine);
+        let window = crate::history::CHECKPOINT_FRAMES * machine.instructions_per_frame() as u64;
+        assert!(stores[0] < window && stores[stores.len() - 1] > 2 * window);
+
+        // Every round back to the first, across the checkpoints.
+        debugger.set_breakpoint(0x20A);
+        for (round, &cycle) in stores.iter().enumerate().rev() {
+            assert_eq!(debugger.reverse_continue(&mut machine), Some(Stop::Breakpoint(0x20A)));
+            assert_eq!((machine.cycles(), machine.register(Register::V(1))), (cycle, round + 1));
+        }
+        assert_eq!(debugger.reverse_continue(&mut machine), None);
+        assert_eq!(machine.cycles(), 0);
+
+        // A watchpoint stops before the instruction doing the access.
+        let mut machine = self::machine(&ROUNDS);
+        let mut debugger = Debugger::new();
+        let stores = run_rounds(&mut debugger, &mut machine);
+        machine.add_watchpoint(Watchpoint { start: 0x301, end: 0x302, read: false, write: true });
+        let stop = debugger.reverse_continue(&mut machine);
+        assert_eq!(stop, Some(Stop::Watchpoint { addr: 0x301, access: Access::Write }));
+        assert_eq!(machine.cycles(), stores[stores.len() - 1]);
+        assert_eq!(machine.register(Register::Pc), 0x20A);
+    }
+
+    #[test]
+    fn last_write_stops_before_the_store() {
+        let mut machine = machine(&ROUNDS);
+        let mut debugger = Debugger::new();
+        let stores = run_rounds(&mut debugger, &mut machine);
+        let rounds = stores.len();
+        assert_eq!(machine.memory()[0x301] as usize, rounds);
+
+        let stop = debugger.last_write(&mut machine, 0x301);
+        assert_eq!(stop, Some(Stop::Watchpoint { addr: 0x301, access: Access::Write }));
+        assert_eq!((machine.cycles(), machine.register(Register::Pc)), (stores[rounds - 1], 0x20A));
+        assert_eq!((machine.memory()[0x301] as usize, machine.register(Register::V(1))), (rounds - 1, rounds));
+
+        // The store is the next instruction.
+        assert_eq!(debugger.step(&mut machine), Stop::Step);
+        assert_eq!(machine.memory()[0x301] as usize, rounds);
+
+        assert_eq!(debugger.last_write(&mut machine, 0x400), None);
+        assert_eq!(machine.cycles(), 0);
+    }
+}
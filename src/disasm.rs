@@ -0,0 +1,372 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::instruction::Instruction;
+use crate::quirks::Platform;
+
+const ROM_ADDR: usize = 0x200;
+
+// Data bytes printed per line of the listing.
+const DATA_PER_LINE: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    Label,
+    Sub,
+}
+
+/*
+ * A ROM split in reachable code and data, found by following the control
+ * flow from 0x200. The listing printed by its Display impl is Octo source
+ * that assembles back to the same bytes, annotated with the address, the
+ * opcode and the Cowgod mnemonic of every instruction.
+ */
+pub struct Disassembly {
+    rom: Vec<u8>,
+    platform: Platform,
+    code: Vec<Option<Instruction>>,     // Instruction starting at each ROM offset
+    claimed: Vec<bool>,                 // Bytes that belong to an instruction
+    labels: BTreeMap<usize, String>,    // Addresses referenced by the code
+}
+
+impl Disassembly {
+    pub fn new(rom: &[u8], platform: Platform) -> Self {
+        let mut disasm = Disassembly {
+            rom: rom.to_vec(),
+            platform,
+            code: vec![None; rom.len()],
+            claimed: vec![false; rom.len()],
+            labels: BTreeMap::new(),
+        };
+
+        let references = disasm.trace();
+
+        for (addr, kind) in references {
+            if disasm.is_line_start(addr) {
+                let prefix = match kind {
+                    LabelKind::Sub => "sub",
+                    LabelKind::Label => "label",
+                    LabelKind::Data => "data",
+                };
+                disasm.labels.insert(addr, format!("{}_{:03X}", prefix, addr));
+            }
+        }
+
+        disasm
+    }
+
+    /// The instruction decoded at an address, if it was reached as code.
+    pub fn instruction_at(&self, addr: usize) -> Option<Instruction> {
+        self.offset(addr).and_then(|offset| self.code[offset])
+    }
+
+    /// Every instruction reached as code and its address, in address order.
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, Instruction)> + '_ {
+        self.code.iter().enumerate()
+            .filter_map(|(offset, instruction)| instruction.map(|instruction| (ROM_ADDR + offset, instruction)))
+    }
+
+    /// The label invented for an address, if the code references it.
+    pub fn label_at(&self, addr: usize) -> Option<&str> {
+        self.labels.get(&addr).map(|label| label.as_str())
+    }
+
+    fn offset(&self, addr: usize) -> Option<usize> {
+        if addr >= ROM_ADDR && addr - ROM_ADDR < self.rom.len() {
+            Some(addr - ROM_ADDR)
+        } else {
+            None
+        }
+    }
+
+    fn is_line_start(&self, addr: usize) -> bool {
+        match self.offset(addr) {
+            Some(offset) => self.code[offset].is_some() || !self.claimed[offset],
+            None => false,
+        }
+    }
+
+    // Decodes the instruction at addr without marking it as code.
+    fn peek(&self, addr: usize) -> Option<Instruction> {
+        let offset = self.offset(addr)?;
+        let instruction = Instruction::decode_at(&self.rom, offset)?;
+        if !instruction.supported_by(self.platform) || offset + instruction.size() > self.rom.len() {
+            return None;
+        }
+        Some(instruction)
+    }
+
+    fn size_at(&self, addr: usize) -> usize {
+        self.peek(addr).map(Instruction::size).unwrap_or(2)
+    }
+
+    // Follows every path from 0x200 and returns the referenced addresses.
+    fn trace(&mut self) -> BTreeMap<usize, LabelKind> {
+        use crate::instruction::Instruction::*;
+
+        let mut references: BTreeMap<usize, LabelKind> = BTreeMap::new();
+        let mut pending = vec![ROM_ADDR];
+
+        let mut reference = |addr: usize, kind: LabelKind| {
+            let entry = references.entry(addr).or_insert(kind);
+            if kind > *entry {
+                *entry = kind;
+            }
+        };
+
+        while let Some(addr) = pending.pop() {
+            let offset = match self.offset(addr) {
+                Some(offset) => offset,
+                None => continue,
+            };
+            if self.code[offset].is_some() {
+                continue;
+            }
+            let instruction = match self.peek(addr) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            // Never decode over the bytes of another instruction.
+            let size = instruction.size();
+            if self.claimed[offset..offset + size].iter().any(|&claimed| claimed) {
+                continue;
+            }
+            self.code[offset] = Some(instruction);
+            for claimed in &mut self.claimed[offset..offset + size] {
+                *claimed = true;
+            }
+
+            let next = addr + size;
+            match instruction {
+                Ret | Exit => {}
+                Jp(nnn) => {
+                    reference(nnn as usize, LabelKind::Label);
+                    pending.push(nnn as usize);
+                }
+                Call(nnn) => {
+                    reference(nnn as usize, LabelKind::Sub);
+                    pending.push(nnn as usize);
+                    pending.push(next);
+                }
+                // The jump table at nnn is indexed by a register, follow
+                // every consecutive jump found there.
+                JpV0(nnn) => {
+                    let mut entry = nnn as usize;
+                    reference(entry, LabelKind::Label);
+                    pending.push(entry);
+                    while let Some(Jp(_)) = self.peek(entry) {
+                        pending.push(entry);
+                        entry += 2;
+                    }
+                }
+                LdI(nnn) => {
+                    reference(nnn as usize, LabelKind::Data);
+                    pending.push(next);
+                }
+                LdILong(Some(long)) => {
+                    reference(long as usize, LabelKind::Data);
+                    pending.push(next);
+                }
+                _ if instruction.is_skip() => {
+                    pending.push(next);
+                    pending.push(next + self.size_at(next));
+                }
+                _ => {
+                    pending.push(next);
+                }
+            }
+        }
+
+        references
+    }
+
+    fn target(&self, addr: u16) -> String {
+        match self.labels.get(&(addr as usize)) {
+            Some(label) => label.clone(),
+            None => format!("0x{:03X}", addr),
+        }
+    }
+
+    // The Octo statement for an instruction.
+    fn octo(&self, instruction: Instruction) -> String {
+        use crate::instruction::Instruction::*;
+
+        match instruction {
+            Cls => "clear".to_string(),
+            Ret => "return".to_string(),
+            Scd(n) => format!("scroll-down {}", n),
+            Scu(n) => format!("scroll-up {}", n),
+            Scr => "scroll-right".to_string(),
+            Scl => "scroll-left".to_string(),
+            Exit => "exit".to_string(),
+            Low => "lores".to_string(),
+            High => "hires".to_string(),
+            Jp(nnn) => format!("jump {}", self.target(nnn)),
+            Call(nnn) => match self.labels.get(&(nnn as usize)) {
+                Some(label) => label.clone(),
+                None => format!(":call 0x{:03X}", nnn),
+            },
+            // Octo's `if ... then` runs the next statement when the condition
+            // holds, so it skips it when the opposite condition holds.
+            SeByte { x, kk } => format!("if v{:x} != 0x{:02X} then", x, kk),
+            SneByte { x, kk } => format!("if v{:x} == 0x{:02X} then", x, kk),
+            SeReg { x, y } => format!("if v{:x} != v{:x} then", x, y),
+            SneReg { x, y } => format!("if v{:x} == v{:x} then", x, y),
+            Skp(x) => format!("if v{:x} -key then", x),
+            Sknp(x) => format!("if v{:x} key then", x),
+            Save { x, y } => format!("save v{:x} - v{:x}", x, y),
+            Load { x, y } => format!("load v{:x} - v{:x}", x, y),
+            LdByte { x, kk } => format!("v{:x} := 0x{:02X}", x, kk),
+            AddByte { x, kk } => format!("v{:x} += 0x{:02X}", x, kk),
+            LdReg { x, y } => format!("v{:x} := v{:x}", x, y),
+            Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+            And { x, y } => format!("v{:x} &= v{:x}", x, y),
+            Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+            AddReg { x, y } => format!("v{:x} += v{:x}", x, y),
+            Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+            Shr { x, y } => format!("v{:x} >>= v{:x}", x, y),
+            Subn { x, y } => format!("v{:x} =- v{:x}", x, y),
+            Shl { x, y } => format!("v{:x} <<= v{:x}", x, y),
+            LdI(nnn) => format!("i := {}", self.target(nnn)),
+            JpV0(nnn) => format!("jump0 {}", self.target(nnn)),
+            Rnd { x, kk } => format!("v{:x} := random 0x{:02X}", x, kk),
+            Drw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+            LdILong(long) => {
+                let long = long.unwrap_or(0);
+                match self.labels.get(&(long as usize)) {
+                    Some(label) => format!("i := long {}", label),
+                    None => format!("i := long 0x{:04X}", long),
+                }
+            }
+            Plane(n) => format!("plane {}", n),
+            Audio => "audio".to_string(),
+            LdVxDt(x) => format!("v{:x} := delay", x),
+            LdVxK(x) => format!("v{:x} := key", x),
+            LdDtVx(x) => format!("delay := v{:x}", x),
+            LdStVx(x) => format!("buzzer := v{:x}", x),
+            AddIVx(x) => format!("i += v{:x}", x),
+            LdFVx(x) => format!("i := hex v{:x}", x),
+            LdHfVx(x) => format!("i := bighex v{:x}", x),
+            LdBVx(x) => format!("bcd v{:x}", x),
+            Pitch(x) => format!("pitch := v{:x}", x),
+            LdIVx(x) => format!("save v{:x}", x),
+            LdVxI(x) => format!("load v{:x}", x),
+            LdRVx(x) => format!("saveflags v{:x}", x),
+            LdVxR(x) => format!("loadflags v{:x}", x),
+        }
+    }
+}
+
+impl Disassembly {
+    /*
+     * The listing with a note appended to the lines for which `note` returns
+     * one. It gets the range of addresses of the line, an instruction or a
+     * run of data bytes.
+     */
+    pub fn annotate(&self, f: &mut dyn fmt::Write, note: &dyn Fn(usize, usize) -> Option<String>) -> fmt::Result {
+        let suffix = |start: usize, end: usize| match note(start, end) {
+            Some(note) => format!(" | {}", note),
+            None => String::new(),
+        };
+
+        let mut offset = 0;
+        while offset < self.rom.len() {
+            let addr = ROM_ADDR + offset;
+            if let Some(label) = self.labels.get(&addr) {
+                writeln!(f, ": {}", label)?;
+            }
+
+            if let Some(instruction) = self.code[offset] {
+                let size = instruction.size();
+                let bytes: Vec<String> = self.rom[offset..offset + size].iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect();
+                let statement = self.octo(instruction);
+                writeln!(f, "\t{:<27} # {:03X}: {:<9} {}{}", statement, addr, bytes.join(""), instruction,
+                    suffix(addr, addr + size))?;
+                offset += size;
+            } else {
+                // Data runs until the next code or label, at most DATA_PER_LINE bytes.
+                let mut end = offset + 1;
+                while end < self.rom.len() && end - offset < DATA_PER_LINE
+                    && !self.claimed[end] && !self.labels.contains_key(&(ROM_ADDR + end)) {
+                    end += 1;
+                }
+                let bytes: Vec<String> = self.rom[offset..end].iter()
+                    .map(|byte| format!("0x{:02X}", byte))
+                    .collect();
+                let bits: Vec<String> = self.rom[offset..end].iter()
+                    .map(|byte| format!("{:08b}", byte).replace('0', ".").replace('1', "#"))
+                    .collect();
+                writeln!(f, "\t{:<27} # {:03X}: {}{}", bytes.join(" "), addr, bits.join(" "),
+                    suffix(addr, ROM_ADDR + end))?;
+                offset = end;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.annotate(f, &|_, _| None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    const SOURCE: &str = "
+        : main
+            hires
+            i := long box
+            v0 := 0
+            loop
+                v1 := random 0x3F
+                sprite v0 v1 4
+                if v0 == 8 then v2 += 1
+                v0 += 1
+                save v0 - v2
+                draw
+            again
+        : draw
+            i := digits
+            v3 := 0x12
+            bcd v3
+            return
+        : digits
+            0x01 0x02 0x03
+        : box
+            0b11110000 0b10010000 0b10010000 0b11110000
+    ";
+
+    #[test]
+    fn listing_assembles_back_to_the_same_rom() {
+        let rom = assemble(SOURCE).unwrap().rom;
+        let listing = Disassembly::new(&rom, Platform::XoChip).to_string();
+        let program = assemble(&listing).unwrap_or_else(|e| panic!("{}\n{}", e, listing));
+        assert_eq!(program.rom, rom, "{}", listing);
+    }
+
+    #[test]
+    fn code_is_followed_from_the_entry() {
+        let rom = assemble(SOURCE).unwrap().rom;
+        let disassembly = Disassembly::new(&rom, Platform::XoChip);
+        assert_eq!(disassembly.instruction_at(0x200), Some(Instruction::High));
+        match disassembly.instruction_at(0x202) {
+            Some(Instruction::LdILong(Some(addr))) => assert!(disassembly.label_at(addr as usize).is_some()),
+            instruction => panic!("{:?}", instruction),
+        }
+        assert!(disassembly.instructions().any(|(_, instruction)| instruction == Instruction::Ret));
+    }
+
+    #[test]
+    fn data_assembles_back_to_the_same_rom() {
+        let rom: Vec<u8> = (0..=255u8).rev().collect();
+        let listing = Disassembly::new(&rom, Platform::CosmacVip).to_string();
+        assert_eq!(assemble(&listing).map(|program| program.rom), Ok(rom));
+    }
+}
@@ -0,0 +1,574 @@
+use std::io::{ self, Read, Write };
+use std::net::{ SocketAddr, TcpListener, TcpStream, ToSocketAddrs };
+
+use crate::debugger::{ Debugger, Remote, Stop };
+use crate::machine::{ Access, Machine, MachineFault, Register, Watchpoint };
+
+// Largest packet accepted from gdb, announced in qSupported.
+const PACKET_SIZE: usize = 0x4000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.emu8.chip8.cpu">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+/*
+ * GDB remote serial protocol server. The registers are the ones of the
+ * target description above, in that order, little endian, and addresses
+ * are offsets in the machine memory.
+ *
+ * The server never blocks: the frontend calls poll every frame to accept a
+ * connection and answer the pending packets, and runs its frames through
+ * run_frame, which stops at the breakpoints and watchpoints set by gdb. The
+ * program waits for gdb to connect, and keeps running after gdb detaches or
+ * disconnects.
+ */
+pub struct GdbStub {
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+    input: Vec<u8>,             // Received bytes not handled yet
+    last_packet: Vec<u8>,       // Sent again when gdb asks for it with a -
+    no_ack: bool,               // QStartNoAckMode
+    debugger: Debugger,
+    watchpoints: Vec<Watchpoint>,   // Set by gdb, removed from the machine when it disconnects
+    killed: bool,
+}
+
+impl GdbStub {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        let mut debugger = Debugger::new();
+        debugger.interrupt();
+
+        Ok(GdbStub {
+            listener,
+            stream: None,
+            input: Vec::new(),
+            last_packet: Vec::new(),
+            no_ack: false,
+            debugger,
+            watchpoints: Vec::new(),
+            killed: false,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    // The program runs on without a debugger.
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.input.clear();
+        for addr in self.debugger.breakpoints().collect::<Vec<_>>() {
+            self.debugger.remove_breakpoint(addr);
+        }
+        self.debugger.resume();
+    }
+
+    // Takes the next packet out of the input, answering the acknowledgements and interrupts before it.
+    fn next_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.input.first() {
+                None => return Ok(None),
+                Some(b'+') => {
+                    self.input.remove(0);
+                }
+                Some(b'-') => {
+                    self.input.remove(0);
+                    let packet = self.last_packet.clone();
+                    self.write(&packet)?;
+                }
+                // Ctrl-C in gdb.
+                Some(0x03) => {
+                    self.input.remove(0);
+                    self.interrupt()?;
+                }
+                Some(b'$') => {
+                    let end = match self.input.iter().position(|&byte| byte == b'#') {
+                        Some(end) if end + 2 < self.input.len() => end,
+                        _ => return Ok(None),
+                    };
+                    let packet: Vec<u8> = self.input[1..end].to_vec();
+                    let checksum = std::str::from_utf8(&self.input[end + 1..end + 3]).ok()
+                        .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+                    self.input.drain(..end + 3);
+
+                    if self.no_ack {
+                        return Ok(Some(packet));
+                    }
+                    if checksum == Some(packet_checksum(&packet)) {
+                        self.write(b"+")?;
+                        return Ok(Some(packet));
+                    }
+                    self.write(b"-")?;
+                }
+                Some(_) => {
+                    self.input.remove(0);
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, machine: &mut Machine, packet: &[u8]) -> io::Result<()> {
+        let packet = String::from_utf8_lossy(packet).into_owned();
+        let (command, args) = packet.split_at(packet.len().min(1));
+
+        match command {
+            "?" => {
+                let stop = self.debugger.stopped().unwrap_or(Stop::Interrupted);
+                self.send_stop(stop)
+            }
+            "g" => {
+                let mut reply = String::new();
+                for register in Register::all() {
+                    reply.push_str(&encode_register(machine, register));
+                }
+                self.send(&reply)
+            }
+            "G" => {
+                let bytes = match decode_hex(args) {
+                    Some(bytes) => bytes,
+                    None => return self.send("E01"),
+                };
+                let mut offset = 0;
+                for register in Register::all() {
+                    let size = register_size(register);
+                    if offset + size > bytes.len() {
+                        break;
+                    }
+                    machine.set_register(register, little_endian(&bytes[offset..offset + size]));
+                    offset += size;
+                }
+                self.debugger.clear_history();
+                self.send("OK")
+            }
+            "p" => match parse_hex(args).and_then(|n| Register::all().get(n).cloned()) {
+                Some(register) => {
+                    let reply = encode_register(machine, register);
+                    self.send(&reply)
+                }
+                None => self.send("E01"),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let register = parts.next().and_then(parse_hex).and_then(|n| Register::all().get(n).cloned());
+                let value = parts.next().and_then(decode_hex);
+                match (register, value) {
+                    (Some(register), Some(value)) => {
+                        machine.set_register(register, little_endian(&value));
+                        self.debugger.clear_history();
+                        self.send("OK")
+                    }
+                    _ => self.send("E01"),
+                }
+            }
+            "m" => {
+                let memory = machine.memory();
+                match parse_range(args).and_then(|(addr, length)| Some((addr, addr.checked_add(length)?))) {
+                    Some((addr, end)) if addr < memory.len() => {
+                        let end = end.min(memory.len());
+                        let reply = encode_hex(&memory[addr..end]);
+                        self.send(&reply)
+                    }
+                    _ => self.send("E01"),
+                }
+            }
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_range);
+                let data = parts.next().and_then(decode_hex);
+                match (range, data) {
+                    (Some((addr, length)), Some(data)) if data.len() == length && addr.checked_add(length).is_some() => {
+                        self.debugger.clear_history();
+                        for (offset, &byte) in data.iter().enumerate() {
+                            if machine.poke(addr + offset, byte).is_err() {
+                                return self.send("E01");
+                            }
+                        }
+                        self.send("OK")
+                    }
+                    _ => self.send("E01"),
+                }
+            }
+            "Z" | "z" => self.handle_breakpoint(machine, command == "Z", args),
+            "c" => self.resume(machine, args, false),
+            "s" => self.resume(machine, args, true),
+            "b" => self.reverse(machine, args),
+            "v" => self.handle_v(machine, &packet),
+            "q" | "Q" => self.handle_query(&packet),
+            "H" | "T" => self.send("OK"),
+            "D" => {
+                self.send("OK")?;
+                self.disconnect();
+                Ok(())
+            }
+            "k" => {
+                self.killed = true;
+                self.stream = None;
+                Ok(())
+            }
+            _ => self.send(""),
+        }
+    }
+
+    // Z and z: type,addr,kind. Software and hardware breakpoints are the same here.
+    fn handle_breakpoint(&mut self, machine: &mut Machine, insert: bool, args: &str) -> io::Result<()> {
+        let fields: Vec<usize> = match args.split(',').map(parse_hex).collect() {
+            Some(fields) => fields,
+            None => return self.send("E01"),
+        };
+        if fields.len() != 3 {
+            return self.send("E01");
+        }
+        let (kind, addr, length) = (fields[0], fields[1], fields[2]);
+        let end = match addr.checked_add(length) {
+            Some(end) => end,
+            None => return self.send("E01"),
+        };
+
+        if kind <= 1 {
+            if insert {
+                self.debugger.set_breakpoint(addr);
+            } else {
+                self.debugger.remove_breakpoint(addr);
+            }
+            return self.send("OK");
+        }
+
+        let watchpoint = match kind {
+            2 => Watchpoint { start: addr, end, read: false, write: true },
+            3 => Watchpoint { start: addr, end, read: true, write: false },
+            4 => Watchpoint { start: addr, end, read: true, write: true },
+            _ => return self.send(""),
+        };
+        if insert {
+            machine.add_watchpoint(watchpoint);
+            self.watchpoints.push(watchpoint);
+        } else {
+            machine.remove_watchpoint(&watchpoint);
+            self.watchpoints.retain(|w| *w != watchpoint);
+        }
+        self.send("OK")
+    }
+
+    // c and s, with an optional address to resume from.
+    fn resume(&mut self, machine: &mut Machine, args: &str, step: bool) -> io::Result<()> {
+        if let Some(addr) = parse_hex(args) {
+            machine.set_register(Register::Pc, addr);
+            self.debugger.clear_history();
+        }
+        if step {
+            let stop = self.debugger.step(machine);
+            self.send_stop(stop)
+        } else {
+            self.debugger.resume();
+            Ok(())
+        }
+    }
+
+    // bs and bc, reverse-stepi and reverse-continue in gdb.
+    fn reverse(&mut self, machine: &mut Machine, args: &str) -> io::Result<()> {
+        if self.debugger.reverse_disabled().is_some() {
+            return self.send("E01");
+        }
+        let stop = match args {
+            "s" => self.debugger.step_back(machine),
+            "c" => self.debugger.reverse_continue(machine),
+            _ => return self.send(""),
+        };
+        match stop {
+            Some(stop) => self.send_stop(stop),
+            None => self.send("T05replaylog:begin;"),
+        }
+    }
+
+    fn handle_v(&mut self, machine: &mut Machine, packet: &str) -> io::Result<()> {
+        if packet == "vCont?" {
+            return self.send("vCont;c;C;s;S");
+        }
+        if let Some(actions) = packet.strip_prefix("vCont;") {
+            // A single thread, the first action applies to it.
+            return match actions.chars().next() {
+                Some('c') | Some('C') => self.resume(machine, "", false),
+                Some('s') | Some('S') => self.resume(machine, "", true),
+                _ => self.send("E01"),
+            };
+        }
+        if packet.starts_with("vKill") {
+            self.killed = true;
+            return self.send("OK");
+        }
+        self.send("")
+    }
+
+    fn handle_query(&mut self, packet: &str) -> io::Result<()> {
+        if packet.starts_with("qSupported") {
+            let reply = format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+;ReverseStep+;ReverseContinue+", PACKET_SIZE);
+            return self.send(&reply);
+        }
+        if packet == "QStartNoAckMode" {
+            self.send("OK")?;
+            self.no_ack = true;
+            return Ok(());
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(range) {
+                Some((offset, length)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = offset.min(xml.len());
+                    let end = offset.saturating_add(length).min(xml.len());
+                    let mut reply = String::from(if end == xml.len() { "l" } else { "m" });
+                    reply.push_str(&String::from_utf8_lossy(&xml[start..end]));
+                    self.send(&reply)
+                }
+                None => self.send("E01"),
+            };
+        }
+        match packet {
+            "qAttached" => self.send("1"),
+            "qC" => self.send("QC1"),
+            "qfThreadInfo" => self.send("m1"),
+            "qsThreadInfo" => self.send("l"),
+            _ if packet.starts_with("qSymbol") => self.send("OK"),
+            _ => self.send(""),
+        }
+    }
+
+    fn send_stop(&mut self, stop: Stop) -> io::Result<()> {
+        let reply = match stop {
+            Stop::Breakpoint(_) => "T05swbreak:;".to_string(),
+            Stop::Watchpoint { addr, access } => {
+                let kind = match access {
+                    Access::Read => "rwatch",
+                    Access::Write => "watch",
+                };
+                format!("T05{}:{:x};", kind, addr)
+            }
+            Stop::RegisterWatchpoint { .. } | Stop::Step => "S05".to_string(),
+            Stop::Interrupted => "S02".to_string(),
+            Stop::Fault(MachineFault::InvalidOpcode { .. }) => "S04".to_string(),
+            Stop::Fault(_) => "S0b".to_string(),
+            Stop::Halted => "W00".to_string(),
+        };
+        self.send(&reply)
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let data = escape(data);
+        let packet = format!("${}#{:02x}", data, packet_checksum(data.as_bytes())).into_bytes();
+        self.write(&packet)?;
+        self.last_packet = packet;
+        Ok(())
+    }
+
+    // Writes the whole buffer even though the socket is non-blocking.
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Some(ref mut stream) = self.stream {
+            stream.set_nonblocking(false)?;
+            let result = stream.write_all(bytes);
+            stream.set_nonblocking(true)?;
+            if result.is_err() {
+                self.disconnect();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Remote for GdbStub {
+    fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.debugger.stopped().is_some()
+    }
+
+    fn interrupt(&mut self) -> io::Result<()> {
+        if self.is_stopped() {
+            return Ok(());
+        }
+        self.debugger.interrupt();
+        self.send_stop(Stop::Interrupted)
+    }
+
+    // Accepts a connection, and handles the packets received since the last call.
+    fn poll(&mut self, machine: &mut Machine) -> io::Result<bool> {
+        if self.stream.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    stream.set_nodelay(true)?;
+                    self.stream = Some(stream);
+                    self.input.clear();
+                    self.no_ack = false;
+                    self.debugger.interrupt();
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut buffer = [0; 4096];
+        loop {
+            let result = match self.stream {
+                Some(ref mut stream) => stream.read(&mut buffer),
+                None => break,
+            };
+            match result {
+                Ok(0) => {
+                    self.disconnect();
+                    break;
+                }
+                Ok(count) => self.input.extend_from_slice(&buffer[..count]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.disconnect();
+                    break;
+                }
+            }
+        }
+
+        while let Some(packet) = self.next_packet()? {
+            self.handle(machine, &packet)?;
+        }
+
+        if self.stream.is_none() {
+            for watchpoint in self.watchpoints.drain(..) {
+                machine.remove_watchpoint(&watchpoint);
+            }
+        }
+
+        Ok(!self.killed)
+    }
+
+    fn run_frame(&mut self, machine: &mut Machine) -> io::Result<()> {
+        if self.is_stopped() {
+            return Ok(());
+        }
+        if let Some(stop) = self.debugger.run_frame(machine) {
+            self.send_stop(stop)?;
+        }
+        Ok(())
+    }
+}
+
+fn register_size(register: Register) -> usize {
+    match register {
+        Register::I | Register::Pc => 2,
+        _ => 1,
+    }
+}
+
+fn encode_register(machine: &Machine, register: Register) -> String {
+    let value = machine.register(register);
+    let bytes: Vec<u8> = (0..register_size(register)).map(|n| (value >> (8 * n)) as u8).collect();
+    encode_hex(&bytes)
+}
+
+fn little_endian(bytes: &[u8]) -> usize {
+    bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as usize)
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+// The characters with a meaning in the protocol are sent as } and the character xor 0x20.
+fn escape(data: &str) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    for c in data.chars() {
+        match c {
+            '$' | '#' | '}' | '*' => {
+                escaped.push('}');
+                escaped.push((c as u8 ^ 0x20) as char);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+// addr,length
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, ',');
+    let addr = parts.next().and_then(parse_hex)?;
+    let length = parts.next().and_then(parse_hex)?;
+    Some((addr, length))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() & 1 != 0 {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|at| u8::from_str_radix(text.get(at..at + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        assert_eq!(packet_checksum(b""), 0);
+        assert_eq!(packet_checksum(b"OK"), 0x9A);
+        assert_eq!(packet_checksum(b"qSupported"), 0x37);
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape("a$b#c}d*e"), "a}\x04b}\x03c}]d}\x0ae");
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(encode_hex(&[0x00, 0x12, 0xAB]), "0012ab");
+        assert_eq!(decode_hex("0012ab"), Some(vec![0x00, 0x12, 0xAB]));
+        assert_eq!(decode_hex("0012a"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(parse_range("200,10"), Some((0x200, 0x10)));
+        assert_eq!(parse_range("200"), None);
+        assert_eq!(little_endian(&[0x34, 0x12]), 0x1234);
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::{ BTreeMap, VecDeque };
+
+use crate::clock::FRAMES_PER_SECOND;
+use crate::keypad::{ Keypad, KeyEvent };
+use crate::machine::{ Machine, MachineFault };
+use crate::state::Snapshot;
+
+// Frames between checkpoints, the most run again to go back an instruction.
+pub(crate) const CHECKPOINT_FRAMES: u64 = 30;
+
+// Seconds of execution the debugger can go back by default.
+const HISTORY_SECONDS: usize = 30;
+
+struct Checkpoint {
+    snapshot: Snapshot,
+    keypad: Keypad,                         // Left out of snapshots, with the events of its frame
+}
+
+impl Checkpoint {
+    fn cycle(&self) -> u64 {
+        self.snapshot.cycles
+    }
+}
+
+/*
+ * Execution history for reverse debugging. The debugger runs the machine
+ * through it, and it keeps a checkpoint of the machine every few frames
+ * along with the key events queued at the start of the frames in between.
+ * Snapshots include the random generator, so running again from a
+ * checkpoint with the same key events gives back the same states, and any
+ * earlier cycle is reached by restoring the checkpoint before it and
+ * running forward.
+ *
+ * The history starts over when the machine is not where it was left, after
+ * a state load or a rewind by the frontend, when the RND generator changes
+ * and when the debugger changes registers or memory.
+ */
+pub struct History {
+    capacity: usize,                        // Checkpoints kept
+    checkpoints: VecDeque<Checkpoint>,      // Oldest first
+    inputs: BTreeMap<u64, Vec<KeyEvent>>,   // Key events queued at the start of a frame, by cycle
+    cycle: Option<u64>,                     // Where the machine was left
+}
+
+impl History {
+    pub fn new(seconds: usize) -> Self {
+        History {
+            capacity: (seconds * FRAMES_PER_SECOND as usize / CHECKPOINT_FRAMES as usize).max(1),
+            checkpoints: VecDeque::new(),
+            inputs: BTreeMap::new(),
+            cycle: None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.checkpoints.clear();
+        self.inputs.clear();
+        self.cycle = None;
+    }
+
+    /// The oldest cycle the machine can go back to.
+    pub fn start(&self) -> Option<u64> {
+        self.checkpoints.front().map(Checkpoint::cycle)
+    }
+
+    /// Runs one cycle of the machine, see `Machine::step`.
+    pub fn step(&mut self, machine: &mut Machine) -> Result<(), MachineFault> {
+        self.record(machine);
+        let result = machine.step();
+        self.cycle = Some(machine.cycles());
+        result
+    }
+
+    /// Runs the rest of the frame, see `Machine::run_frame`.
+    pub fn run_frame(&mut self, machine: &mut Machine) -> Result<(), MachineFault> {
+        self.record(machine);
+        let result = machine.run_frame();
+        self.cycle = Some(machine.cycles());
+        result
+    }
+
+    // Takes a checkpoint when one is due, or logs the key events queued at the start of a frame.
+    fn record(&mut self, machine: &Machine) {
+        let cycle = machine.cycles();
+        let generator = self.checkpoints.back().map(|last| last.snapshot.generator);
+        if self.cycle != Some(cycle) || generator.is_some_and(|generator| generator != machine.generator()) {
+            self.clear();
+        }
+        self.cycle = Some(cycle);
+
+        // A checkpoint taken before the frontend queued the key events of its frame is taken again.
+        let due = match self.checkpoints.back() {
+            None => true,
+            Some(last) => machine.frame_cycle() == 0
+                && (last.cycle() == cycle || machine.frames() >= last.snapshot.frames + CHECKPOINT_FRAMES),
+        };
+        if due {
+            if self.checkpoints.back().is_some_and(|last| last.cycle() == cycle) {
+                self.checkpoints.pop_back();
+            }
+            self.inputs.remove(&cycle);
+            self.checkpoints.push_back(Checkpoint { snapshot: machine.snapshot(), keypad: machine.keypad().clone() });
+            if self.checkpoints.len() > self.capacity {
+                self.checkpoints.pop_front();
+                let start = self.checkpoints[0].cycle();
+                self.inputs = self.inputs.split_off(&start);
+            }
+        } else if machine.frame_cycle() == 0 {
+            let events: Vec<KeyEvent> = machine.keypad().pending().collect();
+            if events.is_empty() {
+                self.inputs.remove(&cycle);
+            } else {
+                self.inputs.insert(cycle, events);
+            }
+        }
+    }
+
+    /*
+     * Takes the machine back to the last instruction before the current
+     * cycle for which `before` holds on the state before running it, or
+     * `after` on the state after, and returns the cycle where it starts.
+     * `after` is called after every cycle run, so it can take the watchpoint
+     * hits. Without such an instruction, the machine is left at the start of
+     * the history and `None` is returned.
+     *
+     * Instructions run again are not traced, but they are profiled. What
+     * was recorded after the new position is dropped, running forward
+     * records it again.
+     */
+    pub fn seek_back(&mut self, machine: &mut Machine,
+                     before: &mut dyn FnMut(&Machine) -> bool,
+                     after: &mut dyn FnMut(&mut Machine) -> bool) -> Option<u64> {
+        if self.cycle != Some(machine.cycles()) || self.checkpoints.is_empty() {
+            self.clear();
+            return None;
+        }
+
+        let tracing = machine.tracing();
+        machine.set_tracing(false);
+
+        // From the newest checkpoint, each one searched up to the next.
+        let mut found = None;
+        let mut until = machine.cycles();
+        for index in (0..self.checkpoints.len()).rev() {
+            let start = self.checkpoints[index].cycle();
+            if start >= until {
+                continue;
+            }
+            self.restore(machine, index);
+            let mut last = None;
+            while machine.cycles() < until {
+                let cycle = machine.cycles();
+                if !machine.blocked() && before(machine) {
+                    last = Some(cycle);
+                }
+                if self.replay_step(machine).is_err() {
+                    break;
+                }
+                if after(machine) {
+                    last = Some(cycle);
+                }
+            }
+            if let Some(cycle) = last {
+                found = Some((index, cycle));
+                break;
+            }
+            until = start;
+        }
+
+        let (index, cycle) = found.unwrap_or((0, self.checkpoints[0].cycle()));
+        self.restore(machine, index);
+        while machine.cycles() < cycle && self.replay_step(machine).is_ok() {}
+        self.queue_inputs(machine);
+        machine.take_watch_hit();
+        machine.take_register_hit();
+        machine.set_tracing(tracing);
+
+        let cycle = machine.cycles();
+        while self.checkpoints.back().is_some_and(|last| last.cycle() > cycle) {
+            self.checkpoints.pop_back();
+        }
+        self.inputs.retain(|&input, _| input <= cycle);
+        self.cycle = Some(cycle);
+        found.map(|(_, cycle)| cycle)
+    }
+
+    fn restore(&self, machine: &mut Machine, index: usize) {
+        let checkpoint = &self.checkpoints[index];
+        // The history starts over when the RND generator changes, so the machine still has the one of the checkpoint.
+        if machine.restore(&checkpoint.snapshot).is_ok() {
+            machine.set_keypad(checkpoint.keypad.clone());
+        }
+    }
+
+    // Queues the key events logged at the start of the current frame.
+    fn queue_inputs(&self, machine: &mut Machine) {
+        if machine.frame_cycle() != 0 {
+            return;
+        }
+        if let Some(events) = self.inputs.get(&machine.cycles()) {
+            for &event in events {
+                machine.push_key(event);
+            }
+        }
+    }
+
+    fn replay_step(&self, machine: &mut Machine) -> Result<(), MachineFault> {
+        self.queue_inputs(machine);
+        machine.step()
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(HISTORY_SECONDS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Platform;
+
+    // Adds random numbers to V3, and counts in V2 the instructions run while key 0 is up.
+    const PROGRAM: [u8; 10] = [0xC0, 0xFF, 0xE1, 0x9E, 0x72, 0x01, 0x83, 0x04, 0x12, 0x00];
+
+    // Key events queued at the start of some frames, across the checkpoint of frame 30.
+    fn key_events(frame: u64) -> Vec<KeyEvent> {
+        match frame {
+            20 => vec![KeyEvent::Down(0)],
+            31 => vec![KeyEvent::Up(0), KeyEvent::Down(0)],
+            45 => vec![KeyEvent::Up(0)],
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn stepping_back_replays_input_and_random_numbers() {
+        let mut machine = Machine::new(Platform::CosmacVip, Platform::CosmacVip.quirks());
+        machine.load(&PROGRAM).unwrap();
+        machine.set_seed(3);
+        let mut history = History::default();
+
+        // The state before every cycle.
+        let mut states = Vec::new();
+        while machine.frames() < 50 {
+            if machine.frame_cycle() == 0 {
+                for event in key_events(machine.frames()) {
+                    machine.push_key(event);
+                }
+            }
+            states.push(machine.snapshot());
+            history.step(&mut machine).unwrap();
+        }
+        let end = machine.cycles();
+        let boundary = CHECKPOINT_FRAMES * machine.instructions_per_frame() as u64;
+        assert_eq!(history.start(), Some(0));
+
+        // Back to before the checkpoint, one instruction at a time.
+        for cycle in (boundary - 20..end).rev() {
+            assert_eq!(history.seek_back(&mut machine, &mut |_| true, &mut |_| false), Some(cycle));
+            assert!(machine.snapshot() == states[cycle as usize], "cycle {}", cycle);
+        }
+
+        // And forward again, the input of the frames ahead is queued again as it was.
+        while machine.cycles() < end {
+            if machine.frame_cycle() == 0 {
+                for event in key_events(machine.frames()) {
+                    machine.push_key(event);
+                }
+            }
+            assert!(machine.snapshot() == states[machine.cycles() as usize], "cycle {}", machine.cycles());
+            history.step(&mut machine).unwrap();
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use std::fmt;
+
+use crate::quirks::Platform;
+
+/*
+ * A decoded CHIP8 instruction. Register operands (x, y) are register
+ * numbers from 0x0 to 0xF, addresses are 12 bit and n is a nibble.
+ *
+ * Decoding does not depend on the platform, use `supported_by` to know
+ * if the interpreter accepts an instruction.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Cls,                        // 00E0
+    Ret,                        // 00EE
+    Scd(u8),                    // 00Cn (SUPER-CHIP)
+    Scu(u8),                    // 00Dn (XO-CHIP)
+    Scr,                        // 00FB (SUPER-CHIP)
+    Scl,                        // 00FC (SUPER-CHIP)
+    Exit,                       // 00FD (SUPER-CHIP)
+    Low,                        // 00FE (SUPER-CHIP)
+    High,                       // 00FF (SUPER-CHIP)
+    Jp(u16),                    // 1nnn
+    Call(u16),                  // 2nnn
+    SeByte { x: u8, kk: u8 },   // 3xkk
+    SneByte { x: u8, kk: u8 },  // 4xkk
+    SeReg { x: u8, y: u8 },     // 5xy0
+    Save { x: u8, y: u8 },      // 5xy2 (XO-CHIP)
+    Load { x: u8, y: u8 },      // 5xy3 (XO-CHIP)
+    LdByte { x: u8, kk: u8 },   // 6xkk
+    AddByte { x: u8, kk: u8 },  // 7xkk
+    LdReg { x: u8, y: u8 },     // 8xy0
+    Or { x: u8, y: u8 },        // 8xy1
+    And { x: u8, y: u8 },       // 8xy2
+    Xor { x: u8, y: u8 },       // 8xy3
+    AddReg { x: u8, y: u8 },    // 8xy4
+    Sub { x: u8, y: u8 },       // 8xy5
+    Shr { x: u8, y: u8 },       // 8xy6
+    Subn { x: u8, y: u8 },      // 8xy7
+    Shl { x: u8, y: u8 },       // 8xyE
+    SneReg { x: u8, y: u8 },    // 9xy0
+    LdI(u16),                   // Annn
+    JpV0(u16),                  // Bnnn
+    Rnd { x: u8, kk: u8 },      // Cxkk
+    Drw { x: u8, y: u8, n: u8 },// Dxyn
+    Skp(u8),                    // Ex9E
+    Sknp(u8),                   // ExA1
+    LdILong(Option<u16>),       // F000 nnnn (XO-CHIP), the address is the next word, when known
+    Plane(u8),                  // Fn01 (XO-CHIP)
+    Audio,                      // F002 (XO-CHIP)
+    LdVxDt(u8),                 // Fx07
+    LdVxK(u8),                  // Fx0A
+    LdDtVx(u8),                 // Fx15
+    LdStVx(u8),                 // Fx18
+    AddIVx(u8),                 // Fx1E
+    LdFVx(u8),                  // Fx29
+    LdHfVx(u8),                 // Fx30 (SUPER-CHIP)
+    LdBVx(u8),                  // Fx33
+    Pitch(u8),                  // Fx3A (XO-CHIP)
+    LdIVx(u8),                  // Fx55
+    LdVxI(u8),                  // Fx65
+    LdRVx(u8),                  // Fx75 (SUPER-CHIP)
+    LdVxR(u8),                  // Fx85 (SUPER-CHIP)
+}
+
+/// Names of the instruction classes, see `Instruction::class`.
+pub const CLASS_NAMES: [&str; 10] = [
+    "flow",
+    "skip",
+    "arithmetic",
+    "load",
+    "memory",
+    "draw",
+    "timer",
+    "key",
+    "random",
+    "sound",
+];
+
+impl Instruction {
+    /*
+     * Decodes an opcode, returns None if no platform defines it. The address
+     * of F000 nnnn is in the next word, so it is left unknown, see `decode_at`.
+     */
+    pub fn decode(opcode: u16) -> Option<Self> {
+        use self::Instruction::*;
+
+        // Extract bit nibbles from the opcode
+        let nibbles = (
+            ((opcode & 0xF000) >> 12) as u8,
+            ((opcode & 0x0F00) >> 8) as u8,
+            ((opcode & 0x00F0) >> 4) as u8,
+            (opcode & 0x000F) as u8,
+            );
+
+        let nnn = opcode & 0x0FFF;
+        let kk = (opcode & 0x00FF) as u8;
+        let x = nibbles.1;
+        let y = nibbles.2;
+        let n = nibbles.3;
+
+        let instruction = match nibbles {
+            (0x00, 0x00, 0x0E, 0x00) => Cls,
+            (0x00, 0x00, 0x0E, 0x0E) => Ret,
+            (0x00, 0x00, 0x0C, _) => Scd(n),
+            (0x00, 0x00, 0x0D, _) => Scu(n),
+            (0x00, 0x00, 0x0F, 0x0B) => Scr,
+            (0x00, 0x00, 0x0F, 0x0C) => Scl,
+            (0x00, 0x00, 0x0F, 0x0D) => Exit,
+            (0x00, 0x00, 0x0F, 0x0E) => Low,
+            (0x00, 0x00, 0x0F, 0x0F) => High,
+            (0x01, _, _, _) => Jp(nnn),
+            (0x02, _, _, _) => Call(nnn),
+            (0x03, _, _, _) => SeByte { x, kk },
+            (0x04, _, _, _) => SneByte { x, kk },
+            (0x05, _, _, 0x00) => SeReg { x, y },
+            (0x05, _, _, 0x02) => Save { x, y },
+            (0x05, _, _, 0x03) => Load { x, y },
+            (0x06, _, _, _) => LdByte { x, kk },
+            (0x07, _, _, _) => AddByte { x, kk },
+            (0x08, _, _, 0x00) => LdReg { x, y },
+            (0x08, _, _, 0x01) => Or { x, y },
+            (0x08, _, _, 0x02) => And { x, y },
+            (0x08, _, _, 0x03) => Xor { x, y },
+            (0x08, _, _, 0x04) => AddReg { x, y },
+            (0x08, _, _, 0x05) => Sub { x, y },
+            (0x08, _, _, 0x06) => Shr { x, y },
+            (0x08, _, _, 0x07) => Subn { x, y },
+            (0x08, _, _, 0x0E) => Shl { x, y },
+            (0x09, _, _, 0x00) => SneReg { x, y },
+            (0x0A, _, _, _) => LdI(nnn),
+            (0x0B, _, _, _) => JpV0(nnn),
+            (0x0C, _, _, _) => Rnd { x, kk },
+            (0x0D, _, _, _) => Drw { x, y, n },
+            (0x0E, _, 0x09, 0x0E) => Skp(x),
+            (0x0E, _, 0x0A, 0x01) => Sknp(x),
+            (0x0F, 0x00, 0x00, 0x00) => LdILong(None),
+            (0x0F, _, 0x00, 0x01) => Plane(x),
+            (0x0F, 0x00, 0x00, 0x02) => Audio,
+            (0x0F, _, 0x00, 0x07) => LdVxDt(x),
+            (0x0F, _, 0x00, 0x0A) => LdVxK(x),
+            (0x0F, _, 0x01, 0x05) => LdDtVx(x),
+            (0x0F, _, 0x01, 0x08) => LdStVx(x),
+            (0x0F, _, 0x01, 0x0E) => AddIVx(x),
+            (0x0F, _, 0x02, 0x09) => LdFVx(x),
+            (0x0F, _, 0x03, 0x00) => LdHfVx(x),
+            (0x0F, _, 0x03, 0x03) => LdBVx(x),
+            (0x0F, _, 0x03, 0x0A) => Pitch(x),
+            (0x0F, _, 0x05, 0x05) => LdIVx(x),
+            (0x0F, _, 0x06, 0x05) => LdVxI(x),
+            (0x0F, _, 0x07, 0x05) => LdRVx(x),
+            (0x0F, _, 0x08, 0x05) => LdVxR(x),
+            _ => return None,
+        };
+
+        Some(instruction)
+    }
+
+    /// Decodes the instruction at `addr` of `memory`, with the address of F000 nnnn when it fits.
+    pub fn decode_at(memory: &[u8], addr: usize) -> Option<Self> {
+        let word = |addr: usize| memory.get(addr..addr.checked_add(2)?).map(|bytes| (bytes[0] as u16) << 8 | bytes[1] as u16);
+        match Instruction::decode(word(addr)?)? {
+            Instruction::LdILong(_) => Some(Instruction::LdILong(word(addr + 2))),
+            instruction => Some(instruction),
+        }
+    }
+
+    /*
+     * Encodes the instruction back to its opcode, the first word of F000
+     * nnnn. `decode(i.encode()) == Some(i)`, but for the address of F000 nnnn.
+     */
+    pub fn encode(self) -> u16 {
+        use self::Instruction::*;
+
+        let xy = |base: u16, x: u8, y: u8| base | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4;
+        let xkk = |base: u16, x: u8, kk: u8| base | (x as u16 & 0xF) << 8 | kk as u16;
+        let fx = |x: u8, low: u16| 0xF000 | (x as u16 & 0xF) << 8 | low;
+
+        match self {
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            Scd(n) => 0x00C0 | (n as u16 & 0xF),
+            Scu(n) => 0x00D0 | (n as u16 & 0xF),
+            Scr => 0x00FB,
+            Scl => 0x00FC,
+            Exit => 0x00FD,
+            Low => 0x00FE,
+            High => 0x00FF,
+            Jp(nnn) => 0x1000 | (nnn & 0x0FFF),
+            Call(nnn) => 0x2000 | (nnn & 0x0FFF),
+            SeByte { x, kk } => xkk(0x3000, x, kk),
+            SneByte { x, kk } => xkk(0x4000, x, kk),
+            SeReg { x, y } => xy(0x5000, x, y),
+            Save { x, y } => xy(0x5002, x, y),
+            Load { x, y } => xy(0x5003, x, y),
+            LdByte { x, kk } => xkk(0x6000, x, kk),
+            AddByte { x, kk } => xkk(0x7000, x, kk),
+            LdReg { x, y } => xy(0x8000, x, y),
+            Or { x, y } => xy(0x8001, x, y),
+            And { x, y } => xy(0x8002, x, y),
+            Xor { x, y } => xy(0x8003, x, y),
+            AddReg { x, y } => xy(0x8004, x, y),
+            Sub { x, y } => xy(0x8005, x, y),
+            Shr { x, y } => xy(0x8006, x, y),
+            Subn { x, y } => xy(0x8007, x, y),
+            Shl { x, y } => xy(0x800E, x, y),
+            SneReg { x, y } => xy(0x9000, x, y),
+            LdI(nnn) => 0xA000 | (nnn & 0x0FFF),
+            JpV0(nnn) => 0xB000 | (nnn & 0x0FFF),
+            Rnd { x, kk } => xkk(0xC000, x, kk),
+            Drw { x, y, n } => xy(0xD000, x, y) | (n as u16 & 0xF),
+            Skp(x) => xkk(0xE000, x, 0x9E),
+            Sknp(x) => xkk(0xE000, x, 0xA1),
+            LdILong(_) => 0xF000,
+            Plane(n) => fx(n, 0x01),
+            Audio => 0xF002,
+            LdVxDt(x) => fx(x, 0x07),
+            LdVxK(x) => fx(x, 0x0A),
+            LdDtVx(x) => fx(x, 0x15),
+            LdStVx(x) => fx(x, 0x18),
+            AddIVx(x) => fx(x, 0x1E),
+            LdFVx(x) => fx(x, 0x29),
+            LdHfVx(x) => fx(x, 0x30),
+            LdBVx(x) => fx(x, 0x33),
+            Pitch(x) => fx(x, 0x3A),
+            LdIVx(x) => fx(x, 0x55),
+            LdVxI(x) => fx(x, 0x65),
+            LdRVx(x) => fx(x, 0x75),
+            LdVxR(x) => fx(x, 0x85),
+        }
+    }
+
+    /// Size in bytes, including the address word of `LdILong`.
+    pub fn size(self) -> usize {
+        if let Instruction::LdILong(_) = self { 4 } else { 2 }
+    }
+
+    /// Whether the interpreter runs this instruction on the given platform.
+    pub fn supported_by(self, platform: Platform) -> bool {
+        use self::Instruction::*;
+
+        match self {
+            Scd(_) | Scr | Scl | Exit | Low | High | LdHfVx(_) | LdRVx(_) | LdVxR(_) =>
+                platform.has_schip(),
+            Scu(_) | Save { .. } | Load { .. } | LdILong(_) | Plane(_) | Audio | Pitch(_) =>
+                platform.has_xo_chip(),
+            _ => true,
+        }
+    }
+
+    /// The coarse kind of the instruction, one of `CLASS_NAMES`.
+    pub fn class(self) -> &'static str {
+        use self::Instruction::*;
+
+        match self {
+            Ret | Exit | Jp(_) | Call(_) | JpV0(_) => "flow",
+            SeByte { .. } | SneByte { .. } | SeReg { .. } | SneReg { .. } => "skip",
+            AddByte { .. } | Or { .. } | And { .. } | Xor { .. } | AddReg { .. } |
+            Sub { .. } | Shr { .. } | Subn { .. } | Shl { .. } | AddIVx(_) => "arithmetic",
+            LdByte { .. } | LdReg { .. } | LdI(_) | LdILong(_) | LdFVx(_) | LdHfVx(_) => "load",
+            Save { .. } | Load { .. } | LdBVx(_) | LdIVx(_) | LdVxI(_) | LdRVx(_) | LdVxR(_) => "memory",
+            Cls | Scd(_) | Scu(_) | Scr | Scl | Low | High | Drw { .. } | Plane(_) => "draw",
+            LdVxDt(_) | LdDtVx(_) | LdStVx(_) => "timer",
+            Skp(_) | Sknp(_) | LdVxK(_) => "key",
+            Rnd { .. } => "random",
+            Audio | Pitch(_) => "sound",
+        }
+    }
+
+    /// Whether the instruction conditionally skips the next one.
+    pub fn is_skip(self) -> bool {
+        use self::Instruction::*;
+
+        matches!(self, SeByte { .. } | SneByte { .. } | SeReg { .. } | SneReg { .. } | Skp(_) | Sknp(_))
+    }
+}
+
+// Cowgod's mnemonics, as used in the comments of Machine::run_opcode.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Instruction::*;
+
+        match *self {
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            Scd(n) => write!(f, "SCD {}", n),
+            Scu(n) => write!(f, "SCU {}", n),
+            Scr => write!(f, "SCR"),
+            Scl => write!(f, "SCL"),
+            Exit => write!(f, "EXIT"),
+            Low => write!(f, "LOW"),
+            High => write!(f, "HIGH"),
+            Jp(nnn) => write!(f, "JP {:03X}", nnn),
+            Call(nnn) => write!(f, "CALL {:03X}", nnn),
+            SeByte { x, kk } => write!(f, "SE V{:X}, {:02X}", x, kk),
+            SneByte { x, kk } => write!(f, "SNE V{:X}, {:02X}", x, kk),
+            SeReg { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Save { x, y } => write!(f, "SAVE V{:X} - V{:X}", x, y),
+            Load { x, y } => write!(f, "LOAD V{:X} - V{:X}", x, y),
+            LdByte { x, kk } => write!(f, "LD V{:X}, {:02X}", x, kk),
+            AddByte { x, kk } => write!(f, "ADD V{:X}, {:02X}", x, kk),
+            LdReg { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            AddReg { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Shr { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Subn { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Shl { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SneReg { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LdI(nnn) => write!(f, "LD I, {:03X}", nnn),
+            JpV0(nnn) => write!(f, "JP V0, {:03X}", nnn),
+            Rnd { x, kk } => write!(f, "RND V{:X}, {:02X}", x, kk),
+            Drw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {:X}", x, y, n),
+            Skp(x) => write!(f, "SKP V{:X}", x),
+            Sknp(x) => write!(f, "SKNP V{:X}", x),
+            LdILong(Some(nnnn)) => write!(f, "LD I, {:04X}", nnnn),
+            LdILong(None) => write!(f, "LD I, LONG"),
+            Plane(n) => write!(f, "PLANE {}", n),
+            Audio => write!(f, "AUDIO"),
+            LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            AddIVx(x) => write!(f, "ADD I, V{:X}", x),
+            LdFVx(x) => write!(f, "LD F, V{:X}", x),
+            LdHfVx(x) => write!(f, "LD HF, V{:X}", x),
+            LdBVx(x) => write!(f, "LD B, V{:X}", x),
+            Pitch(x) => write!(f, "PITCH V{:X}", x),
+            LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            LdVxR(x) => write!(f, "LD V{:X}, R", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoded_opcodes_encode_back() {
+        for opcode in 0..=0xFFFF {
+            if let Some(instruction) = Instruction::decode(opcode) {
+                assert_eq!(instruction.encode(), opcode, "{}", instruction);
+                assert_eq!(Instruction::decode(instruction.encode()), Some(instruction));
+            }
+        }
+    }
+
+    #[test]
+    fn long_load_takes_the_next_word() {
+        let memory = [0xF0, 0x00, 0x12, 0x34, 0xF0, 0x00];
+        let instruction = Instruction::decode_at(&memory, 0);
+        assert_eq!(instruction, Some(Instruction::LdILong(Some(0x1234))));
+        assert_eq!(instruction.map(Instruction::size), Some(4));
+        assert_eq!(instruction.map(|instruction| instruction.to_string()), Some("LD I, 1234".to_string()));
+        assert_eq!(Instruction::decode_at(&memory, 4), Some(Instruction::LdILong(None)));
+        assert_eq!(Instruction::decode_at(&memory, 5), None);
+    }
+
+    #[test]
+    fn platform_instructions() {
+        let scroll = Instruction::decode(0x00C4).unwrap();
+        assert!(!scroll.supported_by(Platform::CosmacVip));
+        assert!(scroll.supported_by(Platform::SuperChip));
+        let plane = Instruction::decode(0xF201).unwrap();
+        assert!(!plane.supported_by(Platform::SuperChip));
+        assert!(plane.supported_by(Platform::XoChip));
+    }
+}
@@ -0,0 +1,344 @@
+use std::fmt;
+
+/*
+ * Minimal JSON values for the debug adapter and the trace files: parsing
+ * with `Json::parse` and writing with `Display`. Objects keep their keys in
+ * insertion order.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+static NULL: Json = Json::Null;
+
+impl Json {
+    pub fn parse(text: &str) -> Option<Json> {
+        let mut parser = Parser { bytes: text.as_bytes(), at: 0 };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.at == parser.bytes.len() { Some(value) } else { None }
+    }
+
+    pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+        Json::Object(pairs.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    /// The member of an object, `Null` when missing.
+    pub fn get(&self, key: &str) -> &Json {
+        match *self {
+            Json::Object(ref pairs) => pairs.iter()
+                .find(|(name, _)| name == key)
+                .map_or(&NULL, |(_, value)| value),
+            _ => &NULL,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Json::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as u64),
+            _ => None,
+        }
+    }
+
+    /// An integer, saturated to the i64 range.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Json::Number(n) if n.fract() == 0.0 => Some(n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match *self {
+            Json::Array(ref items) => items,
+            _ => &[],
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(n: u64) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Self {
+        Json::Array(items)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => write_string(f, s),
+            Json::Array(ref items) => {
+                write!(f, "[")?;
+                for (n, item) in items.iter().enumerate() {
+                    if n > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(ref pairs) => {
+                write!(f, "{{")?;
+                for (n, (key, value)) in pairs.iter().enumerate() {
+                    if n > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn whitespace(&mut self) {
+        while self.at < self.bytes.len() && self.bytes[self.at].is_ascii_whitespace() {
+            self.at += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.whitespace();
+        if self.bytes.get(self.at) == Some(&byte) {
+            self.at += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Option<Json> {
+        if self.bytes[self.at..].starts_with(word.as_bytes()) {
+            self.at += word.len();
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.whitespace();
+        match *self.bytes.get(self.at)? {
+            b'n' => self.literal("null", Json::Null),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'[' => {
+                self.at += 1;
+                let mut items = Vec::new();
+                if self.eat(b']') {
+                    return Some(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if self.eat(b']') {
+                        return Some(Json::Array(items));
+                    }
+                    if !self.eat(b',') {
+                        return None;
+                    }
+                }
+            }
+            b'{' => {
+                self.at += 1;
+                let mut pairs = Vec::new();
+                if self.eat(b'}') {
+                    return Some(Json::Object(pairs));
+                }
+                loop {
+                    self.whitespace();
+                    let key = self.string()?;
+                    if !self.eat(b':') {
+                        return None;
+                    }
+                    pairs.push((key, self.value()?));
+                    if self.eat(b'}') {
+                        return Some(Json::Object(pairs));
+                    }
+                    if !self.eat(b',') {
+                        return None;
+                    }
+                }
+            }
+            _ => self.number(),
+        }
+    }
+
+    fn number(&mut self) -> Option<Json> {
+        let start = self.at;
+        while self.at < self.bytes.len() && b"+-0123456789.eE".contains(&self.bytes[self.at]) {
+            self.at += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.at]).ok()?.parse().ok().map(Json::Number)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.bytes.get(self.at) != Some(&b'"') {
+            return None;
+        }
+        self.at += 1;
+
+        let mut s = String::new();
+        loop {
+            let start = self.at;
+            while self.at < self.bytes.len() && self.bytes[self.at] != b'"' && self.bytes[self.at] != b'\\' {
+                self.at += 1;
+            }
+            s.push_str(std::str::from_utf8(&self.bytes[start..self.at]).ok()?);
+
+            match *self.bytes.get(self.at)? {
+                b'"' => {
+                    self.at += 1;
+                    return Some(s);
+                }
+                _ => {
+                    let escape = *self.bytes.get(self.at + 1)?;
+                    self.at += 2;
+                    match escape {
+                        b'n' => s.push('\n'),
+                        b'r' => s.push('\r'),
+                        b't' => s.push('\t'),
+                        b'b' => s.push('\u{8}'),
+                        b'f' => s.push('\u{c}'),
+                        b'u' => {
+                            let code = self.hex4()?;
+                            // A surrogate pair is two escapes.
+                            let code = if (0xD800..0xDC00).contains(&code) && self.bytes[self.at..].starts_with(b"\\u") {
+                                self.at += 2;
+                                let low = self.hex4()?;
+                                0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+                            } else {
+                                code
+                            };
+                            s.push(std::char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        other => s.push(other as char),
+                    }
+                }
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = std::str::from_utf8(self.bytes.get(self.at..self.at + 4)?).ok()?;
+        self.at += 4;
+        u32::from_str_radix(digits, 16).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_print() {
+        let text = r#"{"seq":1,"command":"launch","arguments":{"program":"a b.8o","stopOnEntry":true,"lines":[1,-2.5,null]}}"#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(json.get("seq").as_u64(), Some(1));
+        assert_eq!(json.get("arguments").get("program").as_str(), Some("a b.8o"));
+        assert_eq!(json.get("arguments").get("stopOnEntry").as_bool(), Some(true));
+        assert_eq!(json.get("arguments").get("lines").as_array().len(), 3);
+        assert_eq!(json.get("missing"), &Json::Null);
+        assert_eq!(json.to_string(), text);
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let json = Json::from("quote \" backslash \\ newline \n tab \t bell \u{7}");
+        let text = json.to_string();
+        assert_eq!(text, r#""quote \" backslash \\ newline \n tab \t bell \u0007""#);
+        assert_eq!(Json::parse(&text), Some(json));
+        assert_eq!(Json::parse(r#""é\/""#).unwrap().as_str(), Some("é/"));
+    }
+
+    #[test]
+    fn invalid_documents() {
+        for text in ["", "{", "[1,]", "{\"a\" 1}", "tru", "1 2", "\"open"].iter() {
+            assert_eq!(Json::parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn integers() {
+        let json = Json::parse("[3, -3, 2.5, 1e30, -1e30]").unwrap();
+        let items = json.as_array();
+        assert_eq!(items.iter().map(Json::as_u64).collect::<Vec<_>>(), vec![Some(3), None, None, Some(u64::MAX), None]);
+        assert_eq!(items.iter().map(Json::as_i64).collect::<Vec<_>>(), vec![Some(3), Some(-3), None, Some(i64::MAX), Some(i64::MIN)]);
+    }
+}
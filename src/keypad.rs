@@ -0,0 +1,112 @@
+use std::collections::VecDeque;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyEvent {
+    Down(u8),
+    Up(u8),
+}
+
+impl KeyEvent {
+    pub fn key(self) -> u8 {
+        match self {
+            KeyEvent::Down(key) | KeyEvent::Up(key) => key & 0x0F,
+        }
+    }
+}
+
+/*
+ * The 16 key hexadecimal keypad, fed with key events.
+ *
+ * Frontends queue the events of a frame between frames, and the machine
+ * applies them together before its next instruction, so the emulation only
+ * depends on which events happened in which frame. A key pressed during a
+ * frame reads as down until that frame ends even if it was released right
+ * away, which keeps taps shorter than a frame from being lost.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Keypad {
+    held: [bool; 16],           // Physically down
+    latched: [bool; 16],        // Down at some point of the current frame
+    queue: VecDeque<KeyEvent>,  // Not applied yet
+}
+
+impl Keypad {
+    pub fn new() -> Self {
+        Keypad::default()
+    }
+
+    /// Queues an event, applied before the next instruction.
+    pub fn push(&mut self, event: KeyEvent) {
+        self.queue.push_back(event);
+    }
+
+    pub fn key_down(&mut self, key: u8) {
+        self.push(KeyEvent::Down(key));
+    }
+
+    pub fn key_up(&mut self, key: u8) {
+        self.push(KeyEvent::Up(key));
+    }
+
+    /// Whether the key reads as down in the current frame.
+    pub fn is_down(&self, key: u8) -> bool {
+        self.latched[key as usize & 0x0F]
+    }
+
+    /// Whether the key is physically down, a tapped key is not.
+    pub fn is_held(&self, key: u8) -> bool {
+        self.held[key as usize & 0x0F]
+    }
+
+    /// The lowest key that reads as down.
+    pub fn first_down(&self) -> Option<u8> {
+        self.latched.iter().position(|&down| down).map(|key| key as u8)
+    }
+
+    /// The events queued and not applied yet.
+    pub fn pending(&self) -> impl Iterator<Item = KeyEvent> + '_ {
+        self.queue.iter().cloned()
+    }
+
+    pub fn release_all(&mut self) {
+        self.held = [false; 16];
+        self.latched = [false; 16];
+        self.queue.clear();
+    }
+
+    // Applies the queued events.
+    pub(crate) fn apply_events(&mut self) {
+        for event in self.queue.drain(..) {
+            match event {
+                KeyEvent::Down(_) => {
+                    self.held[event.key() as usize] = true;
+                    self.latched[event.key() as usize] = true;
+                }
+                KeyEvent::Up(_) => self.held[event.key() as usize] = false,
+            }
+        }
+    }
+
+    // A tap is only reported once to Fx0A.
+    pub(crate) fn consume(&mut self, key: u8) {
+        self.latched[key as usize] = self.held[key as usize];
+    }
+
+    // The held and latched keys, one bit per key, for save states.
+    pub(crate) fn key_bits(&self) -> (u16, u16) {
+        let bits = |keys: &[bool; 16]| keys.iter().enumerate().fold(0, |bits, (key, &down)| bits | (down as u16) << key);
+        (bits(&self.held), bits(&self.latched))
+    }
+
+    pub(crate) fn set_key_bits(&mut self, held: u16, latched: u16) {
+        for key in 0..16 {
+            self.held[key] = held & 1 << key != 0;
+            self.latched[key] = latched & 1 << key != 0;
+        }
+    }
+
+    // Forgets the keys tapped during the frame that ended.
+    pub(crate) fn end_frame(&mut self) {
+        self.latched = self.held;
+    }
+}
@@ -0,0 +1,40 @@
+//! Emu8 - A simple CHIP8 emulator.
+//!
+//! The interpreter core lives in [`machine`] and has no dependency on any
+//! frontend. The SDL2 window, keyboard and audio frontend used by the `emu8`
+//! binary is available in [`modules`] when the `sdl` feature is enabled.
+
+extern crate rand;
+#[cfg(feature = "sdl")]
+extern crate sdl2;
+
+pub mod assembler;
+pub mod callgraph;
+pub mod clock;
+pub mod compare;
+pub mod condition;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod history;
+pub mod instruction;
+pub mod json;
+pub mod keypad;
+pub mod machine;
+pub mod movie;
+pub mod profile;
+pub mod quirks;
+pub mod random;
+pub mod rewind;
+pub mod state;
+pub mod trace;
+#[cfg(feature = "sdl")]
+pub mod modules;
+
+pub use instruction::Instruction;
+pub use keypad::{Keypad, KeyEvent};
+pub use machine::{Machine, MachineFault, Output, VRAM_WIDTH, VRAM_HEIGHT, MEMSIZ};
+pub use quirks::{Quirks, Platform};
+pub use state::{Snapshot, StateError};
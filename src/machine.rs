@@ -1,14 +1,30 @@
 use rand;
-use rand::Rng;
-use std::fs::File;
-use std::io::prelude::*;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
 
-pub const VRAM_WIDTH: usize = 64;
-pub const VRAM_HEIGHT: usize = 32;
+use crate::instruction::Instruction;
+use crate::keypad::{ Keypad, KeyEvent };
+use crate::quirks::{ Quirks, Platform };
+use crate::random::{ Generator, RandomSource };
+use crate::state::{ Snapshot, StateError };
+use crate::profile::Profile;
+use crate::trace::TraceRecord;
+
+// The screen array is sized for the SUPER-CHIP high resolution mode,
+// the low resolution mode only uses its LORES_WIDTH x LORES_HEIGHT corner.
+pub const VRAM_WIDTH: usize = 128;
+pub const VRAM_HEIGHT: usize = 64;
+
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
 
 pub const MEMSIZ: usize = 4096;
+pub const XO_MEMSIZ: usize = 65536;
 
-const ROMSIZE: usize = 3584;
+const ROM_ADDR: usize = 0x200;
 
 const FONT_HEX: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -29,16 +45,191 @@ const FONT_HEX: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80  // F
 ];
 
+// SUPER-CHIP large hex font, 10 bytes per digit, stored after FONT_HEX.
+const BIG_FONT_ADDR: usize = 0x50;
+
+const BIG_FONT_HEX: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x3C, 0x7E, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, // B
+    0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xC0, 0xC0  // F
+];
+
+/*
+ * Errors raised by a ROM doing something the CHIP8 cannot do. When returned
+ * by tick_cpu, the machine is left as it was before the faulting instruction,
+ * with the program counter pointing to it.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MachineFault {
+    StackOverflow { pc: usize },
+    StackUnderflow { pc: usize },
+    PcOutOfBounds { pc: usize },
+    MemoryOutOfBounds { addr: usize },
+    InvalidOpcode { opcode: u16, pc: usize },
+}
+
+impl fmt::Display for MachineFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MachineFault::StackOverflow { pc } =>
+                write!(f, "stack overflow, more than 16 nested calls at {:03X}", pc),
+            MachineFault::StackUnderflow { pc } =>
+                write!(f, "stack underflow, return without call at {:03X}", pc),
+            MachineFault::PcOutOfBounds { pc } =>
+                write!(f, "program counter out of memory at {:03X}", pc),
+            MachineFault::MemoryOutOfBounds { addr } =>
+                write!(f, "memory access out of bounds at {:03X}", addr),
+            MachineFault::InvalidOpcode { opcode, pc } =>
+                write!(f, "invalid opcode {:04X} at {:03X}", opcode, pc),
+        }
+    }
+}
+
+impl Error for MachineFault {}
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    Empty,
+    TooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::Io(ref e) => write!(f, "{}", e),
+            RomError::Empty => write!(f, "the ROM is empty"),
+            RomError::TooLarge { size, max } =>
+                write!(f, "the ROM is {} bytes long, at most {} fit in memory", size, max),
+        }
+    }
+}
+
+impl Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> Self {
+        RomError::Io(e)
+    }
+}
+
+/// The CPU registers, as named by the debuggers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Register {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Register {
+    /// Parses v0-vf, i, pc, sp, dt and st, in any case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        match name.as_str() {
+            "i" => Some(Register::I),
+            "pc" => Some(Register::Pc),
+            "sp" => Some(Register::Sp),
+            "dt" | "delay" => Some(Register::Dt),
+            "st" | "buzzer" => Some(Register::St),
+            _ if name.len() == 2 && name.starts_with('v') => {
+                u8::from_str_radix(&name[1..], 16).ok().map(Register::V)
+            }
+            _ => None,
+        }
+    }
+
+    /// The position of the register in `all`.
+    pub fn index(self) -> usize {
+        match self {
+            Register::V(x) => x as usize & 0x0F,
+            Register::I => 16,
+            Register::Pc => 17,
+            Register::Sp => 18,
+            Register::Dt => 19,
+            Register::St => 20,
+        }
+    }
+
+    /// V0-VF, I, PC, SP, DT and ST in this order.
+    pub fn all() -> Vec<Register> {
+        let mut registers: Vec<Register> = (0..16).map(Register::V).collect();
+        registers.extend_from_slice(&[Register::I, Register::Pc, Register::Sp, Register::Dt, Register::St]);
+        registers
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Pc => write!(f, "PC"),
+            Register::Sp => write!(f, "SP"),
+            Register::Dt => write!(f, "DT"),
+            Register::St => write!(f, "ST"),
+        }
+    }
+}
+
+/// A memory access by an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Stops the debuggers when an instruction reads or writes the bytes `start..end`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Watchpoint {
+    pub start: usize,
+    pub end: usize,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, addr: usize, access: Access) -> bool {
+        let kind = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        };
+        kind && addr >= self.start && addr < self.end
+    }
+}
+
 pub struct Output<'a> {
     pub vram: &'a [[u8; VRAM_WIDTH]; VRAM_HEIGHT],
     pub vram_changed: bool,
+    pub width: usize,
+    pub height: usize,
     pub beep: bool,
+    pub audio_pattern: Option<&'a [u8; 16]>,
+    pub pitch: u8,
+    pub halted: bool,
 }
 
 pub struct Machine {
-    ram: [u8; MEMSIZ],                      // Memory bank available for the CPU
-    vram: [[u8; VRAM_WIDTH]; VRAM_HEIGHT],  // Screen array
+    ram: Vec<u8>,                           // Memory bank available for the CPU
+    vram: [[u8; VRAM_WIDTH]; VRAM_HEIGHT],  // Screen array, one bit per plane
     vram_changed: bool,                     // Screen status
+    hires: bool,                            // SUPER-CHIP 128x64 mode
+    plane: u8,                              // XO-CHIP selected bitplanes mask
 
     pc: usize,                              // Program counter
     sp: usize,                              // Stack pointer
@@ -51,23 +242,67 @@ pub struct Machine {
     dt: u8,                                 // Delay timer
     st: u8,                                 // Sound timer
 
-    input: [bool; 16],                      // Keypad array
-    input_reg: usize,                       // Keypad array
+    audio_pattern: Option<[u8; 16]>,        // XO-CHIP 1-bit audio samples
+    pitch: u8,                              // XO-CHIP audio playback rate
+
+    keypad: Keypad,                         // Keypad state and pending events
+    input_reg: usize,                       // Register receiving the Fx0A key
     wait_input: bool,                       // Waiting key
+    wait_key: Option<u8>,                   // Fx0A key pressed, waiting for its release
+    wait_vblank: bool,                      // Waiting the next frame after a draw
+    halted: bool,                           // Program exited with 00FD
+
+    rpl: [u8; 16],                          // SUPER-CHIP RPL user flags
+
+    platform: Platform,                     // Instruction set to decode
+    quirks: Quirks,                         // Behaviour of the ambiguous instructions
+
+    seed: u64,                              // Seed of the RND generator
+    generator: Generator,                   // Kind of RND generator
+    rng: Box<dyn RandomSource>,             // RND generator
+
+    instructions_per_frame: usize,          // Instructions run between two timer ticks
+    frame_cycle: usize,                     // Instructions run in the current frame
+    cycles: u64,                            // Instructions run since reset
+    frames: u64,                            // Frames run since reset
+
+    watchpoints: Vec<Watchpoint>,           // Memory accesses reported to the debuggers
+    watch_hit: Option<(usize, Access)>,     // First watched access since the last take_watch_hit
+    register_watchpoints: Vec<Register>,    // Registers whose changes are reported to the debuggers
+    register_hit: Option<(Register, usize, usize)>,  // First watched change and its old and new values
+
+    tracing: bool,                          // Record every instruction run
+    trace: Vec<TraceRecord>,                // Recorded since the last take_trace
+    trace_writes: Vec<(usize, u8)>,         // Memory written by the current instruction
+
+    profile: Option<Box<Profile>>,          // Execution counts, when profiling
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new(Platform::default(), Quirks::default())
+    }
 }
 
 impl Machine {
-    pub fn new() -> Self {
-        let mut ram = [0u8; MEMSIZ];
+    pub fn new(platform: Platform, quirks: Quirks) -> Self {
+        let mut ram = vec![0u8; platform.memory_size()];
 
-        for i in 0..FONT_HEX.len() {
-            ram[i] = FONT_HEX[i];
-        }
+        ram[..FONT_HEX.len()].copy_from_slice(&FONT_HEX);
+        ram[BIG_FONT_ADDR..BIG_FONT_ADDR + BIG_FONT_HEX.len()].copy_from_slice(&BIG_FONT_HEX);
+
+        // Random unless set_seed is called, like the old thread_rng RND.
+        let seed = rand::random();
+        let generator = Generator::default();
+        let mut rng = generator.source().unwrap();
+        rng.seed(seed);
 
         Machine {
-            ram: ram,
+            ram,
             vram: [[0; VRAM_WIDTH]; VRAM_HEIGHT],
             vram_changed: false,
+            hires: false,
+            plane: 1,
 
             pc: 0x200,
             sp: 0,
@@ -80,53 +315,286 @@ impl Machine {
             dt: 0,
             st: 0,
 
-            input: [false; 16],
+            audio_pattern: None,
+            pitch: 64,
+
+            keypad: Keypad::new(),
             input_reg: 0,
             wait_input: false,
+            wait_key: None,
+            wait_vblank: false,
+            halted: false,
+
+            rpl: [0; 16],
+
+            platform,
+            quirks,
+
+            seed,
+            generator,
+            rng,
+
+            instructions_per_frame: platform.instructions_per_frame(),
+            frame_cycle: 0,
+            cycles: 0,
+            frames: 0,
+
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            register_watchpoints: Vec::new(),
+            register_hit: None,
+
+            tracing: false,
+            trace: Vec::new(),
+            trace_writes: Vec::new(),
+
+            profile: None,
         }
     }
 
-    pub fn get_output(&self) -> Output {
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// The whole address space, font and ROM included.
+    pub fn memory(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn get_output(&self) -> Output<'_> {
         Output {
             vram: &self.vram,
             vram_changed: self.vram_changed,
+            width: self.width(),
+            height: self.height(),
             beep: self.st > 0,
+            audio_pattern: self.audio_pattern.as_ref(),
+            pitch: self.pitch,
+            halted: self.halted,
         }
     }
 
-    pub fn load_rom(&mut self, filename: &str) -> bool {
-        let mut f = File::open(filename).expect(filename);
+    pub fn width(&self) -> usize {
+        if self.hires { VRAM_WIDTH } else { LORES_WIDTH }
+    }
 
-        let mut buffer = [0u8; ROMSIZE];
+    pub fn height(&self) -> usize {
+        if self.hires { VRAM_HEIGHT } else { LORES_HEIGHT }
+    }
 
-        let bytes_read = if let Ok(bytes_read) = f.read(&mut buffer) {
-            bytes_read
-        } else {
-            0
-        };
+    pub fn load_rom(&mut self, filename: &str) -> Result<(), RomError> {
+        let buffer = fs::read(filename)?;
+
+        if buffer.is_empty() {
+            return Err(RomError::Empty);
+        }
+
+        self.load(&buffer)
+    }
 
-        if bytes_read == 0 {
-            println!("ERROR: ROM too large.");
-            return false;
+    // Copies a ROM image to 0x200, memory is left untouched if it does not fit.
+    pub fn load(&mut self, rom: &[u8]) -> Result<(), RomError> {
+        let max = self.ram.len() - ROM_ADDR;
+        if rom.len() > max {
+            return Err(RomError::TooLarge { size: rom.len(), max });
         }
 
-        self.rom_to_ram(&buffer);
+        self.ram[ROM_ADDR..ROM_ADDR + rom.len()].copy_from_slice(rom);
 
-        true
+        Ok(())
     }
 
-    fn rom_to_ram(&mut self, rom: &[u8]) {
-        for (i, &byte) in rom.iter().enumerate() {
-            let addr = 0x200 + i;
-            if addr < 4096 {
-                self.ram[0x200 + i] = byte;
-            } else {
-                break;
+    /// Copies the whole machine state, see `Snapshot::save` to store it.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            platform: self.platform,
+            quirks: self.quirks,
+            ram: self.ram.clone(),
+            vram: self.vram,
+            hires: self.hires,
+            plane: self.plane,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            v: self.v,
+            i: self.i,
+            dt: self.dt,
+            st: self.st,
+            audio_pattern: self.audio_pattern,
+            pitch: self.pitch,
+            input_reg: self.input_reg,
+            wait_input: self.wait_input,
+            wait_key: self.wait_key,
+            wait_vblank: self.wait_vblank,
+            halted: self.halted,
+            rpl: self.rpl,
+            keys_held: self.keypad.key_bits().0,
+            keys_latched: self.keypad.key_bits().1,
+            generator: self.generator,
+            random_state: self.rng.state(),
+            instructions_per_frame: self.instructions_per_frame,
+            frame_cycle: self.frame_cycle,
+            cycles: self.cycles,
+            frames: self.frames,
+        }
+    }
+
+    /*
+     * Replaces the machine state, the platform, quirks and RND generator
+     * come from the snapshot too. The key events queued are kept. A snapshot
+     * taken with a custom RND source needs one set with `set_random_source`
+     * first, otherwise nothing is restored and an error is returned.
+     */
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), StateError> {
+        if snapshot.generator == Generator::Custom && self.generator != Generator::Custom {
+            return Err(StateError::CustomGenerator);
+        }
+        self.platform = snapshot.platform;
+        self.quirks = snapshot.quirks;
+        self.ram.clear();
+        self.ram.extend_from_slice(&snapshot.ram);
+        self.vram = snapshot.vram;
+        self.vram_changed = true;
+        self.hires = snapshot.hires;
+        self.plane = snapshot.plane;
+        self.pc = snapshot.pc;
+        self.sp = snapshot.sp;
+        self.stack = snapshot.stack;
+        self.v = snapshot.v;
+        self.i = snapshot.i;
+        self.dt = snapshot.dt;
+        self.st = snapshot.st;
+        self.audio_pattern = snapshot.audio_pattern;
+        self.pitch = snapshot.pitch;
+        self.input_reg = snapshot.input_reg;
+        self.wait_input = snapshot.wait_input;
+        self.wait_key = snapshot.wait_key;
+        self.wait_vblank = snapshot.wait_vblank;
+        self.halted = snapshot.halted;
+        self.rpl = snapshot.rpl;
+        self.keypad.set_key_bits(snapshot.keys_held, snapshot.keys_latched);
+        if snapshot.generator != self.generator {
+            self.set_generator(snapshot.generator);
+        }
+        self.rng.set_state(snapshot.random_state);
+        self.instructions_per_frame = snapshot.instructions_per_frame;
+        self.frame_cycle = snapshot.frame_cycle;
+        self.cycles = snapshot.cycles;
+        self.frames = snapshot.frames;
+        Ok(())
+    }
+
+    pub fn save_state(&self, path: &Path) -> Result<(), StateError> {
+        self.snapshot().save(path)
+    }
+
+    pub fn load_state(&mut self, path: &Path) -> Result<(), StateError> {
+        let snapshot = Snapshot::load(path)?;
+        self.restore(&snapshot)
+    }
+
+    /// Restarts the RND sequence, the same seed and input give the same run.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng.seed(seed);
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Switches to a built-in RND generator, seeded with the current seed.
+    pub fn set_generator(&mut self, generator: Generator) {
+        if let Some(rng) = generator.source() {
+            self.generator = generator;
+            self.rng = rng;
+            self.rng.seed(self.seed);
+        }
+    }
+
+    pub fn generator(&self) -> Generator {
+        self.generator
+    }
+
+    /// Uses a custom RND generator, seeded with the current seed.
+    pub fn set_random_source(&mut self, rng: Box<dyn RandomSource>) {
+        self.generator = Generator::Custom;
+        self.rng = rng;
+        self.rng.seed(self.seed);
+    }
+
+    pub fn keypad(&self) -> &Keypad {
+        &self.keypad
+    }
+
+    /// Replaces the keypad, which snapshots leave out, along with its pending events.
+    pub fn set_keypad(&mut self, keypad: Keypad) {
+        self.keypad = keypad;
+    }
+
+    /// Queues a key event, see `Keypad`.
+    pub fn push_key(&mut self, event: KeyEvent) {
+        self.keypad.push(event);
+    }
+
+    pub fn set_instructions_per_frame(&mut self, instructions_per_frame: usize) {
+        self.instructions_per_frame = instructions_per_frame.max(1);
+    }
+
+    pub fn instructions_per_frame(&self) -> usize {
+        self.instructions_per_frame
+    }
+
+    /// Instructions run in the current frame, 0 at the start of a frame.
+    pub fn frame_cycle(&self) -> usize {
+        self.frame_cycle
+    }
+
+    /// Instructions run since reset, including the cycles spent waiting.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Frames completed since reset.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Runs one instruction, and ticks the timers after the last instruction of a frame.
+    pub fn step(&mut self) -> Result<(), MachineFault> {
+        if self.frame_cycle == 0 {
+            self.vram_changed = false;
+        }
+        self.tick_cpu()?;
+        self.frame_cycle += 1;
+        if self.frame_cycle >= self.instructions_per_frame {
+            self.tick_timers();
+            self.frame_cycle = 0;
+            self.frames += 1;
+            if let Some(ref mut profile) = self.profile {
+                profile.end_frame();
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the rest of the current frame, a whole frame unless it was stepped into.
+    pub fn run_frame(&mut self) -> Result<(), MachineFault> {
+        loop {
+            self.step()?;
+            if self.frame_cycle == 0 {
+                return Ok(());
             }
         }
     }
 
     pub fn tick_timers(&mut self) {
+        self.keypad.end_frame();
+        self.wait_vblank = false;
         if self.dt > 0 {
             self.dt -= 1
         }
@@ -135,259 +603,1001 @@ impl Machine {
         }
     }
 
-    pub fn tick_cpu(&mut self, input: [bool; 16], debug: bool) {
-        self.input = input;
-        self.vram_changed = false;
+    pub fn tick_cpu(&mut self) -> Result<(), MachineFault> {
+        self.keypad.apply_events();
+
+        if self.wait_vblank || self.halted {
+            self.cycles += 1;
+            if let Some(ref mut profile) = self.profile {
+                profile.record_idle();
+            }
+            return Ok(());
+        }
+
+        // Timers ticking down are not instructions, only the changes made by the CPU are reported.
+        let registers = if self.register_watchpoints.is_empty() { None } else { Some(self.trace_registers()) };
 
         if self.wait_input {
-            for i in 0..input.len() {
-                if input[i] {
-                    self.wait_input = true;
-                    self.v[self.input_reg] = i as u8;
-                    break;
-                }
+            self.wait_key_press();
+            if let Some(ref mut profile) = self.profile {
+                profile.record_idle();
             }
         } else {
             // Read next opcode from memory and run.
-            let opcode = self.get_opcode();
+            let pc = self.pc;
+            let opcode = self.get_opcode()?;
+            let before = if self.tracing { Some(self.trace_registers()) } else { None };
+            if let Some(ref mut profile) = self.profile {
+                profile.record(pc, opcode, &self.stack[..self.sp], &self.ram);
+            }
             self.increment_pc();
-            self.run_opcode(opcode);
+            if let Err(fault) = self.run_opcode(opcode) {
+                self.pc = pc;
+                self.trace_writes.clear();
+                return Err(fault);
+            }
+            if let Some(before) = before {
+                self.record_trace(pc, opcode, &before);
+            }
+        }
+        if let Some(registers) = registers {
+            self.watch_registers(&registers);
+        }
 
-            if debug {
-                println!("Opcode: {:>width_4$X} | PC: {:>width_4$X} | SP: {:>width_4$X} | I: {:>width_4$X}\n\
-                --------------------------------------------\n\
-                V0: {:>width$} | V1: {:>width$} | V2: {:>width$} | V3: {:>width$}\n\
-                V4: {:>width$} | V5: {:>width$} | V6: {:>width$} | V7: {:>width$}\n\
-                V8: {:>width$} | V9: {:>width$} | VA: {:>width$} | VB: {:>width$}\n\
-                VC: {:>width$} | VD: {:>width$} | VE: {:>width$} | VF: {:>width$}\n",
-                opcode, self.pc, self.sp, self.i, self.v[0], self.v[1],
-                self.v[2], self.v[3], self.v[4], self.v[5],  self.v[6], self.v[7],
-                self.v[8], self.v[9], self.v[10], self.v[11], self.v[12],
-                self.v[13], self.v[14], self.v[15], width = 3, width_4 = 4);
+        self.cycles += 1;
+        Ok(())
+    }
+
+    /*
+     * Multi-line table of the CPU registers, used by the debugger
+     * and the fault reports.
+     */
+    pub fn dump_registers(&self) -> String {
+        format!("PC: {:>width_4$X} | SP: {:>width_4$X} | I: {:>width_4$X}\n\
+        --------------------------------------------\n\
+        V0: {:>width$} | V1: {:>width$} | V2: {:>width$} | V3: {:>width$}\n\
+        V4: {:>width$} | V5: {:>width$} | V6: {:>width$} | V7: {:>width$}\n\
+        V8: {:>width$} | V9: {:>width$} | VA: {:>width$} | VB: {:>width$}\n\
+        VC: {:>width$} | VD: {:>width$} | VE: {:>width$} | VF: {:>width$}\n",
+        self.pc, self.sp, self.i, self.v[0], self.v[1],
+        self.v[2], self.v[3], self.v[4], self.v[5],  self.v[6], self.v[7],
+        self.v[8], self.v[9], self.v[10], self.v[11], self.v[12],
+        self.v[13], self.v[14], self.v[15], width = 3, width_4 = 4)
+    }
+
+    pub fn register(&self, register: Register) -> usize {
+        match register {
+            Register::V(x) => self.v[x as usize & 0x0F] as usize,
+            Register::I => self.i,
+            Register::Pc => self.pc,
+            Register::Sp => self.sp,
+            Register::Dt => self.dt as usize,
+            Register::St => self.st as usize,
+        }
+    }
+
+    /// Sets a register, the value is truncated to the register size.
+    pub fn set_register(&mut self, register: Register, value: usize) {
+        match register {
+            Register::V(x) => self.v[x as usize & 0x0F] = value as u8,
+            Register::I => self.i = value & 0xFFFF,
+            Register::Pc => self.pc = value & 0xFFFF,
+            Register::Sp => self.sp = value.min(self.stack.len()),
+            Register::Dt => self.dt = value as u8,
+            Register::St => self.st = value as u8,
+        }
+    }
+
+    /// Return addresses of the active calls, the innermost last.
+    pub fn stack(&self) -> &[usize] {
+        &self.stack[..self.sp]
+    }
+
+    /// Writes a byte of memory, for debuggers. Watchpoints are not triggered.
+    pub fn poke(&mut self, addr: usize, byte: u8) -> Result<(), MachineFault> {
+        match self.ram.get_mut(addr) {
+            Some(cell) => {
+                *cell = byte;
+                Ok(())
             }
+            None => Err(MachineFault::MemoryOutOfBounds { addr }),
         }
     }
 
-    fn increment_pc(&mut self) {
-        self.pc = self.pc + 2;
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != count
     }
 
-    fn get_opcode(&self) -> u16 {
-        (self.ram[self.pc] as u16) << 8 | (self.ram[self.pc + 1] as u16)
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
     }
 
-    pub fn run_opcode(&mut self, opcode: u16) {
-        // Extract bit nibbles from the opcode
-        let nibbles = (
-            (opcode & 0xF000) >> 12 as u8,
-            (opcode & 0x0F00) >> 8 as u8,
-            (opcode & 0x00F0) >> 4 as u8,
-            (opcode & 0x000F) as u8,
-            );
+    /// The first watched access since the last call, the instruction doing it has completed.
+    pub fn take_watch_hit(&mut self) -> Option<(usize, Access)> {
+        self.watch_hit.take()
+    }
 
-        let nnn = (opcode & 0x0FFF) as usize;
-        let kk = (opcode & 0x00FF) as u8;
-        let x = nibbles.1 as usize;
-        let y = nibbles.2 as usize;
-        let n = nibbles.3 as usize;
+    /// Stops the debuggers when an instruction changes V0-VF, I, SP, DT or ST.
+    pub fn add_register_watchpoint(&mut self, register: Register) {
+        if register != Register::Pc && !self.register_watchpoints.contains(&register) {
+            self.register_watchpoints.push(register);
+        }
+    }
 
-        match nibbles {
-            // CLS
-            (0x00, 0x00, 0x0E, 0x00) => {
-                for y in 0..VRAM_HEIGHT {
-                    for x in 0..VRAM_WIDTH {
-                        self.vram[y][x] = 0;
+    pub fn remove_register_watchpoint(&mut self, register: Register) -> bool {
+        let count = self.register_watchpoints.len();
+        self.register_watchpoints.retain(|&r| r != register);
+        self.register_watchpoints.len() != count
+    }
+
+    pub fn register_watchpoints(&self) -> &[Register] {
+        &self.register_watchpoints
+    }
+
+    /// The first watched register change since the last call, with its old and new values.
+    pub fn take_register_hit(&mut self) -> Option<(Register, usize, usize)> {
+        self.register_hit.take()
+    }
+
+    /// Records every instruction run from now on, see `take_trace`. Off by default.
+    pub fn set_tracing(&mut self, tracing: bool) {
+        self.tracing = tracing;
+    }
+
+    pub fn tracing(&self) -> bool {
+        self.tracing
+    }
+
+    /// The instructions run since the last call, the frontend takes them every frame.
+    pub fn take_trace(&mut self) -> Vec<TraceRecord> {
+        std::mem::take(&mut self.trace)
+    }
+
+    /// Counts the instructions run from now on, see `profile`. Turning it off drops the counts.
+    pub fn set_profiling(&mut self, profiling: bool) {
+        if !profiling {
+            self.profile = None;
+        } else if self.profile.is_none() {
+            self.profile = Some(Box::new(Profile::new()));
+        }
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_deref()
+    }
+
+    /// Whether the CPU is blocked by Fx0A.
+    pub fn waiting_key(&self) -> bool {
+        self.wait_input
+    }
+
+    /// Whether the next tick runs no instruction: halted, waiting a key or the next frame.
+    pub fn blocked(&self) -> bool {
+        self.wait_input || self.wait_vblank || self.halted
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    // Fx0A completes on a key press, or once the pressed key is released with the key-release quirk.
+    fn wait_key_press(&mut self) {
+        let key = match self.wait_key {
+            Some(key) if self.keypad.is_held(key) => return,
+            Some(key) => key,
+            None => match self.keypad.first_down() {
+                Some(key) if self.quirks.key_release => {
+                    self.wait_key = Some(key);
+                    // A key tapped within the frame is already released.
+                    if self.keypad.is_held(key) {
+                        return;
                     }
+                    key
                 }
+                Some(key) => key,
+                None => return,
+            },
+        };
+
+        self.keypad.consume(key);
+        self.v[self.input_reg] = key;
+        self.wait_key = None;
+        self.wait_input = false;
+    }
+
+    // The values of `Register::all`, taken before an instruction runs.
+    fn trace_registers(&self) -> Vec<usize> {
+        Register::all().into_iter().map(|register| self.register(register)).collect()
+    }
+
+    fn watch_registers(&mut self, before: &[usize]) {
+        if self.register_hit.is_some() {
+            return;
+        }
+        let after = self.trace_registers();
+        for &register in &self.register_watchpoints {
+            let n = register.index();
+            if before[n] != after[n] {
+                self.register_hit = Some((register, before[n], after[n]));
+                return;
+            }
+        }
+    }
+
+    /*
+     * The PC is left out of the changed registers, records have the next one.
+     * I is kept for F000 nnnn even when unchanged, it names the address.
+     */
+    fn record_trace(&mut self, pc: usize, opcode: u16, before: &[usize]) {
+        let after = self.trace_registers();
+        let long = Instruction::decode(opcode).is_some_and(|instruction| instruction.size() == 4);
+        let registers = Register::all().into_iter()
+            .filter(|&register| register != Register::Pc)
+            .filter(|&register| after[register.index()] != before[register.index()] || (long && register == Register::I))
+            .map(|register| (register, after[register.index()]))
+            .collect();
+
+        self.trace.push(TraceRecord {
+            cycle: self.cycles,
+            frame: self.frames,
+            pc,
+            opcode,
+            registers,
+            writes: std::mem::take(&mut self.trace_writes),
+            next_pc: self.pc,
+        });
+    }
+
+    fn increment_pc(&mut self) {
+        self.pc += 2;
+    }
+
+    // Skips the next instruction, which is 4 bytes long for the XO-CHIP F000 nnnn.
+    fn skip_next(&mut self) -> Result<(), MachineFault> {
+        if self.platform.has_xo_chip() && self.get_opcode()? == 0xF000 {
+            self.increment_pc();
+        }
+        self.increment_pc();
+        Ok(())
+    }
+
+    fn get_opcode(&self) -> Result<u16, MachineFault> {
+        if self.pc + 1 >= self.ram.len() {
+            return Err(MachineFault::PcOutOfBounds { pc: self.pc });
+        }
+        Ok((self.ram[self.pc] as u16) << 8 | (self.ram[self.pc + 1] as u16))
+    }
+
+    fn read(&mut self, addr: usize) -> Result<u8, MachineFault> {
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, Access::Read);
+        }
+        match self.ram.get(addr) {
+            Some(&byte) => Ok(byte),
+            None => Err(MachineFault::MemoryOutOfBounds { addr }),
+        }
+    }
+
+    fn write(&mut self, addr: usize, byte: u8) -> Result<(), MachineFault> {
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, Access::Write);
+        }
+        if self.tracing && addr < self.ram.len() {
+            self.trace_writes.push((addr, byte));
+        }
+        match self.ram.get_mut(addr) {
+            Some(cell) => {
+                *cell = byte;
+                Ok(())
+            }
+            None => Err(MachineFault::MemoryOutOfBounds { addr }),
+        }
+    }
+
+    // Faults when the bytes `addr..addr + length` are not all in memory, checked before an instruction changes anything.
+    fn check_range(&self, addr: usize, length: usize) -> Result<(), MachineFault> {
+        match addr.checked_add(length) {
+            Some(end) if end <= self.ram.len() => Ok(()),
+            _ => Err(MachineFault::MemoryOutOfBounds { addr: addr.max(self.ram.len()) }),
+        }
+    }
+
+    fn watch(&mut self, addr: usize, access: Access) {
+        if self.watch_hit.is_none() && self.watchpoints.iter().any(|w| w.matches(addr, access)) {
+            self.watch_hit = Some((addr, access));
+        }
+    }
+
+    /*
+     * Runs a single opcode. The program counter must already point to the
+     * next instruction, as done by tick_cpu.
+     */
+    pub fn run_opcode(&mut self, opcode: u16) -> Result<(), MachineFault> {
+        match Instruction::decode(opcode) {
+            Some(instruction) if instruction.supported_by(self.platform) => {
+                self.execute(instruction)
+            }
+            _ => Err(MachineFault::InvalidOpcode { opcode, pc: self.pc - 2 }),
+        }
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<(), MachineFault> {
+        use crate::instruction::Instruction::*;
+
+        match instruction {
+            // CLS
+            Cls => {
+                self.clear_vram();
+            }
+            // SCD n: scroll the screen n pixels down (SUPER-CHIP)
+            Scd(n) => {
+                self.scroll(0, n as isize);
+            }
+            // SCU n: scroll the screen n pixels up (XO-CHIP)
+            Scu(n) => {
+                self.scroll(0, -(n as isize));
+            }
+            // SCR: scroll the screen 4 pixels right (SUPER-CHIP)
+            Scr => {
+                self.scroll(4, 0);
+            }
+            // SCL: scroll the screen 4 pixels left (SUPER-CHIP)
+            Scl => {
+                self.scroll(-4, 0);
+            }
+            // EXIT: stop the interpreter (SUPER-CHIP)
+            Exit => {
+                self.halted = true;
+            }
+            // LOW: switch to the 64x32 mode (SUPER-CHIP)
+            Low => {
+                self.hires = false;
+                self.vram = [[0; VRAM_WIDTH]; VRAM_HEIGHT];
+                self.vram_changed = true;
+            }
+            // HIGH: switch to the 128x64 mode (SUPER-CHIP)
+            High => {
+                self.hires = true;
+                self.vram = [[0; VRAM_WIDTH]; VRAM_HEIGHT];
                 self.vram_changed = true;
             }
             // RET
-            (0x00, 0x00, 0x0E, 0x0E) => {
+            Ret => {
+                if self.sp == 0 {
+                    return Err(MachineFault::StackUnderflow { pc: self.pc - 2 });
+                }
                 self.sp -= 1;
                 self.pc = self.stack[self.sp];
             }
             // JP nnn: set program counter to nnn
-            (0x01, _, _, _) => {
+            Jp(nnn) => {
                 self.pc = nnn as usize;
             }
             // CALL nnn: stack[sp++] = pc, pc = nnn
-            (0x02, _, _, _) => {
-                if self.sp < 16 {
-                    self.stack[self.sp] = self.pc;
+            Call(nnn) => {
+                if self.sp >= self.stack.len() {
+                    return Err(MachineFault::StackOverflow { pc: self.pc - 2 });
                 }
+                self.stack[self.sp] = self.pc;
                 self.sp += 1;
-                self.pc = nnn;
+                self.pc = nnn as usize;
             }
             // SE x, kk: if v[x] == kk -> pc += 2
-            (0x03, _, _, _) => {
-                if self.v[x] == kk {
-                    self.increment_pc();
+            SeByte { x, kk } => {
+                if self.v[x as usize] == kk {
+                    self.skip_next()?;
                 }
             }
             // SNE x, kk: if v[x] != kk -> pc += 2
-            (0x04, _, _, _) => {
-                if self.v[x] != kk {
-                    self.increment_pc();
+            SneByte { x, kk } => {
+                if self.v[x as usize] != kk {
+                    self.skip_next()?;
                 }
             }
             // SE x, y: if v[x] == v[y] -> pc += 2
-            (0x05, _, _, 0x00) => {
-                if self.v[x] == self.v[y] {
-                    self.increment_pc();
+            SeReg { x, y } => {
+                if self.v[x as usize] == self.v[y as usize] {
+                    self.skip_next()?;
+                }
+            }
+            // SAVE x, y: save v[x]..v[y] in [i], in either order (XO-CHIP)
+            Save { x, y } => {
+                self.check_range(self.i, (x as usize).abs_diff(y as usize) + 1)?;
+                for (offset, reg) in register_range(x as usize, y as usize).enumerate() {
+                    self.write(self.i + offset, self.v[reg])?;
+                }
+            }
+            // LOAD x, y: load v[x]..v[y] from [i], in either order (XO-CHIP)
+            Load { x, y } => {
+                self.check_range(self.i, (x as usize).abs_diff(y as usize) + 1)?;
+                for (offset, reg) in register_range(x as usize, y as usize).enumerate() {
+                    self.v[reg] = self.read(self.i + offset)?;
                 }
             }
             // LD x, kk: v[x] -> kk
-            (0x06, _, _, _) => {
-                self.v[x] = kk;
+            LdByte { x, kk } => {
+                self.v[x as usize] = kk;
             }
             // ADD x, kk: v[x] = (v[x] + kk) & 0xff
-            (0x07, _, _, _) => {
-                let vx = self.v[x] as u16;
-                let val = kk as u16;
-                let result = vx + val;
-                self.v[x] = result as u8;
+            AddByte { x, kk } => {
+                let x = x as usize;
+                self.v[x] = self.v[x].wrapping_add(kk);
             }
             // LD x, y: v[x] = v[y]
-            (0x08, _, _, 0x00) => {
-                self.v[x] = self.v[y];
+            LdReg { x, y } => {
+                self.v[x as usize] = self.v[y as usize];
             }
             // OR x, y: v[x] = v[x] | v[y];
-            (0x08, _, _, 0x01) => {
-                self.v[x] |= self.v[y];
+            Or { x, y } => {
+                self.v[x as usize] |= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[0x0f] = 0;
+                }
             }
             // AND x, y: v[x] = v[x] & v[y]
-            (0x08, _, _, 0x02) => {
-                self.v[x] &= self.v[y];
+            And { x, y } => {
+                self.v[x as usize] &= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[0x0f] = 0;
+                }
             }
             // XOR x, y: v[x] = v[x] ^ v[y]
-            (0x08, _, _, 0x03) => {
-                self.v[x] ^= self.v[y];
+            Xor { x, y } => {
+                self.v[x as usize] ^= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[0x0f] = 0;
+                }
             }
             // ADD x, y: v[x] += v[y]
-            (0x08, _, _, 0x04) => {
+            AddReg { x, y } => {
+                let (x, y) = (x as usize, y as usize);
                 let vx = self.v[x] as u16;
                 let vy = self.v[y] as u16;
                 let result = vx + vy;
                 self.v[x] = result as u8;
                 self.v[0x0F] = if result > 0xFF { 1 } else { 0 };
             }
-            // SUB x, y: v[x] -= v[y]
-            (0x08, _, _, 0x05) => {
-                self.v[0x0f] = if self.v[x] > self.v[y] { 1 } else { 0 };
+            // SUB x, y: v[x] -= v[y], v[F] = 1 when there is no borrow
+            Sub { x, y } => {
+                let (x, y) = (x as usize, y as usize);
+                let flag = if self.v[x] >= self.v[y] { 1 } else { 0 };
                 self.v[x] = self.v[x].wrapping_sub(self.v[y]);
+                self.v[0x0f] = flag;
             }
-            // SHR x : v[x] = v[x] >> 1
-            (0x08, _, _, 0x06) => {
-                self.v[0x0f] = self.v[x] & 1;
-                self.v[x] >>= 1;
+            // SHR x : v[x] = v[x] >> 1 (v[x] = v[y] >> 1 with the shift quirk)
+            Shr { x, y } => {
+                let (x, y) = (x as usize, y as usize);
+                let value = if self.quirks.shift_uses_vy { self.v[y] } else { self.v[x] };
+                self.v[x] = value >> 1;
+                self.v[0x0f] = value & 1;
             }
-            // SUBN x, y: v[x] = v[y] - v[x]
-            (0x08, _, _, 0x07) => {
-                self.v[0x0f] = if self.v[y] > self.v[x] { 1 } else { 0 };
+            // SUBN x, y: v[x] = v[y] - v[x], v[F] = 1 when there is no borrow
+            Subn { x, y } => {
+                let (x, y) = (x as usize, y as usize);
+                let flag = if self.v[y] >= self.v[x] { 1 } else { 0 };
                 self.v[x] = self.v[y].wrapping_sub(self.v[x]);
+                self.v[0x0f] = flag;
             }
-            // SHL x : v[x] = v[x] << 1
-            (0x08, _, _, 0x0E) => {
-                self.v[0x0f] = (self.v[x] & 0b10000000) >> 7;
-                self.v[x] <<= 1;
+            // SHL x : v[x] = v[x] << 1 (v[x] = v[y] << 1 with the shift quirk)
+            Shl { x, y } => {
+                let (x, y) = (x as usize, y as usize);
+                let value = if self.quirks.shift_uses_vy { self.v[y] } else { self.v[x] };
+                self.v[x] = value << 1;
+                self.v[0x0f] = (value & 0b10000000) >> 7;
             }
             // SNE x, y: v[x] != v[y] -> pc += 2;
-            (0x09, _, _, 0x00) => {
-                if self.v[x] != self.v[y] {
-                    self.increment_pc();
+            SneReg { x, y } => {
+                if self.v[x as usize] != self.v[y as usize] {
+                    self.skip_next()?;
                 }
             }
             // LD I, x : I = nnn
-            (0x0A, _, _, _) => {
-                self.i = nnn;
+            LdI(nnn) => {
+                self.i = nnn as usize;
             }
-            // JP v[0], nnn: pc = v[0] + nnn
-            (0x0B, _, _, _) => {
-                self.pc = (self.v[0] as usize) + nnn;
+            // JP v[0], nnn: pc = v[0] + nnn (pc = v[x] + nnn with the jump quirk)
+            JpV0(nnn) => {
+                let x = (nnn >> 8) as usize;
+                let offset = if self.quirks.jump_uses_vx { self.v[x] } else { self.v[0] };
+                self.pc = (offset as usize) + nnn as usize;
             }
             // RND x, kk: x[x] = random() & kk
-            (0x0C, _, _, _) => {
-                let mut rng = rand::thread_rng();
-                self.v[x] = rng.gen::<u8>() & kk;
+            Rnd { x, kk } => {
+                self.v[x as usize] = self.rng.next_byte(&self.ram) & kk;
             }
             /*
              * DRW x, y, n:
              * Draw a sprite in the pixel v[x], v[y].
              * The number of rows to draw is indicated by n.
              * The sprite is taken out of the memory address [i].
+             * In SUPER-CHIP, n = 0 draws a 16x16 sprite of 32 bytes.
              */
-            (0x0D, _, _, _) => {
-                self.v[0x0f] = 0;
-                for byte in 0..n {
-                    let y = (self.v[y] as usize + byte) % VRAM_HEIGHT;
-                    for bit in 0..8 {
-                        let x = (self.v[x] as usize + bit) % VRAM_WIDTH;
-                        let color = (self.ram[self.i + byte] >> (7 - bit)) & 1;
-                        self.v[0x0f] |= color & self.vram[y][x];
-                        self.vram[y][x] ^= color;
-                    }
+            Drw { x, y, n } => {
+                let (x, y) = (self.v[x as usize] as usize, self.v[y as usize] as usize);
+                if n == 0 && self.platform.has_schip() {
+                    self.draw_sprite(x, y, 16, 2)?;
+                } else {
+                    self.draw_sprite(x, y, n as usize, 1)?;
                 }
-                self.vram_changed = true;
             }
             // SKP x: if key v[x] isDown is true, skip next instruction
-            (0x0E, _, 0x09, 0x0E) => {
-                if self.input[self.v[x] as usize] {
-                    self.increment_pc();
+            Skp(x) => {
+                if self.keypad.is_down(self.v[x as usize]) {
+                    self.skip_next()?;
                 }
             }
             // SKP x: if key v[x] isDown is false, skip next instruction
-            (0x0E, _, 0x0A, 0x01) => {
-                if !(self.input[self.v[x] as usize]) {
-                    self.increment_pc();
+            Sknp(x) => {
+                if !self.keypad.is_down(self.v[x as usize]) {
+                    self.skip_next()?;
                 }
             }
+            // LD I, nnnn: I = the 16 bit word following the instruction (XO-CHIP)
+            LdILong(_) => {
+                self.i = self.get_opcode()? as usize;
+                self.increment_pc();
+            }
+            // PLANE n: select the bitplanes drawn by DRW, CLS and scrolling (XO-CHIP)
+            Plane(n) => {
+                self.plane = n & 0x03;
+            }
+            // AUDIO: load the 16 byte audio pattern from [i] (XO-CHIP)
+            Audio => {
+                let mut pattern = [0u8; 16];
+                self.check_range(self.i, pattern.len())?;
+                for (offset, sample) in pattern.iter_mut().enumerate() {
+                    *sample = self.read(self.i + offset)?;
+                }
+                self.audio_pattern = Some(pattern);
+            }
+            // PITCH x: set the audio playback rate to v[x] (XO-CHIP)
+            Pitch(x) => {
+                self.pitch = self.v[x as usize];
+            }
             // LD v[x], dt: v[x] = dt
-            (0x0F, _, 0x00, 0x07) => {
-                self.v[x] = self.dt;
+            LdVxDt(x) => {
+                self.v[x as usize] = self.dt;
             }
             //LD x, j: wait input key
-            (0x0F, _, 0x00, 0x0A) => {
+            LdVxK(x) => {
                 self.wait_input = true;
-                self.input_reg = x;
+                self.wait_key = None;
+                self.input_reg = x as usize;
             }
             // LD dt, v[x] -> dt = v[x]
-            (0x0F, _, 0x01, 0x05) => {
-                self.dt = self.v[x];
+            LdDtVx(x) => {
+                self.dt = self.v[x as usize];
             }
             // LD st, v[x] -> st = v[x]
-            (0x0F, _, 0x01, 0x08) => {
-                self.st = self.v[x];
+            LdStVx(x) => {
+                self.st = self.v[x as usize];
             }
             // ADD i, v[x] -> I += v[x]
-            (0x0F, _, 0x01, 0x0E) => {
-                self.i += self.v[x] as usize;
-                self.v[0x0f] = if self.i > 0x0F00 { 1 } else { 0 };
+            AddIVx(x) => {
+                self.i += self.v[x as usize] as usize;
+                if self.quirks.i_overflow_flag {
+                    self.v[0x0f] = if self.i > 0x0F00 { 1 } else { 0 };
+                }
             }
             // LD f, v[x] -> i = [memory adress of the number v[x]]
-            (0x0F, _, 0x02, 0x09) => {
-                self.i = (self.v[x] as usize) * 5;
+            LdFVx(x) => {
+                self.i = (self.v[x as usize] as usize) * 5;
+            }
+            // LD HF, v[x] -> i = [memory address of the large number v[x]] (SUPER-CHIP)
+            LdHfVx(x) => {
+                self.i = BIG_FONT_ADDR + (self.v[x as usize] as usize & 0x0F) * 10;
             }
             // LD B, V[x] = loads BCD number in memory
-            (0x0F, _, 0x03, 0x03) => {
-                self.ram[self.i] = self.v[x] / 100;
-                self.ram[self.i + 1] = (self.v[x] % 100) / 10;
-                self.ram[self.i + 2] = self.v[x] % 10;
+            LdBVx(x) => {
+                let vx = self.v[x as usize];
+                self.check_range(self.i, 3)?;
+                self.write(self.i, vx / 100)?;
+                self.write(self.i + 1, (vx % 100) / 10)?;
+                self.write(self.i + 2, vx % 10)?;
             }
             // LD [i], x -> save in i
-            (0x0F, _, 0x05, 0x05) => {
+            LdIVx(x) => {
+                let x = x as usize;
+                self.check_range(self.i, x + 1)?;
                 for i in 0..x + 1 {
-                    self.ram[self.i + i] = self.v[i];
+                    self.write(self.i + i, self.v[i])?;
+                }
+                if self.quirks.load_store_increments_i {
+                    self.i += x + 1;
                 }
             }
             // LD x, [i] -> read of i
-            (0x0F, _, 0x06, 0x05) => {
+            LdVxI(x) => {
+                let x = x as usize;
+                self.check_range(self.i, x + 1)?;
                 for i in 0..x + 1 {
-                    self.v[i] = self.ram[self.i + i];
+                    self.v[i] = self.read(self.i + i)?;
+                }
+                if self.quirks.load_store_increments_i {
+                    self.i += x + 1;
                 }
             }
-            _ => {
-                self.increment_pc();
+            // LD R, v[x] -> save v[0]..v[x] in the RPL user flags (SUPER-CHIP)
+            LdRVx(x) => {
+                let x = x as usize;
+                self.rpl[..=x].copy_from_slice(&self.v[..=x]);
             }
+            // LD v[x], R -> read v[0]..v[x] from the RPL user flags (SUPER-CHIP)
+            LdVxR(x) => {
+                let x = x as usize;
+                self.v[..=x].copy_from_slice(&self.rpl[..=x]);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Clears the selected bitplanes.
+    fn clear_vram(&mut self) {
+        for row in self.vram.iter_mut() {
+            for pixel in row.iter_mut() {
+                *pixel &= !self.plane;
+            }
+        }
+        self.vram_changed = true;
+    }
+
+    // Moves the selected bitplanes dx pixels right and dy pixels down.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let width = self.width() as isize;
+        let height = self.height() as isize;
+        let old = self.vram;
+        for y in 0..height {
+            for x in 0..width {
+                let (src_x, src_y) = (x - dx, y - dy);
+                let moved = if src_x >= 0 && src_x < width && src_y >= 0 && src_y < height {
+                    old[src_y as usize][src_x as usize] & self.plane
+                } else {
+                    0
+                };
+                let pixel = &mut self.vram[y as usize][x as usize];
+                *pixel = (*pixel & !self.plane) | moved;
+            }
+        }
+        self.vram_changed = true;
+    }
+
+    /*
+     * Draws a sprite of `rows` rows, `bytes_per_row` bytes wide, at the
+     * pixel x, y of the current resolution, taken from the memory address [i].
+     * When both XO-CHIP planes are selected, the sprite of the second plane
+     * follows the one of the first plane in memory.
+     */
+    fn draw_sprite(&mut self, x: usize, y: usize, rows: usize, bytes_per_row: usize) -> Result<(), MachineFault> {
+        let width = self.width();
+        let height = self.height();
+        let origin_x = x % width;
+        let origin_y = y % height;
+        let plane = self.plane;
+        let mut addr = self.i;
+        self.check_range(addr, (plane.count_ones() as usize) * rows * bytes_per_row)?;
+        self.v[0x0f] = 0;
+        for &mask in [1u8, 2u8].iter().filter(|&&mask| plane & mask != 0) {
+            for row in 0..rows {
+                if self.quirks.clip_sprites && origin_y + row >= height {
+                    break;
+                }
+                let y = (origin_y + row) % height;
+                for bit in 0..bytes_per_row * 8 {
+                    if self.quirks.clip_sprites && origin_x + bit >= width {
+                        break;
+                    }
+                    let x = (origin_x + bit) % width;
+                    let byte = self.read(addr + row * bytes_per_row + bit / 8)?;
+                    if (byte >> (7 - bit % 8)) & 1 == 1 {
+                        if self.vram[y][x] & mask != 0 {
+                            self.v[0x0f] = 1;
+                        }
+                        self.vram[y][x] ^= mask;
+                    }
+                }
+            }
+            addr += rows * bytes_per_row;
         }
+        self.vram_changed = true;
+        self.wait_vblank = self.quirks.display_wait;
+        Ok(())
+    }
+}
+
+// Registers x..=y, counting down when y < x.
+fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boot(platform: Platform, program: &[u8]) -> Machine {
+        let mut machine = Machine::new(platform, platform.quirks());
+        machine.load(program).unwrap();
+        machine
+    }
+
+    fn run(machine: &mut Machine, instructions: usize) {
+        for _ in 0..instructions {
+            machine.step().unwrap();
+        }
+    }
+
+    // Coordinates of the lit pixels of the selected planes.
+    fn lit(machine: &Machine, planes: u8) -> Vec<(usize, usize)> {
+        let output = machine.get_output();
+        let mut pixels = Vec::new();
+        for y in 0..output.height {
+            for x in 0..output.width {
+                if output.vram[y][x] & planes != 0 {
+                    pixels.push((x, y));
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn large_sprites_in_high_resolution() {
+        // HIGH, I = 20A, DRW V0, V1, 0 twice, then LOW.
+        let mut program = vec![0x00, 0xFF, 0xA2, 0x0A, 0xD0, 0x10, 0xD0, 0x10, 0x00, 0xFE];
+        program.extend_from_slice(&[0xFF, 0x01].repeat(16));
+        let mut machine = boot(Platform::SuperChip, &program);
+
+        run(&mut machine, 3);
+        assert_eq!((machine.width(), machine.height()), (VRAM_WIDTH, VRAM_HEIGHT));
+        let pixels = lit(&machine, 1);
+        assert_eq!(pixels.len(), 16 * 9);
+        assert!(pixels.iter().all(|&(x, y)| y < 16 && (x < 8 || x == 15)));
+        assert_eq!(machine.register(Register::V(0xF)), 0);
+
+        run(&mut machine, 1);
+        assert!(lit(&machine, 1).is_empty());
+        assert_eq!(machine.register(Register::V(0xF)), 1);
+
+        run(&mut machine, 1);
+        assert_eq!((machine.width(), machine.height()), (LORES_WIDTH, LORES_HEIGHT));
+        assert!(lit(&machine, 1).is_empty());
+    }
+
+    #[test]
+    fn scrolling() {
+        // HIGH, I = 210, DRW V0, V1, 1, SCD 2, SCR, SCL, SCL.
+        let program = [0x00, 0xFF, 0xA2, 0x10, 0xD0, 0x11, 0x00, 0xC2, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFC, 0x00, 0x00, 0x80];
+        let mut machine = boot(Platform::SuperChip, &program);
+
+        run(&mut machine, 3);
+        assert_eq!(lit(&machine, 1), vec![(0, 0)]);
+        run(&mut machine, 1);
+        assert_eq!(lit(&machine, 1), vec![(0, 2)]);
+        run(&mut machine, 1);
+        assert_eq!(lit(&machine, 1), vec![(4, 2)]);
+        run(&mut machine, 1);
+        assert_eq!(lit(&machine, 1), vec![(0, 2)]);
+        run(&mut machine, 1);
+        assert!(lit(&machine, 1).is_empty());
+    }
+
+    #[test]
+    fn register_ranges_above_4k() {
+        // I = 1000, V0..V2 = 1, 2, 3, SAVE V0 - V2, LOAD V2 - V0.
+        let program = [0xF0, 0x00, 0x10, 0x00, 0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0x50, 0x22, 0x52, 0x03];
+        let mut machine = boot(Platform::XoChip, &program);
+
+        run(&mut machine, 5);
+        assert_eq!(machine.register(Register::I), 0x1000);
+        assert_eq!(machine.register(Register::Pc), 0x20C);
+        assert_eq!(&machine.memory()[0x1000..0x1003], &[1, 2, 3]);
+
+        run(&mut machine, 1);
+        let registers: Vec<usize> = (0..3).map(|x| machine.register(Register::V(x))).collect();
+        assert_eq!(registers, vec![3, 2, 1]);
+        assert_eq!(machine.register(Register::I), 0x1000);
+    }
+
+    #[test]
+    fn audio_pattern_and_pitch() {
+        // I = 20A, AUDIO, V0 = 40, PITCH V0.
+        let mut program = vec![0xF0, 0x00, 0x02, 0x0A, 0xF0, 0x02, 0x60, 0x40, 0xF0, 0x3A];
+        let pattern: Vec<u8> = (0..16).collect();
+        program.extend_from_slice(&pattern);
+        let mut machine = boot(Platform::XoChip, &program);
+
+        run(&mut machine, 4);
+        let output = machine.get_output();
+        assert_eq!(output.audio_pattern.map(|samples| samples.to_vec()), Some(pattern));
+        assert_eq!(output.pitch, 0x40);
+    }
+
+    #[test]
+    fn bitplanes() {
+        // PLANE 3, I = 20E, DRW V0, V0, 1, PLANE 1, CLS, PLANE 2, SCD 1.
+        let program = [0xF3, 0x01, 0xA2, 0x0E, 0xD0, 0x01, 0xF1, 0x01, 0x00, 0xE0, 0xF2, 0x01, 0x00, 0xC1, 0xC0, 0x60];
+        let mut machine = boot(Platform::XoChip, &program);
+
+        run(&mut machine, 3);
+        assert_eq!(lit(&machine, 1), vec![(0, 0), (1, 0)]);
+        assert_eq!(lit(&machine, 2), vec![(1, 0), (2, 0)]);
+
+        run(&mut machine, 2);
+        assert!(lit(&machine, 1).is_empty());
+        assert_eq!(lit(&machine, 2), vec![(1, 0), (2, 0)]);
+
+        run(&mut machine, 2);
+        assert_eq!(lit(&machine, 2), vec![(1, 1), (2, 1)]);
+    }
+
+    #[test]
+    fn faults_leave_the_machine_unchanged() {
+        // VF = 1, I = FFE, then a store and a sprite that run past the end of memory.
+        let mut machine = boot(Platform::CosmacVip, &[0x6F, 0x01, 0xAF, 0xFE, 0xF3, 0x55, 0xD0, 0x05]);
+        run(&mut machine, 2);
+        for &pc in [0x204, 0x206].iter() {
+            machine.set_register(Register::Pc, pc);
+            let before = machine.snapshot();
+            assert_eq!(machine.step(), Err(MachineFault::MemoryOutOfBounds { addr: MEMSIZ }));
+            assert!(machine.snapshot() == before, "{:03X}", pc);
+        }
+    }
+
+    #[test]
+    fn stack_faults() {
+        let mut machine = boot(Platform::CosmacVip, &[0x00, 0xEE]);
+        assert_eq!(machine.step(), Err(MachineFault::StackUnderflow { pc: 0x200 }));
+        assert_eq!(machine.register(Register::Pc), 0x200);
+
+        let mut machine = boot(Platform::CosmacVip, &[0x22, 0x00]);
+        run(&mut machine, 16);
+        assert_eq!(machine.step(), Err(MachineFault::StackOverflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn roms_that_do_not_fit_are_refused() {
+        let mut machine = boot(Platform::CosmacVip, &[]);
+        assert!(matches!(machine.load(&[1; MEMSIZ]), Err(RomError::TooLarge { size: MEMSIZ, .. })));
+        assert!(machine.memory()[ROM_ADDR..].iter().all(|&byte| byte == 0));
+    }
+
+    // Runs a single 8xy_ opcode with v[x] and v[y] set, returns v[x] and v[F].
+    fn alu(opcode: u16, vx: u8, vy: u8) -> (usize, usize) {
+        let (x, y) = ((opcode >> 8 & 0x0F) as u8, (opcode >> 4 & 0x0F) as u8);
+        let mut machine = boot(Platform::CosmacVip, &[]);
+        machine.set_register(Register::V(y), vy as usize);
+        machine.set_register(Register::V(x), vx as usize);
+        machine.run_opcode(opcode).unwrap();
+        (machine.register(Register::V(x)), machine.register(Register::V(0xF)))
+    }
+
+    #[test]
+    fn subtraction_flags() {
+        // SUB V0, V1 and SUBN V0, V1: the flag is set when there is no borrow.
+        assert_eq!(alu(0x8015, 5, 3), (2, 1));
+        assert_eq!(alu(0x8015, 3, 5), (0xFE, 0));
+        assert_eq!(alu(0x8015, 5, 5), (0, 1));
+        assert_eq!(alu(0x8017, 3, 5), (2, 1));
+        assert_eq!(alu(0x8017, 5, 3), (0xFE, 0));
+        assert_eq!(alu(0x8017, 5, 5), (0, 1));
+
+        // SUB VF, V1 and SUBN VF, V1: the flag overwrites the result.
+        assert_eq!(alu(0x8F15, 3, 1), (1, 1));
+        assert_eq!(alu(0x8F15, 0, 1), (0, 0));
+        assert_eq!(alu(0x8F17, 1, 3), (1, 1));
+        assert_eq!(alu(0x8F17, 3, 1), (0, 0));
+    }
+
+    #[test]
+    fn roms_are_loaded_whole() {
+        let path = std::env::temp_dir().join(format!("emu8-load-{}.ch8", std::process::id()));
+        let file = path.to_str().unwrap();
+        let rom: Vec<u8> = (0..MEMSIZ - ROM_ADDR).map(|i| i as u8).collect();
+
+        fs::write(&path, &rom).unwrap();
+        let mut machine = boot(Platform::CosmacVip, &[]);
+        assert!(machine.load_rom(file).is_ok());
+        assert_eq!(&machine.memory()[ROM_ADDR..], &rom[..]);
+
+        // Larger than the 4 KiB memory, but not than the XO-CHIP one.
+        fs::write(&path, [0xAA; MEMSIZ]).unwrap();
+        assert!(matches!(machine.load_rom(file), Err(RomError::TooLarge { .. })));
+        let mut machine = boot(Platform::XoChip, &[]);
+        assert!(machine.load_rom(file).is_ok());
+        assert_eq!(machine.memory()[ROM_ADDR + MEMSIZ - 1], 0xAA);
+
+        fs::write(&path, b"").unwrap();
+        assert!(matches!(machine.load_rom(file), Err(RomError::Empty)));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(machine.load_rom(file), Err(RomError::Io(_))));
+    }
+
+    // LD V3, K then loops, with the key-release quirk on or off.
+    fn wait_key(key_release: bool) -> Machine {
+        let mut quirks = Platform::CosmacVip.quirks();
+        quirks.key_release = key_release;
+        let mut machine = Machine::new(Platform::CosmacVip, quirks);
+        machine.load(&[0xF3, 0x0A, 0x12, 0x02]).unwrap();
+        machine
+    }
+
+    #[test]
+    fn key_wait_completes_on_press_or_release() {
+        let mut machine = wait_key(false);
+        run(&mut machine, 1);
+        machine.run_frame().unwrap();
+        assert!(machine.waiting_key());
+        machine.push_key(KeyEvent::Down(7));
+        run(&mut machine, 1);
+        assert!(!machine.waiting_key());
+        assert_eq!(machine.register(Register::V(3)), 7);
+
+        let mut machine = wait_key(true);
+        run(&mut machine, 1);
+        machine.push_key(KeyEvent::Down(7));
+        machine.run_frame().unwrap();
+        machine.run_frame().unwrap();
+        assert!(machine.waiting_key());
+        assert_eq!(machine.register(Register::V(3)), 0);
+        machine.push_key(KeyEvent::Up(7));
+        run(&mut machine, 1);
+        assert!(!machine.waiting_key());
+        assert_eq!(machine.register(Register::V(3)), 7);
+    }
+
+    #[test]
+    fn taps_within_a_frame_are_latched() {
+        for &key_release in [false, true].iter() {
+            let mut machine = wait_key(key_release);
+            run(&mut machine, 1);
+            machine.push_key(KeyEvent::Down(7));
+            machine.push_key(KeyEvent::Up(7));
+            run(&mut machine, 1);
+            assert!(!machine.waiting_key(), "{}", key_release);
+            assert_eq!(machine.register(Register::V(3)), 7);
+        }
+
+        // V0 = 7, SKP V0, V1 = 1, then loops.
+        let mut machine = boot(Platform::CosmacVip, &[0x60, 0x07, 0xE0, 0x9E, 0x61, 0x01, 0x12, 0x06]);
+        run(&mut machine, 1);
+        machine.push_key(KeyEvent::Down(7));
+        machine.push_key(KeyEvent::Up(7));
+        run(&mut machine, 1);
+        assert_eq!(machine.register(Register::Pc), 0x206);
+        assert!(machine.keypad().is_down(7) && !machine.keypad().is_held(7));
+
+        // Forgotten once the frame ends.
+        machine.run_frame().unwrap();
+        machine.set_register(Register::Pc, 0x202);
+        run(&mut machine, 1);
+        assert_eq!(machine.register(Register::Pc), 0x204);
+    }
+
+    #[test]
+    fn register_watchpoints_and_traces() {
+        let mut machine = boot(Platform::CosmacVip, &[0x62, 0x01, 0xF2, 0x15, 0xF0, 0x00]);
+        machine.add_register_watchpoint(Register::Dt);
+        machine.set_tracing(true);
+        run(&mut machine, 1);
+        assert_eq!(machine.take_register_hit(), None);
+        run(&mut machine, 1);
+        assert_eq!(machine.take_register_hit(), Some((Register::Dt, 0, 1)));
+
+        let trace = machine.take_trace();
+        assert_eq!(trace[0].registers, vec![(Register::V(2), 1)]);
+        assert_eq!(trace[1].registers, vec![(Register::Dt, 1)]);
+        assert_eq!(trace[1].next_pc, 0x204);
     }
 }
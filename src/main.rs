@@ -1,91 +1,1057 @@
-extern crate rand;
-extern crate sdl2;
+extern crate emu8;
 
-mod modules;
-pub mod machine;
+use emu8::assembler::{ self, Program };
+use emu8::callgraph::CallGraph;
+use emu8::compare;
+use emu8::coverage::{ Coverage, CoverageFormat };
+use emu8::dap::DapServer;
+use emu8::debugger::{ self, Debugger, Remote };
+use emu8::disasm::Disassembly;
+use emu8::gdb::GdbStub;
+use emu8::instruction::CLASS_NAMES;
+use emu8::machine::Machine;
+use emu8::movie::Movie;
+use emu8::quirks::{ Platform, PLATFORM_NAMES, QUIRK_NAMES };
+use emu8::random::{ Generator, GENERATOR_NAMES };
+use emu8::rewind::Rewind;
+use emu8::trace::{ TraceFilter, TraceFormat, TraceWriter };
 
-use modules::*;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{ AtomicBool, Ordering };
 
-use machine::Machine;
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-use std::thread;
-use std::time::{ Instant, Duration };
-use std::env;
+// Seconds of gameplay kept for rewinding by default.
+const REWIND_SECONDS: usize = 10;
+
+// Slowdown of the slow motion by default.
+const SLOW_FACTOR: f64 = 2.0;
+
+// Addresses and subroutines listed by the profile report.
+const PROFILE_TOP: usize = 20;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+// Steps shown around the first difference with a reference trace by default.
+const COMPARE_CONTEXT: usize = 8;
+
+// With --dap stdio, stdout carries the protocol and messages go to stderr.
+static STDOUT_IS_DAP: AtomicBool = AtomicBool::new(false);
+
+macro_rules! message {
+    ($($arg:tt)*) => {
+        if STDOUT_IS_DAP.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+// What the frontend needs besides the machine.
+struct Session {
+    rom_title: String,
+    rom_file: String,               // Save state slots are stored next to it
+    rewind: Rewind,
+    paused: bool,
+    turbo: Option<f64>,             // Speed cap while the turbo key is held, uncapped when None
+    slow: f64,                      // Speed divider of the slow motion
+    slow_motion: bool,
+    record: Option<(String, Movie)>,
+    replay: Option<Movie>,
+    debugger: Debugger,
+    debug: bool,                    // Faults stop in the debugger
+    remote: Option<Box<dyn Remote>>,  // gdb or an editor, replaces the debugger when set
+    trace: Option<(String, TraceWriter)>,
+    coverage: Option<(String, CoverageFormat, Coverage)>,
+    calls: Option<CallGraph>,
+    call_graph_file: Option<String>,  // DOT
+    call_trace_file: Option<String>,  // Chrome trace events
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
+    // The listing goes to stdout without the banner so it can be re-assembled.
+    if args.len() > 1 && args[1] == "disasm" {
+        disasm(&args[0], &args[2..]);
+        return;
+    }
+    if args.len() > 1 && args[1] == "compare" {
+        compare(&args[0], &args[2..]);
+        return;
+    }
+
     let mut loaded: bool = false;
     let mut debug: bool = false;
+    let mut rewind_seconds = REWIND_SECONDS;
+    let mut record_file: Option<String> = None;
+    let mut replay_file: Option<String> = None;
+    let mut seed: Option<u64> = None;
+    let mut generator = Generator::default();
+    let mut instructions_per_frame: Option<usize> = None;
+    let mut gdb_addr: Option<String> = None;
+    let mut dap_addr: Option<String> = None;
+    let mut paused = false;
+    let mut turbo = None;
+    let mut slow = SLOW_FACTOR;
+    let mut slow_motion = false;
+    let mut profile = false;
+    let mut call_graph_file: Option<String> = None;
+    let mut call_trace_file: Option<String> = None;
+    let mut coverage_file: Option<String> = None;
+    let mut coverage_format: Option<CoverageFormat> = None;
+    let mut trace_file: Option<String> = None;
+    let mut trace_format: Option<TraceFormat> = None;
+    let mut trace_filter = TraceFilter::default();
 
-    let mut machine = Machine::new();
+    let mut platform = Platform::default();
+    let mut quirk_overrides: Vec<(String, bool)> = Vec::new();
+    let mut rom_files: Vec<String> = Vec::new();
 
-    let mut rom_title = String::new();
+    // Debug adapter messages go to stdout.
+    let dap_stdio = args.windows(2).any(|pair| pair[0] == "--dap" && pair[1] == "stdio");
 
-    println!("Emu8 - A simple CHIP8 emulator.\nProgrammed by Juan Villacorta.\nVersion {}.\n", VERSION);
+    STDOUT_IS_DAP.store(dap_stdio, Ordering::Relaxed);
+    if !dap_stdio {
+        println!("Emu8 - A simple CHIP8 emulator.\nProgrammed by Juan Villacorta.\nVersion {}.\n", VERSION);
+    }
 
     if args.len() > 1 {
-        for i in 1..args.len() {
-            if args[i] == "-d" || args[i] == "--debug" {
+        let mut i = 1;
+        while i < args.len() {
+            let arg = &args[i];
+            if arg == "-d" || arg == "--debug" {
                 debug = true;
             }
-            else if machine.load_rom(&args[i]) {
-                rom_title.push_str(&args[i]);
+            else if arg == "--rewind" {
+                i += 1;
+                match args.get(i).and_then(|value| value.parse().ok()) {
+                    Some(seconds) => rewind_seconds = seconds,
+                    None => {
+                        message!("ERROR: --rewind expects a number of seconds.");
+                        return;
+                    }
+                }
+            }
+            else if arg == "--seed" {
+                i += 1;
+                match args.get(i).and_then(|value| parse_seed(value)) {
+                    Some(value) => seed = Some(value),
+                    None => {
+                        message!("ERROR: --seed expects a decimal or 0x hexadecimal number.");
+                        return;
+                    }
+                }
+            }
+            else if arg == "--ipf" {
+                i += 1;
+                match args.get(i).and_then(|value| value.parse().ok()) {
+                    Some(ipf) if ipf > 0 => instructions_per_frame = Some(ipf),
+                    _ => {
+                        message!("ERROR: --ipf expects a number of instructions per frame.");
+                        return;
+                    }
+                }
+            }
+            else if arg == "--gdb" || arg == "--dap" {
+                i += 1;
+                match args.get(i) {
+                    Some(addr) if arg == "--gdb" => gdb_addr = Some(addr.clone()),
+                    Some(addr) => dap_addr = Some(addr.clone()),
+                    None => {
+                        message!("ERROR: {} expects an address such as 127.0.0.1:1234.", arg);
+                        return;
+                    }
+                }
+            }
+            else if arg == "--profile" {
+                profile = true;
+            }
+            else if arg == "--call-graph" || arg == "--call-trace" {
+                i += 1;
+                match args.get(i) {
+                    Some(file) if arg == "--call-graph" => call_graph_file = Some(file.clone()),
+                    Some(file) => call_trace_file = Some(file.clone()),
+                    None => {
+                        message!("ERROR: missing output file for {}.", arg);
+                        return;
+                    }
+                }
+            }
+            else if arg == "--coverage" {
+                i += 1;
+                match args.get(i) {
+                    Some(file) => coverage_file = Some(file.clone()),
+                    None => {
+                        message!("ERROR: missing report file for --coverage.");
+                        return;
+                    }
+                }
+            }
+            else if arg == "--coverage-format" {
+                i += 1;
+                match args.get(i).and_then(|name| CoverageFormat::from_name(name)) {
+                    Some(format) => coverage_format = Some(format),
+                    None => {
+                        message!("ERROR: --coverage-format expects listing or lcov.");
+                        return;
+                    }
+                }
+            }
+            else if arg == "--trace" {
+                i += 1;
+                match args.get(i) {
+                    Some(file) => trace_file = Some(file.clone()),
+                    None => {
+                        message!("ERROR: missing trace file for --trace.");
+                        return;
+                    }
+                }
+            }
+            else if arg == "--trace-format" {
+                i += 1;
+                match args.get(i).and_then(|name| TraceFormat::from_name(name)) {
+                    Some(format) => trace_format = Some(format),
+                    None => {
+                        message!("ERROR: --trace-format expects json or binary.");
+                        return;
+                    }
+                }
+            }
+            else if arg == "--trace-pc" || arg == "--trace-frames" {
+                i += 1;
+                match args.get(i).and_then(|value| parse_range(value)) {
+                    Some(range) if arg == "--trace-pc" => trace_filter.addresses.push(range),
+                    Some((start, end)) => trace_filter.frames = Some((start as u64, end as u64)),
+                    None => {
+                        message!("ERROR: {} expects a range such as 0x200-0x2FF.", arg);
+                        return;
+                    }
+                }
+            }
+            else if arg == "--trace-class" {
+                i += 1;
+                let classes = match args.get(i) {
+                    Some(value) => value.split(',').map(|class| class.trim().to_string()).collect::<Vec<_>>(),
+                    None => Vec::new(),
+                };
+                if classes.is_empty() || !classes.iter().all(|class| CLASS_NAMES.contains(&&class[..])) {
+                    message!("ERROR: --trace-class expects a comma separated list of: {}.", CLASS_NAMES.join(", "));
+                    return;
+                }
+                trace_filter.classes.extend(classes);
+            }
+            else if arg == "--paused" {
+                paused = true;
+            }
+            else if arg == "--turbo" || arg == "--slow" {
+                i += 1;
+                match args.get(i).and_then(|value| value.parse::<f64>().ok()) {
+                    Some(factor) if factor >= 1.0 => {
+                        if arg == "--turbo" {
+                            turbo = Some(factor);
+                        } else {
+                            slow = factor;
+                            slow_motion = true;
+                        }
+                    }
+                    _ => {
+                        message!("ERROR: {} expects a factor of at least 1.", arg);
+                        return;
+                    }
+                }
+            }
+            else if arg == "--random" {
+                i += 1;
+                match args.get(i).and_then(|name| Generator::from_name(name)) {
+                    Some(g) => generator = g,
+                    None => {
+                        message!("ERROR: --random expects one of: {}.", GENERATOR_NAMES.join(", "));
+                        return;
+                    }
+                }
+            }
+            else if arg == "--record" || arg == "--replay" {
+                i += 1;
+                match args.get(i) {
+                    Some(file) if arg == "--record" => record_file = Some(file.clone()),
+                    Some(file) => replay_file = Some(file.clone()),
+                    None => {
+                        message!("ERROR: missing movie file for {}.", arg);
+                        return;
+                    }
+                }
+            }
+            else if arg == "-p" || arg == "--platform" || arg == "--quirk" || arg == "--no-quirk" {
+                i += 1;
+                let value = match args.get(i) {
+                    Some(value) => value,
+                    None => {
+                        message!("ERROR: missing value for {}.", arg);
+                        return;
+                    }
+                };
+                if arg == "--quirk" || arg == "--no-quirk" {
+                    quirk_overrides.push((value.clone(), arg == "--quirk"));
+                }
+                else if let Some(p) = Platform::from_name(value) {
+                    platform = p;
+                }
+                else {
+                    message!("ERROR: unknown platform {}, expected one of: {}.", value, PLATFORM_NAMES.join(", "));
+                    return;
+                }
+            }
+            else {
+                rom_files.push(arg.clone());
+            }
+            i += 1;
+        }
+    }
+    else {
+        usage(&args[0]);
+    }
+
+    // The editor names the program to debug.
+    let mut dap = None;
+    if let Some(addr) = dap_addr {
+        let server = if addr == "stdio" {
+            Ok(DapServer::stdio())
+        } else {
+            message!("Waiting for an editor on {}.", addr);
+            DapServer::listen(&addr[..])
+        };
+        let mut server = match server {
+            Ok(server) => server,
+            Err(e) => {
+                message!("ERROR: cannot listen on {}: {}.", addr, e);
+                return;
+            }
+        };
+        let launch = match server.wait_launch() {
+            Ok(Some(launch)) => launch,
+            _ => return,
+        };
+        if let Some(name) = launch.platform {
+            match Platform::from_name(&name) {
+                Some(p) => platform = p,
+                None => {
+                    let _ = server.launch_failed(&format!("unknown platform {}, expected one of: {}", name, PLATFORM_NAMES.join(", ")));
+                    return;
+                }
+            }
+        }
+        rom_files = vec![launch.program];
+        dap = Some(server);
+    }
+
+    let mut quirks = platform.quirks();
+    for (name, value) in &quirk_overrides {
+        if !quirks.set(name, *value) {
+            launch_error(&mut dap, &format!("unknown quirk {}, expected one of: {}.", name, QUIRK_NAMES.join(", ")));
+            return;
+        }
+    }
+
+    let mut machine = Machine::new(platform, quirks);
+    machine.set_generator(generator);
+    if let Some(seed) = seed {
+        machine.set_seed(seed);
+    }
+
+    let mut debugger = Debugger::new();
+
+    let mut rom_title = String::new();
+    let mut program = None;
+
+    for rom_file in &rom_files {
+        match load(&mut machine, rom_file) {
+            Ok(assembled) => {
+                rom_title.push_str(rom_file);
                 loaded = true;
+                if assembled.is_some() {
+                    program = assembled;
+                }
+            }
+            Err(e) => launch_error(&mut dap, &e),
+        }
+    }
+
+    if !loaded {
+        return;
+    }
+
+    // The ROM ends at its last non-zero byte, trailing zeros are neither code nor useful data.
+    let coverage = coverage_file.map(|file| {
+        let rom = &machine.memory()[0x200..];
+        let end = rom.iter().rposition(|&byte| byte != 0).map_or(0, |n| n + 1);
+        let mut coverage = Coverage::new(&rom[..end], platform);
+        if let (Some(ref program), Some(source)) = (&program, rom_files.iter().find(|file| file.ends_with(".8o"))) {
+            coverage.set_source(source, &program.source_map);
+        }
+        // lcov unless asked otherwise or the file is named .txt or .8o.
+        let format = coverage_format.unwrap_or(if file.ends_with(".txt") || file.ends_with(".8o") {
+            CoverageFormat::Listing
+        } else {
+            CoverageFormat::Lcov
+        });
+        machine.set_tracing(true);
+        (file, format, coverage)
+    });
+
+    let calls = if call_graph_file.is_some() || call_trace_file.is_some() {
+        machine.set_tracing(true);
+        Some(CallGraph::new())
+    } else {
+        None
+    };
+
+    let replay = match replay_file {
+        Some(file) => match Movie::load(Path::new(&file)) {
+            Ok(movie) => {
+                if !movie.matches(&machine) {
+                    launch_error(&mut dap, &format!("{} was recorded with another ROM, platform or quirks.", file));
+                    return;
+                }
+                if instructions_per_frame.is_some_and(|ipf| ipf != movie.cycles_per_frame) {
+                    message!("WARNING: {} was recorded at {} instructions per frame, using it.", file, movie.cycles_per_frame);
+                }
+                instructions_per_frame = Some(movie.cycles_per_frame);
+                machine.set_generator(movie.generator);
+                machine.set_seed(movie.seed);
+                Some(movie)
+            }
+            Err(e) => {
+                launch_error(&mut dap, &format!("cannot load movie {}: {}.", file, e));
+                return;
+            }
+        },
+        None => None,
+    };
+
+    machine.set_instructions_per_frame(instructions_per_frame.unwrap_or_else(|| platform.instructions_per_frame()));
+    machine.set_profiling(profile);
+
+    // A recording during a replay starts with the replayed input, seed and generator.
+    let record = record_file.map(|file| (file, Movie::new(&machine)));
+
+    let trace = match trace_file {
+        Some(file) => {
+            // JSON Lines unless asked otherwise or the file is named .bin.
+            let format = trace_format.unwrap_or(if file.ends_with(".bin") { TraceFormat::Binary } else { TraceFormat::Json });
+            match TraceWriter::create(Path::new(&file), format, trace_filter) {
+                Ok(writer) => {
+                    machine.set_tracing(true);
+                    Some((file, writer))
+                }
+                Err(e) => {
+                    launch_error(&mut dap, &format!("cannot create trace {}: {}.", file, e));
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut remote: Option<Box<dyn Remote>> = None;
+    if let Some(mut server) = dap {
+        let source = rom_files.iter().find(|file| file.ends_with(".8o")).map(Path::new);
+        if server.launched(program.take(), source).is_err() {
+            return;
+        }
+        remote = Some(Box::new(server));
+    }
+    else if let Some(addr) = gdb_addr {
+        match GdbStub::bind(&addr[..]) {
+            Ok(gdb) => {
+                message!("Waiting for gdb on {}.", addr);
+                remote = Some(Box::new(gdb));
+            }
+            Err(e) => {
+                message!("ERROR: cannot listen on {}: {}.", addr, e);
+                return;
             }
         }
     }
     else {
-        println!("Usage: {} <args> <ROM file>", args[0]);
-        println!("Arguments:");
-        println!("    -d | --debug: will load with debug output.");
+        if let Some(program) = program {
+            debugger.add_symbols(&program.labels);
+            for &(addr, _) in &program.breakpoints {
+                debugger.set_breakpoint(addr as usize);
+            }
+        }
+        if debug {
+            debugger.interrupt();
+        }
+    }
+
+    // Going back from the debugger would drop the input recorded since.
+    if record.is_some() {
+        debugger.disable_reverse("while recording a movie");
+        if let Some(ref mut remote) = remote {
+            remote.debugger().disable_reverse("while recording a movie");
+        }
     }
 
-    if loaded {
-        let sleep_duration = Duration::from_millis(1);
-        let mut last_timers_update_time = Instant::now();
-        let mut last_cpu_update_time = Instant::now();
+    let session = Session {
+        rom_title,
+        rom_file: rom_files[0].clone(),
+        rewind: Rewind::new(rewind_seconds),
+        paused,
+        turbo,
+        slow,
+        slow_motion,
+        record,
+        replay,
+        debugger,
+        debug,
+        remote,
+        trace,
+        coverage,
+        calls,
+        call_graph_file,
+        call_trace_file,
+    };
 
-        let sdl_context = sdl2::init().unwrap();
+    run(machine, session);
+}
 
-        let mut screen = Screen::new(&sdl_context);
-        let mut events = Events::new(&sdl_context);
-        let sound = Sound::new(&sdl_context);
+// Reports an error that stops the program, to the editor once it asked for a launch.
+fn launch_error(dap: &mut Option<DapServer>, message: &str) {
+    match dap {
+        Some(ref mut server) => {
+            let _ = server.launch_failed(message);
+        }
+        None => message!("ERROR: {}", message),
+    }
+}
+
+fn parse_seed(value: &str) -> Option<u64> {
+    if value.starts_with("0x") || value.starts_with("0X") {
+        u64::from_str_radix(&value[2..], 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
 
-        screen.set_title(&rom_title);
+// An inclusive range such as 0x200-0x2FF, or a single number.
+fn parse_range(value: &str) -> Option<(usize, usize)> {
+    let (start, end) = match value.find('-') {
+        Some(n) => (debugger::parse_number(&value[..n])?, debugger::parse_number(&value[n + 1..])?),
+        None => (debugger::parse_number(value)?, debugger::parse_number(value)?),
+    };
+    if start <= end { Some((start, end)) } else { None }
+}
 
-        while let Ok(keypad) = events.poll() {
-            if !events.should_run {
-                break;
+// Loads a ROM, or assembles and loads an Octo source when it ends in .8o and
+// returns its symbols. Errors are returned rather than printed for the editors.
+fn load(machine: &mut Machine, file: &str) -> Result<Option<Program>, String> {
+    let (rom, program) = if file.ends_with(".8o") {
+        let source = fs::read_to_string(file).map_err(|e| format!("cannot open {}: {}.", file, e))?;
+        match assembler::assemble(&source) {
+            Ok(program) => (program.rom.clone(), Some(program)),
+            Err(e) => {
+                let mut message = format!("{}:{}", file, e);
+                if let Some(line) = source.lines().nth(e.line - 1) {
+                    message.push_str(&format!("\n    {}\n    {:>width$}", line, "^", width = e.column));
+                }
+                return Err(message);
+            }
+        }
+    } else {
+        let rom = fs::read(file).map_err(|e| format!("cannot open {}: {}.", file, e))?;
+        if rom.is_empty() {
+            return Err(format!("cannot read {}.", file));
+        }
+        (rom, None)
+    };
+
+    machine.load(&rom).map_err(|e| format!("cannot load {}: {}.", file, e))?;
+    Ok(program)
+}
+
+fn disasm(program: &str, args: &[String]) {
+    let mut platform = Platform::default();
+    let mut rom_file = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == "-p" || args[i] == "--platform" {
+            i += 1;
+            match args.get(i).and_then(|name| Platform::from_name(name)) {
+                Some(p) => platform = p,
+                None => {
+                    eprintln!("ERROR: expected one of: {}.", PLATFORM_NAMES.join(", "));
+                    return;
+                }
             }
+        }
+        else {
+            rom_file = Some(&args[i]);
+        }
+        i += 1;
+    }
+
+    let rom_file = match rom_file {
+        Some(rom_file) => rom_file,
+        None => {
+            eprintln!("Usage: {} disasm [-p <platform>] <ROM file>", program);
+            return;
+        }
+    };
+
+    match fs::read(rom_file) {
+        Ok(rom) => {
+            println!("# {} disassembled by Emu8 {}.", rom_file, VERSION);
+            print!("{}", Disassembly::new(&rom, platform));
+        }
+        Err(e) => eprintln!("ERROR: cannot open {}: {}.", rom_file, e),
+    }
+}
+
+// Runs a ROM in lockstep with the trace of another emulator, without a window.
+fn compare(program: &str, args: &[String]) {
+    let mut platform = Platform::default();
+    let mut quirk_overrides: Vec<(String, bool)> = Vec::new();
+    let mut instructions_per_frame = None;
+    let mut context = COMPARE_CONTEXT;
+    let mut files = Vec::new();
 
-            if Instant::now() - last_timers_update_time > Duration::from_millis(20) {
-                machine.tick_timers();
-                last_timers_update_time = Instant::now();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == "-p" || arg == "--platform" || arg == "--quirk" || arg == "--no-quirk" || arg == "--ipf" || arg == "--context" {
+            i += 1;
+            let value = match args.get(i) {
+                Some(value) => value,
+                None => {
+                    println!("ERROR: missing value for {}.", arg);
+                    return;
+                }
+            };
+            if arg == "--quirk" || arg == "--no-quirk" {
+                quirk_overrides.push((value.clone(), arg == "--quirk"));
+            }
+            else if arg == "--ipf" || arg == "--context" {
+                match value.parse() {
+                    Ok(n) if arg == "--context" => context = n,
+                    Ok(n) if n > 0 => instructions_per_frame = Some(n),
+                    _ => {
+                        println!("ERROR: {} expects a number.", arg);
+                        return;
+                    }
+                }
+            }
+            else if let Some(p) = Platform::from_name(value) {
+                platform = p;
             }
+            else {
+                println!("ERROR: unknown platform {}, expected one of: {}.", value, PLATFORM_NAMES.join(", "));
+                return;
+            }
+        }
+        else {
+            files.push(arg);
+        }
+        i += 1;
+    }
+
+    let (reference_file, rom_file) = match files[..] {
+        [reference_file, rom_file] => (reference_file, rom_file),
+        _ => {
+            println!("Usage: {} compare [-p <platform>] [--quirk <name>] [--ipf <number>] [--context <steps>] <reference trace> <ROM file>", program);
+            return;
+        }
+    };
 
-            if Instant::now() - last_cpu_update_time > Duration::from_millis(2) {
-                machine.tick_cpu(keypad, debug);
-                last_cpu_update_time = Instant::now();
+    let mut quirks = platform.quirks();
+    for (name, value) in &quirk_overrides {
+        if !quirks.set(name, *value) {
+            println!("ERROR: unknown quirk {}, expected one of: {}.", name, QUIRK_NAMES.join(", "));
+            return;
+        }
+    }
+
+    let reference = match compare::load_reference(Path::new(reference_file)) {
+        Ok(reference) => reference,
+        Err(e) => {
+            println!("ERROR: cannot load reference trace {}: {}.", reference_file, e);
+            return;
+        }
+    };
+
+    let mut machine = Machine::new(platform, quirks);
+    if let Err(e) = load(&mut machine, rom_file) {
+        println!("ERROR: {}", e);
+        return;
+    }
+    machine.set_instructions_per_frame(instructions_per_frame.unwrap_or_else(|| platform.instructions_per_frame()));
+
+    match compare::compare(&mut machine, &reference, context) {
+        Ok(steps) => println!("All {} steps of {} match.", steps, reference_file),
+        Err(difference) => print!("{}", difference),
+    }
+}
+
+fn usage(program: &str) {
+    println!("Usage: {} <args> <ROM file or Octo .8o source>", program);
+    println!("       {} disasm [-p <platform>] <ROM file>", program);
+    println!("       {} compare [-p <platform>] [--quirk <name>] [--ipf <number>] [--context <steps>] <reference trace> <ROM file>", program);
+    println!("Arguments:");
+    println!("    -d | --debug: start stopped in the debugger, and stop in it on faults.");
+    println!("    --ipf <number>: instructions per 60 Hz frame, the default depends on the platform.");
+    println!("    --gdb <address>: wait for gdb on a TCP address such as 127.0.0.1:1234.");
+    println!("    --dap <stdio | address>: debug adapter for editors, on stdin and stdout or a TCP address.");
+    println!("                             The editor launches the program.");
+    println!("    --paused: start paused, see the keys below.");
+    println!("    --turbo <factor>: caps the speed while the turbo key is held, uncapped by default.");
+    println!("    --slow <factor>: start in slow motion, <factor> times slower, default {}.", SLOW_FACTOR);
+    println!("    --seed <number>: seed of the random numbers, for reproducible runs.");
+    println!("    --random <name>: random number generator, one of: {}.", GENERATOR_NAMES.join(", "));
+    println!("    --record <file>: record the key presses and releases of every frame to a movie file.");
+    println!("    --replay <file>: replay a movie recorded with the same ROM.");
+    println!("    --profile: print on exit the {} busiest addresses and subroutines, and the opcode classes.", PROFILE_TOP);
+    println!("    --call-graph <file>: write the subroutine calls as a Graphviz DOT graph on exit.");
+    println!("    --call-trace <file>: write every call as Chrome trace events on exit, for chrome://tracing or Perfetto.");
+    println!("    --coverage <file>: write the instructions run and the skip directions taken on exit.");
+    println!("    --coverage-format <lcov | listing>: lcov, the default, or an annotated disassembly, the default for .txt files.");
+    println!("    --trace <file>: log every instruction run to a file, see --trace-format.");
+    println!("    --trace-format <json | binary>: JSON Lines, the default, or binary, the default for .bin files.");
+    println!("    --trace-pc <start>-<end>: only trace instructions at these addresses, can be repeated.");
+    println!("    --trace-class <names>: only trace these instruction classes, comma separated.");
+    println!("                           Classes: {}.", CLASS_NAMES.join(", "));
+    println!("    --trace-frames <start>-<end>: only trace these frames.");
+    println!("    --rewind <seconds>: gameplay kept for rewinding, default {}, 0 disables it.", REWIND_SECONDS);
+    println!("    -p | --platform <name>: quirks preset, one of: {}.", PLATFORM_NAMES.join(", "));
+    println!("    --quirk <name> | --no-quirk <name>: enable or disable a single quirk.");
+    println!("    Quirks: {}.", QUIRK_NAMES.join(", "));
+    println!("Keys:");
+    println!("    F1-F10: load state slot, Shift + F1-F10: save state slot.");
+    println!("    Backspace (hold): rewind.");
+    println!("    P: pause, N: advance one frame, M: slow motion, Tab (hold): turbo.");
+    println!("    F12: break into the debugger, type help at its prompt for the commands.");
+}
+
+fn run(mut machine: Machine, mut session: Session) {
+    use emu8::modules::*;
+
+    use std::io::{ self, Write };
+    use std::mem;
+    use std::path::PathBuf;
+    use std::time::{ Duration, Instant };
+    use emu8::clock::{ FrameClock, FRAMES_PER_SECOND };
+    use emu8::debugger::{ Action, Stop };
+
+    let sdl_context = sdl2::init().unwrap();
+
+    let mut screen = Screen::new(&sdl_context);
+    let mut events = Events::new(&sdl_context);
+    let mut sound = Sound::new(&sdl_context);
+
+    let rom_title = session.rom_title.clone();
+    screen.set_title(&window_title(&rom_title, session.paused, session.slow_motion));
+
+    let mut clock = FrameClock::new();
+    let mut faulted = false;
+
+    // An uncapped turbo runs frames back to back, and only shows some of them.
+    let present_interval = Duration::from_secs(1) / FRAMES_PER_SECOND;
+    let mut last_present = Instant::now();
+    let mut screen_changed = false;
+
+    // Key events wait here while no frame runs, so releases are never lost.
+    let mut pending_keys = Vec::new();
+
+    while let Some(key_events) = events.poll() {
+        if !events.should_run {
+            break;
+        }
+        pending_keys.extend(key_events);
+
+        // Going back in time would break the recorded or replayed input sequence.
+        let movie_active = session.record.is_some() || session.replay.is_some();
+
+        let mut advance = false;
+
+        for hotkey in events.hotkeys.drain(..) {
+            match hotkey {
+                Hotkey::Pause => session.paused = !session.paused,
+                Hotkey::FrameAdvance => {
+                    session.paused = true;
+                    advance = true;
+                }
+                Hotkey::SlowMotion => session.slow_motion = !session.slow_motion,
+                Hotkey::Break => match session.remote {
+                    Some(ref mut remote) => {
+                        if remote.interrupt().is_err() {
+                            message!("ERROR: lost the debugger connection.");
+                        }
+                    }
+                    None => session.debugger.interrupt(),
+                },
+                Hotkey::SaveState(slot) => {
+                    let path = PathBuf::from(format!("{}.state{}", session.rom_file, slot));
+                    match machine.save_state(&path) {
+                        Ok(()) => message!("Saved state {} to {}.", slot, path.display()),
+                        Err(e) => message!("ERROR: cannot save state {}: {}.", slot, e),
+                    }
+                }
+                Hotkey::LoadState(_) if movie_active => {
+                    message!("ERROR: states cannot be loaded while recording or replaying a movie.");
+                }
+                Hotkey::LoadState(slot) => {
+                    let path = PathBuf::from(format!("{}.state{}", session.rom_file, slot));
+                    match machine.load_state(&path) {
+                        Ok(()) => {
+                            message!("Loaded state {} from {}.", slot, path.display());
+                            faulted = false;
+                        }
+                        Err(e) => message!("ERROR: cannot load state {}: {}.", slot, e),
+                    }
+                }
+            }
+            if !faulted {
+                screen.set_title(&window_title(&rom_title, session.paused, session.slow_motion));
             }
+        }
 
-            {
-                let output = machine.get_output();
+        if let Some(ref mut remote) = session.remote {
+            match remote.poll(&mut machine) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    message!("ERROR: debugger connection: {}.", e);
+                    break;
+                }
+            }
+        }
+
+        // The window is frozen while the debugger reads commands from the terminal.
+        if session.debugger.stopped().is_some() {
+            sound.stop_beep();
+            let stdin = io::stdin();
+            match session.debugger.prompt(&mut machine, &mut stdin.lock(), &mut io::stdout()) {
+                Ok(Action::Resume) => {}
+                Ok(Action::Quit) | Err(_) => break,
+            }
+            faulted = false;
+            screen.set_title(&window_title(&rom_title, session.paused, session.slow_motion));
+            let output = machine.get_output();
+            screen.draw(output.vram, output.width, output.height);
+            clock.reset();
+        }
+
+        let rewinding = events.rewind && !movie_active;
+        let remote_stopped = session.remote.as_ref().is_some_and(|remote| remote.is_stopped());
+        let running = (!session.paused || advance) && !remote_stopped;
+
+        let uncapped = events.turbo && session.turbo.is_none() && running && !rewinding;
+        clock.set_speed(if events.turbo {
+            session.turbo.unwrap_or(1.0)
+        } else if session.slow_motion {
+            1.0 / session.slow
+        } else {
+            1.0
+        });
+
+        // Keep the last frame on screen until the user closes the window or rewinds.
+        if faulted && !rewinding {
+            sound.stop_beep();
+            clock.wait();
+            continue;
+        }
+
+        // While rewinding, every frame restores the one before it instead of running.
+        if rewinding {
+            if let Some(snapshot) = session.rewind.pop() {
+                if let Err(e) = machine.restore(&snapshot) {
+                    message!("ERROR: cannot rewind: {}.", e);
+                } else if faulted {
+                    screen.set_title(&window_title(&rom_title, session.paused, session.slow_motion));
+                    faulted = false;
+                }
+            }
+        } else if running {
+            // A frame cut by a breakpoint is finished without new input.
+            let frame = machine.frames() as usize;
+            let new_frame = machine.frame_cycle() == 0;
+            let key_events = match session.replay {
+                _ if !new_frame => Vec::new(),
+                Some(ref movie) => match movie.frame(frame) {
+                    Some(key_events) => {
+                        pending_keys.clear();
+                        key_events.to_vec()
+                    }
+                    None => {
+                        message!("Replay finished after {} frames.", frame);
+                        session.replay = None;
+                        mem::take(&mut pending_keys)
+                    }
+                },
+                None => mem::take(&mut pending_keys),
+            };
+            let mut record_failed = false;
+            if let Some((_, ref mut movie)) = session.record {
+                if new_frame {
+                    // Frames run from the debugger prompt had no input.
+                    movie.frames.resize(frame, Vec::new());
+                    if let Err(e) = movie.record(&key_events) {
+                        message!("ERROR: {}, the recording stops at frame {}.", e, frame);
+                        record_failed = true;
+                    }
+                }
+            }
+            if record_failed {
+                if let Some((path, movie)) = session.record.take() {
+                    save_movie(&path, &movie);
+                }
+            }
+            for event in key_events {
+                machine.push_key(event);
+            }
+
+            let stop = match session.remote {
+                // The client is told why the program stopped, and stays in charge.
+                Some(ref mut remote) => {
+                    if let Err(e) = remote.run_frame(&mut machine) {
+                        message!("ERROR: debugger connection: {}.", e);
+                    }
+                    None
+                }
+                None => session.debugger.run_frame(&mut machine),
+            };
+            match stop {
+                Some(Stop::Fault(fault)) => {
+                    message!("FAULT: {}.\n{}", fault, machine.dump_registers());
+                    screen.set_title(&format!("{} - FAULT: {}", rom_title, fault));
+                    faulted = true;
+                    // Without --debug the last frame stays on screen as before.
+                    if !session.debug {
+                        session.debugger.resume();
+                    }
+                }
+                Some(Stop::Halted) | None => {}
+                Some(_) => screen.set_title(&format!("{} - DEBUGGER", rom_title)),
+            }
+            if machine.frame_cycle() == 0 {
+                session.rewind.push(&machine.snapshot());
+            }
+        }
+
+        {
+            let output = machine.get_output();
+
+            if output.halted {
+                break;
+            }
+
+            screen_changed |= output.vram_changed;
+            if screen_changed && (!uncapped || last_present.elapsed() >= present_interval) {
+                screen.draw(output.vram, output.width, output.height);
+                screen_changed = false;
+                last_present = Instant::now();
+            }
 
-                if output.vram_changed {
-                    screen.draw(output.vram);
+            sound.set_pattern(output.audio_pattern, output.pitch);
+
+            if output.beep && running {
+                sound.start_beep();
+            } else {
+                sound.stop_beep();
+            }
+        }
+
+        if machine.tracing() {
+            let records = machine.take_trace();
+            if let Some((_, _, ref mut coverage)) = session.coverage {
+                coverage.record(&records);
+            }
+            if let Some(ref mut calls) = session.calls {
+                calls.record(&records);
+            }
+            if let Some((ref path, ref mut writer)) = session.trace {
+                if let Err(e) = writer.write(&records) {
+                    message!("ERROR: cannot write trace {}: {}.", path, e);
+                    session.trace = None;
+                    machine.set_tracing(session.coverage.is_some() || session.calls.is_some());
                 }
+            }
+        }
+
+        if uncapped {
+            clock.reset();
+        } else {
+            clock.wait();
+        }
+    }
+
+    if let Some(profile) = machine.profile() {
+        let symbolize = |addr| session.debugger.symbolize(addr);
+        message!("\n{}", profile.report(machine.memory(), PROFILE_TOP, &symbolize).trim_end());
+    }
+
+    let records = machine.take_trace();
 
-                if output.beep {
-                    sound.start_beep();
+    if let Some((path, format, mut coverage)) = session.coverage {
+        coverage.record(&records);
+        let (run, instructions) = coverage.instructions();
+        let (taken, branches) = coverage.branches();
+        match coverage.save(Path::new(&path), format, &session.rom_file) {
+            Ok(()) => message!("Coverage: {} of {} instructions run, {} of {} skip directions taken, written to {}.",
+                run, instructions, taken, branches, path),
+            Err(e) => message!("ERROR: cannot write coverage {}: {}.", path, e),
+        }
+    }
+
+    if let Some(mut calls) = session.calls {
+        calls.record(&records);
+        let debugger = &session.debugger;
+        let symbolize = |addr| debugger.symbolize(addr);
+        let outputs = [(session.call_graph_file, true), (session.call_trace_file, false)];
+        for (path, dot) in outputs.iter().filter_map(|(path, dot)| path.as_ref().map(|path| (path, *dot))) {
+            let written = fs::File::create(path).and_then(|file| {
+                let mut out = io::BufWriter::new(file);
+                if dot {
+                    calls.write_dot(&mut out, &symbolize)?;
                 } else {
-                    sound.stop_beep();
+                    calls.write_chrome_trace(&mut out, &symbolize)?;
                 }
+                out.flush()
+            });
+            match written {
+                Ok(()) => message!("Wrote the calls of {} instructions to {}.", calls.instructions(), path),
+                Err(e) => message!("ERROR: cannot write {}: {}.", path, e),
             }
+        }
+    }
 
-            thread::sleep(sleep_duration);
+    if let Some((path, mut writer)) = session.trace {
+        let written = writer.write(&records).and_then(|()| writer.flush());
+        if let Err(e) = written {
+            message!("ERROR: cannot write trace {}: {}.", path, e);
         }
     }
+
+    if let Some((path, movie)) = session.record {
+        save_movie(&path, &movie);
+    }
+}
+
+fn save_movie(path: &str, movie: &Movie) {
+    match movie.save(Path::new(path)) {
+        Ok(()) => message!("Recorded {} frames to {}.", movie.frames.len(), path),
+        Err(e) => message!("ERROR: cannot save movie {}: {}.", path, e),
+    }
+}
+
+fn window_title(rom_title: &str, paused: bool, slow_motion: bool) -> String {
+    let mut title = rom_title.to_string();
+    if paused {
+        title.push_str(" - PAUSED");
+    }
+    if slow_motion {
+        title.push_str(" - SLOW");
+    }
+    title
 }
@@ -1,65 +1,124 @@
 use sdl2;
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{ Keycode, Mod };
+
+use crate::keypad::KeyEvent;
+
+/// Frontend actions requested from the keyboard, outside of the CHIP8 keypad.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    SaveState(u8),  // Shift + F1..F10
+    LoadState(u8),  // F1..F10
+    Pause,          // P
+    FrameAdvance,   // N, runs a single frame and pauses
+    SlowMotion,     // M, toggles slow motion
+    Break,          // F12, stops in the debugger
+}
 
 pub struct Events {
     events: sdl2::EventPump,
     pub should_run: bool,
+    pub hotkeys: Vec<Hotkey>,   // Pressed since the last poll
+    pub rewind: bool,           // Backspace held
+    pub turbo: bool,            // Tab held
 }
 
 impl Events {
     pub fn new(sdl_context: &sdl2::Sdl) -> Self {
-        Events { events: sdl_context.event_pump().unwrap(), should_run: true }
+        Events { events: sdl_context.event_pump().unwrap(), should_run: true, hotkeys: Vec::new(), rewind: false, turbo: false }
     }
 
-    pub fn poll(&mut self) -> Result<[bool; 16], ()> {
+    /// Returns the CHIP8 key events since the last poll, or `None` when the window was closed.
+    pub fn poll(&mut self) -> Option<Vec<KeyEvent>> {
+        self.hotkeys.clear();
+
+        let mut key_events = Vec::new();
 
         for event in self.events.poll_iter() {
             match event {
                 Event::Quit { .. } => {
-                    return Err(());
+                    return None;
                 }
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     self.should_run = false;
                 }
+                Event::KeyDown { keycode: Some(key), keymod, repeat: false, .. } => {
+                    if let Some(chip8_key) = chip8_key(key) {
+                        key_events.push(KeyEvent::Down(chip8_key));
+                    }
+                    else if let Some(slot) = state_slot(key) {
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            self.hotkeys.push(Hotkey::SaveState(slot));
+                        } else {
+                            self.hotkeys.push(Hotkey::LoadState(slot));
+                        }
+                    }
+                    else {
+                        match key {
+                            Keycode::P => self.hotkeys.push(Hotkey::Pause),
+                            Keycode::N => self.hotkeys.push(Hotkey::FrameAdvance),
+                            Keycode::M => self.hotkeys.push(Hotkey::SlowMotion),
+                            Keycode::F12 => self.hotkeys.push(Hotkey::Break),
+                            _ => {}
+                        }
+                    }
+                }
+                Event::KeyUp { keycode: Some(key), repeat: false, .. } => {
+                    if let Some(chip8_key) = chip8_key(key) {
+                        key_events.push(KeyEvent::Up(chip8_key));
+                    }
+                }
                 _ => {}
             }
         }
 
-        let keys: Vec<Keycode> = self.events
+        let held: Vec<Keycode> = self.events
             .keyboard_state()
             .pressed_scancodes()
             .filter_map(Keycode::from_scancode)
             .collect();
 
-        let mut chip8_keys = [false; 16];
+        self.rewind = held.contains(&Keycode::Backspace);
+        self.turbo = held.contains(&Keycode::Tab);
 
-        for key in keys {
-            let index = match key {
-                Keycode::Num1 => Some(0x1),
-                Keycode::Num2 => Some(0x2),
-                Keycode::Num3 => Some(0x3),
-                Keycode::Num4 => Some(0xc),
-                Keycode::Q => Some(0x4),
-                Keycode::W => Some(0x5),
-                Keycode::E => Some(0x6),
-                Keycode::R => Some(0xd),
-                Keycode::A => Some(0x7),
-                Keycode::S => Some(0x8),
-                Keycode::D => Some(0x9),
-                Keycode::F => Some(0xe),
-                Keycode::Z => Some(0xa),
-                Keycode::X => Some(0x0),
-                Keycode::C => Some(0xb),
-                Keycode::V => Some(0xf),
-                _ => None,
-            };
+        Some(key_events)
+    }
+}
 
-            if let Some(i) = index {
-                chip8_keys[i] = true;
-            }
-        }
+fn chip8_key(key: Keycode) -> Option<u8> {
+    match key {
+        Keycode::Num1 => Some(0x1),
+        Keycode::Num2 => Some(0x2),
+        Keycode::Num3 => Some(0x3),
+        Keycode::Num4 => Some(0xc),
+        Keycode::Q => Some(0x4),
+        Keycode::W => Some(0x5),
+        Keycode::E => Some(0x6),
+        Keycode::R => Some(0xd),
+        Keycode::A => Some(0x7),
+        Keycode::S => Some(0x8),
+        Keycode::D => Some(0x9),
+        Keycode::F => Some(0xe),
+        Keycode::Z => Some(0xa),
+        Keycode::X => Some(0x0),
+        Keycode::C => Some(0xb),
+        Keycode::V => Some(0xf),
+        _ => None,
+    }
+}
 
-        Ok(chip8_keys)
+fn state_slot(key: Keycode) -> Option<u8> {
+    match key {
+        Keycode::F1 => Some(1),
+        Keycode::F2 => Some(2),
+        Keycode::F3 => Some(3),
+        Keycode::F4 => Some(4),
+        Keycode::F5 => Some(5),
+        Keycode::F6 => Some(6),
+        Keycode::F7 => Some(7),
+        Keycode::F8 => Some(8),
+        Keycode::F9 => Some(9),
+        Keycode::F10 => Some(10),
+        _ => None,
     }
 }
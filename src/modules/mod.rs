@@ -3,5 +3,5 @@ mod events;
 mod sound;
 
 pub use self::screen::Screen;
-pub use self::events::Events;
+pub use self::events::{ Events, Hotkey };
 pub use self::sound::Sound;
@@ -7,8 +7,8 @@ use sdl2::video::Window;
 use crate::machine::*;
 
 const SCALE_FACTOR: u32 = 10;
-const SCREEN_WIDTH: u32 = (VRAM_WIDTH as u32) * SCALE_FACTOR;
-const SCREEN_HEIGHT: u32 = (VRAM_HEIGHT as u32) * SCALE_FACTOR;
+const SCREEN_WIDTH: u32 = (LORES_WIDTH as u32) * SCALE_FACTOR;
+const SCREEN_HEIGHT: u32 = (LORES_HEIGHT as u32) * SCALE_FACTOR;
 
 pub struct Screen {
     canvas: Canvas<Window>,
@@ -34,18 +34,23 @@ impl Screen {
         canvas.clear();
         canvas.present();
 
-        Screen { canvas: canvas }
+        Screen { canvas }
     }
 
-    pub fn draw(&mut self, pixels: &[[u8; 64]; 32]) {
-        for (y, row) in pixels.iter().enumerate() {
-            for (x, &col) in row.iter().enumerate() {
-                let x = (x as u32) * SCALE_FACTOR;
-                let y = (y as u32) * SCALE_FACTOR;
+    /*
+     * Draws the top left width x height pixels of the screen array,
+     * scaled to fill the window (64x32 or 128x64).
+     */
+    pub fn draw(&mut self, pixels: &[[u8; VRAM_WIDTH]; VRAM_HEIGHT], width: usize, height: usize) {
+        let scale = SCREEN_WIDTH / (width as u32);
+        for (y, row) in pixels.iter().take(height).enumerate() {
+            for (x, &col) in row.iter().take(width).enumerate() {
+                let x = (x as u32) * scale;
+                let y = (y as u32) * scale;
 
                 self.canvas.set_draw_color(color(col));
                 let _ = self.canvas
-                    .fill_rect(Rect::new(x as i32, y as i32, SCALE_FACTOR, SCALE_FACTOR));
+                    .fill_rect(Rect::new(x as i32, y as i32, scale, scale));
             }
         }
         self.canvas.present();
@@ -61,10 +66,12 @@ impl Screen {
     }
 }
 
+// The pixel value holds one bit per XO-CHIP plane, giving four colours.
 fn color(value: u8) -> pixels::Color {
-    if value == 0 {
-        pixels::Color::RGB(201, 171, 142)
-    } else {
-        pixels::Color::RGB(41, 30, 19)
+    match value {
+        0 => pixels::Color::RGB(201, 171, 142),
+        1 => pixels::Color::RGB(41, 30, 19),
+        2 => pixels::Color::RGB(150, 95, 58),
+        _ => pixels::Color::RGB(95, 62, 34),
     }
 }
@@ -2,7 +2,8 @@ use sdl2;
 use sdl2::audio::{AudioDevice, AudioCallback, AudioSpecDesired};
 
 pub struct Sound {
-    device: AudioDevice<SquareWave>,
+    device: AudioDevice<Beeper>,
+    pattern: Option<([u8; 16], u8)>,
 }
 
 impl Sound {
@@ -21,15 +22,17 @@ impl Sound {
                 //println!("{:?}", spec);
 
                 // initialize the audio callback
-                SquareWave {
+                Beeper {
+                    freq: spec.freq as f32,
                     phase_inc: 240.0 / spec.freq as f32,
                     phase: 0.0,
                     volume: 0.25,
+                    pattern: None,
                 }
             })
             .unwrap();
 
-        Sound { device: device }
+        Sound { device, pattern: None }
     }
 
     pub fn start_beep(&self) {
@@ -38,21 +41,57 @@ impl Sound {
     pub fn stop_beep(&self) {
         self.device.pause();
     }
+
+    /*
+     * Plays the XO-CHIP 128 bit audio pattern at the given pitch instead
+     * of the square wave, or goes back to the square wave with None.
+     */
+    pub fn set_pattern(&mut self, pattern: Option<&[u8; 16]>, pitch: u8) {
+        let pattern = pattern.map(|pattern| (*pattern, pitch));
+        if pattern == self.pattern {
+            return;
+        }
+        self.pattern = pattern;
+
+        let mut beeper = self.device.lock();
+        match pattern {
+            Some((pattern, pitch)) => {
+                // XO-CHIP playback rate, in bits per second
+                let rate = 4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0);
+                beeper.phase_inc = rate / 128.0 / beeper.freq;
+                beeper.pattern = Some(pattern);
+            }
+            None => {
+                beeper.phase_inc = 240.0 / beeper.freq;
+                beeper.pattern = None;
+            }
+        }
+    }
 }
 
-struct SquareWave {
+struct Beeper {
+    freq: f32,
     phase_inc: f32,
     phase: f32,
     volume: f32,
+    pattern: Option<[u8; 16]>,
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for Beeper {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
         for x in out.iter_mut() {
-            *x = self.volume * if self.phase < 0.5 { 1.0 } else { -1.0 };
+            let high = match self.pattern {
+                // Play the pattern bits, most significant first
+                Some(ref pattern) => {
+                    let bit = (self.phase * 128.0) as usize % 128;
+                    (pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1
+                }
+                // Generate a square wave
+                None => self.phase < 0.5,
+            };
+            *x = self.volume * if high { 1.0 } else { -1.0 };
             self.phase = (self.phase + self.phase_inc) % 1.0;
         }
     }
@@ -0,0 +1,302 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::keypad::KeyEvent;
+use crate::machine::{ Machine, MachineFault };
+use crate::quirks::{ Platform, Quirks };
+use crate::random::Generator;
+use crate::state::{ crc32, generator_from_id, generator_id, platform_from_id, platform_id, quirks_bits, quirks_from_bits };
+
+/*
+ * Movie file layout, all numbers little endian:
+ *
+ *   magic "EMU8MOVIE" | version u16 | ROM hash u64 | platform u8 | quirks u16 |
+ *   seed u64 | generator u8 | cycles per frame u64 | frame count u32 |
+ *   frames | CRC-32 u32
+ *
+ * Each frame is an event count u8 followed by the key events pushed before
+ * it, one byte each: the key in the low nibble and bit 7 set for a release.
+ * A movie replays from a freshly loaded machine with the recorded platform,
+ * quirks, seed and RND generator. The ROM hash is taken from the memory
+ * right after loading, so it covers the fonts too. The platform and quirks
+ * are stored as in save states.
+ */
+pub const MOVIE_VERSION: u16 = 4;
+
+// Key events a frame can hold, its count is a byte.
+const MAX_FRAME_EVENTS: usize = 255;
+
+const MAGIC: &[u8; 9] = b"EMU8MOVIE";
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    ChecksumMismatch,
+    Invalid(&'static str),
+    TooManyEvents(usize),                   // Key events of a frame, more than can be recorded
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::Io(ref e) => write!(f, "{}", e),
+            MovieError::BadMagic => write!(f, "not an emu8 movie"),
+            MovieError::UnsupportedVersion(version) =>
+                write!(f, "movie version {} is not supported (expected {})", version, MOVIE_VERSION),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::ChecksumMismatch => write!(f, "movie is corrupted (checksum mismatch)"),
+            MovieError::Invalid(what) => write!(f, "movie has an invalid {}", what),
+            MovieError::TooManyEvents(count) =>
+                write!(f, "a frame has {} key events, at most {} can be recorded", count, MAX_FRAME_EVENTS),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> Self {
+        MovieError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub seed: u64,
+    pub generator: Generator,
+    pub cycles_per_frame: usize,
+    pub frames: Vec<Vec<KeyEvent>>,
+}
+
+impl Movie {
+    /// An empty movie starting from the current state of a freshly loaded machine.
+    pub fn new(machine: &Machine) -> Self {
+        Movie {
+            rom_hash: rom_hash(machine.memory()),
+            platform: machine.platform(),
+            quirks: machine.quirks(),
+            seed: machine.seed(),
+            generator: machine.generator(),
+            cycles_per_frame: machine.instructions_per_frame(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Whether the movie was recorded with the ROM, platform and quirks of this machine.
+    pub fn matches(&self, machine: &Machine) -> bool {
+        self.rom_hash == rom_hash(machine.memory()) && self.platform == machine.platform() && self.quirks == machine.quirks()
+    }
+
+    /// Records the key events pushed before a frame, a frame with too many is refused.
+    pub fn record(&mut self, events: &[KeyEvent]) -> Result<(), MovieError> {
+        if events.len() > MAX_FRAME_EVENTS {
+            return Err(MovieError::TooManyEvents(events.len()));
+        }
+        self.frames.push(events.to_vec());
+        Ok(())
+    }
+
+    /// The key events of a recorded frame.
+    pub fn frame(&self, frame: usize) -> Option<&[KeyEvent]> {
+        self.frames.get(frame).map(|events| events.as_slice())
+    }
+
+    /// Runs the whole movie on a freshly loaded machine.
+    pub fn replay(&self, machine: &mut Machine) -> Result<(), MachineFault> {
+        machine.set_seed(self.seed);
+        machine.set_generator(self.generator);
+        machine.set_instructions_per_frame(self.cycles_per_frame);
+        for events in &self.frames {
+            for &event in events {
+                machine.push_key(event);
+            }
+            machine.run_frame()?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.frames.len() + 48);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&MOVIE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.rom_hash.to_le_bytes());
+        bytes.push(platform_id(self.platform));
+        bytes.extend_from_slice(&quirks_bits(self.quirks).to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.push(generator_id(self.generator));
+        bytes.extend_from_slice(&(self.cycles_per_frame as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        for events in &self.frames {
+            bytes.push(events.len() as u8);
+            bytes.extend(events.iter().map(|&event| match event {
+                KeyEvent::Down(_) => event.key(),
+                KeyEvent::Up(_) => 0x80 | event.key(),
+            }));
+        }
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MovieError> {
+        const HEADER: usize = 9 + 2 + 8 + 1 + 2 + 8 + 1 + 8 + 4;
+
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        if bytes.len() < HEADER {
+            return Err(MovieError::Truncated);
+        }
+        let number = |at: usize, size: usize| {
+            let mut word = [0; 8];
+            word[..size].copy_from_slice(&bytes[at..at + size]);
+            u64::from_le_bytes(word)
+        };
+
+        let version = number(9, 2) as u16;
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let rom_hash = number(11, 8);
+        let platform = platform_from_id(bytes[19]).ok_or(MovieError::Invalid("platform"))?;
+        let quirks = quirks_from_bits(number(20, 2) as u16);
+        let seed = number(22, 8);
+        let generator = generator_from_id(bytes[30]).ok_or(MovieError::Invalid("random generator"))?;
+        let cycles_per_frame = number(31, 8) as usize;
+        if cycles_per_frame == 0 {
+            return Err(MovieError::Invalid("instructions per frame"));
+        }
+        let count = number(39, 4) as usize;
+
+        let mut frames = Vec::with_capacity(count.min(bytes.len()));
+        let mut end = HEADER;
+        for _ in 0..count {
+            let length = *bytes.get(end).ok_or(MovieError::Truncated)? as usize;
+            let events = bytes.get(end + 1..end + 1 + length).ok_or(MovieError::Truncated)?;
+            frames.push(events.iter().map(|&byte| {
+                if byte & 0x80 != 0 { KeyEvent::Up(byte & 0x0F) } else { KeyEvent::Down(byte & 0x0F) }
+            }).collect());
+            end += 1 + length;
+        }
+        if bytes.len() < end + 4 {
+            return Err(MovieError::Truncated);
+        }
+        if crc32(&bytes[..end]) != number(end, 4) as u32 {
+            return Err(MovieError::ChecksumMismatch);
+        }
+
+        Ok(Movie { rom_hash, platform, quirks, seed, generator, cycles_per_frame, frames })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), MovieError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, MovieError> {
+        Movie::from_bytes(&fs::read(path)?)
+    }
+}
+
+// 64-bit FNV-1a.
+fn rom_hash(memory: &[u8]) -> u64 {
+    memory.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Draws random sprites where the pressed key says, so the final screen depends on every input.
+    const ROM: [u8; 20] = [
+        0x60, 0x00, 0xF0, 0x0A, 0xC1, 0x1F, 0xF0, 0x29, 0xD0, 0x15, 0x70, 0x01, 0xE0, 0x9E, 0x12, 0x02,
+        0x12, 0x06, 0x00, 0x00,
+    ];
+
+    fn machine() -> Machine {
+        let mut machine = Machine::new(Platform::CosmacVip, Platform::CosmacVip.quirks());
+        machine.load(&ROM).unwrap();
+        machine.set_seed(7);
+        machine
+    }
+
+    fn input(frame: usize) -> Vec<KeyEvent> {
+        match frame % 6 {
+            0 => vec![KeyEvent::Down(frame as u8 % 16)],
+            3 => vec![KeyEvent::Up((frame - 3) as u8 % 16)],
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn replay_reaches_the_recorded_state() {
+        let mut recorded = machine();
+        let mut movie = Movie::new(&recorded);
+        for frame in 0..120 {
+            let events = input(frame);
+            movie.record(&events).unwrap();
+            for &event in &events {
+                recorded.push_key(event);
+            }
+            recorded.run_frame().unwrap();
+        }
+
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        let mut replayed = Machine::new(Platform::CosmacVip, Platform::CosmacVip.quirks());
+        replayed.load(&ROM).unwrap();
+        assert!(movie.matches(&replayed));
+        movie.replay(&mut replayed).unwrap();
+        assert!(replayed.snapshot() == recorded.snapshot());
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut movie = Movie::new(&machine());
+        for frame in 0..10 {
+            movie.record(&input(frame)).unwrap();
+        }
+        let bytes = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&bytes).unwrap(), movie);
+
+        let mut corrupted = bytes.clone();
+        corrupted[20] ^= 1;
+        assert!(Movie::from_bytes(&corrupted).is_err());
+        assert!(Movie::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn other_rom_platform_or_quirks_do_not_match() {
+        let movie = Movie::new(&machine());
+
+        let mut other_rom = machine();
+        other_rom.poke(0x200 + ROM.len(), 1).unwrap();
+        assert!(!movie.matches(&other_rom));
+
+        let mut other_platform = Machine::new(Platform::Chip48, Platform::CosmacVip.quirks());
+        other_platform.load(&ROM).unwrap();
+        assert!(!movie.matches(&other_platform));
+
+        let mut quirks = Quirks::cosmac_vip();
+        quirks.set("clip", false);
+        let mut other_quirks = Machine::new(Platform::CosmacVip, quirks);
+        other_quirks.load(&ROM).unwrap();
+        assert!(!movie.matches(&other_quirks));
+    }
+
+    #[test]
+    fn frames_with_too_many_events_are_refused() {
+        let mut movie = Movie::new(&machine());
+        assert!(movie.record(&vec![KeyEvent::Down(1); MAX_FRAME_EVENTS]).is_ok());
+        assert!(matches!(movie.record(&vec![KeyEvent::Down(1); MAX_FRAME_EVENTS + 1]), Err(MovieError::TooManyEvents(_))));
+        assert_eq!(movie.frames.len(), 1);
+    }
+}
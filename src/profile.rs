@@ -0,0 +1,178 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::instruction::{ Instruction, CLASS_NAMES };
+
+// The subroutine of the instructions run outside any CALL.
+const MAIN: usize = usize::MAX;
+
+/*
+ * Where the cycles of a ROM go, see `Machine::set_profiling`. Instructions
+ * are counted per address, per class and per subroutine: the subroutine is
+ * the target of the CALL before the return address on top of the stack, so
+ * the counts stay right across state loads and rewinds. Self counts go to
+ * the innermost subroutine, total counts to every subroutine on the stack.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub addresses: BTreeMap<usize, u64>,    // Instructions run at each address
+    pub classes: [u64; CLASS_NAMES.len()],  // Instructions run of each class
+    pub invalid: u64,                       // Opcodes that did not decode, XO-CHIP data for instance
+    pub subroutines: BTreeMap<usize, (u64, u64)>,  // Self and total instructions by entry address
+    pub instructions: u64,
+    pub idle: u64,                          // Cycles waiting for the display or a key
+    pub frames: u64,
+    pub frame_min: u64,                     // Instructions run in a single frame
+    pub frame_max: u64,
+    frame_instructions: u64,                // In the current frame
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile { frame_min: u64::MAX, ..Profile::default() }
+    }
+
+    pub fn record(&mut self, pc: usize, opcode: u16, stack: &[usize], memory: &[u8]) {
+        self.instructions += 1;
+        self.frame_instructions += 1;
+        *self.addresses.entry(pc).or_insert(0) += 1;
+        match Instruction::decode(opcode) {
+            Some(instruction) => {
+                let class = CLASS_NAMES.iter().position(|&name| name == instruction.class()).unwrap_or(0);
+                self.classes[class] += 1;
+            }
+            None => self.invalid += 1,
+        }
+
+        // Subroutines from the outermost, every one counted once in the totals.
+        let mut chain = [MAIN; 17];
+        let mut depth = 1;
+        for &ret in stack {
+            let entry = match memory.get(ret.wrapping_sub(2)..ret).and_then(|bytes| Instruction::decode((bytes[0] as u16) << 8 | bytes[1] as u16)) {
+                Some(Instruction::Call(addr)) => addr as usize,
+                _ => ret,
+            };
+            if depth < chain.len() {
+                chain[depth] = entry;
+                depth += 1;
+            }
+        }
+        for (n, &entry) in chain[..depth].iter().enumerate() {
+            if !chain[..n].contains(&entry) {
+                self.subroutines.entry(entry).or_insert((0, 0)).1 += 1;
+            }
+        }
+        self.subroutines.entry(chain[depth - 1]).or_insert((0, 0)).0 += 1;
+    }
+
+    pub fn record_idle(&mut self) {
+        self.idle += 1;
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        self.frame_min = self.frame_min.min(self.frame_instructions);
+        self.frame_max = self.frame_max.max(self.frame_instructions);
+        self.frame_instructions = 0;
+    }
+
+    /*
+     * Text report of the `top` hottest addresses and subroutines, the opcode
+     * classes and the instructions per frame. `symbolize` names addresses,
+     * with the labels of an Octo source for instance.
+     */
+    pub fn report(&self, memory: &[u8], top: usize, symbolize: &dyn Fn(usize) -> Option<String>) -> String {
+        let percent = |count: u64| 100.0 * count as f64 / self.instructions.max(1) as f64;
+        let name = |addr: usize| match symbolize(addr) {
+            Some(label) => format!("{:03X} <{}>", addr, label),
+            None => format!("{:03X}", addr),
+        };
+        let mut out = String::new();
+
+        let cycles = self.instructions + self.idle;
+        let _ = writeln!(out, "Profile: {} instructions in {} frames, {:.1} per frame (min {}, max {}), {:.1}% of the cycles idle.",
+            self.instructions, self.frames,
+            self.instructions as f64 / self.frames.max(1) as f64,
+            if self.frames > 0 { self.frame_min } else { 0 }, self.frame_max,
+            100.0 * self.idle as f64 / cycles.max(1) as f64);
+
+        let mut addresses: Vec<(&usize, &u64)> = self.addresses.iter().collect();
+        addresses.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let _ = writeln!(out, "\nHot spots:\n  instructions       %  address");
+        for (&addr, &count) in addresses.into_iter().take(top) {
+            let instruction = match Instruction::decode_at(memory, addr) {
+                Some(instruction) => instruction.to_string(),
+                None => "??".to_string(),
+            };
+            let _ = writeln!(out, "  {:>12}  {:>5.1}%  {:<24}  {}", count, percent(count), name(addr), instruction);
+        }
+
+        let mut subroutines: Vec<(&usize, &(u64, u64))> = self.subroutines.iter().collect();
+        subroutines.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(b.0)));
+        let _ = writeln!(out, "\nSubroutines:\n          self       %         total       %  entry");
+        for (&entry, &(own, total)) in subroutines.into_iter().take(top) {
+            let entry = if entry == MAIN { "main".to_string() } else { name(entry) };
+            let _ = writeln!(out, "  {:>12}  {:>5.1}%  {:>12}  {:>5.1}%  {}", own, percent(own), total, percent(total), entry);
+        }
+
+        let mut classes: Vec<(&str, u64)> = CLASS_NAMES.iter().cloned().zip(self.classes.iter().cloned()).collect();
+        if self.invalid > 0 {
+            classes.push(("invalid", self.invalid));
+        }
+        classes.sort_by_key(|&(_, count)| Reverse(count));
+        let _ = writeln!(out, "\nOpcode classes:");
+        for (class, count) in classes.into_iter().filter(|&(_, count)| count > 0) {
+            let _ = writeln!(out, "  {:>12}  {:>5.1}%  {}", count, percent(count), class);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subroutines_from_the_stack() {
+        // CALL 300 at 200, CALL 400 at 302, and 400 calls itself at 402.
+        let mut memory = vec![0; 0x1000];
+        memory[0x200..0x202].copy_from_slice(&[0x23, 0x00]);
+        memory[0x302..0x304].copy_from_slice(&[0x24, 0x00]);
+        memory[0x402..0x404].copy_from_slice(&[0x24, 0x00]);
+
+        let mut profile = Profile::new();
+        profile.record(0x200, 0x2300, &[], &memory);
+        profile.record(0x300, 0x6001, &[0x202], &memory);
+        profile.record(0x400, 0x6001, &[0x202, 0x304], &memory);
+        profile.record(0x400, 0x00EE, &[0x202, 0x304, 0x404], &memory);
+        // A return address without a CALL before it, after poking memory.
+        profile.record(0x500, 0x00EE, &[0x502], &memory);
+
+        assert_eq!(profile.subroutines.get(&MAIN), Some(&(1, 5)));
+        assert_eq!(profile.subroutines.get(&0x300), Some(&(1, 3)));
+        assert_eq!(profile.subroutines.get(&0x400), Some(&(2, 2)));
+        assert_eq!(profile.subroutines.get(&0x502), Some(&(1, 1)));
+        assert_eq!(profile.addresses.get(&0x400), Some(&2));
+        assert_eq!(profile.instructions, 5);
+    }
+
+    #[test]
+    fn frames_and_classes() {
+        let memory = vec![0; 0x1000];
+        let mut profile = Profile::new();
+        for instructions in [3, 1, 2].iter() {
+            for _ in 0..*instructions {
+                profile.record(0x200, 0xD015, &[], &memory);
+            }
+            profile.end_frame();
+        }
+        profile.record(0x202, 0x5001, &[], &memory);
+        profile.record_idle();
+
+        assert_eq!((profile.frames, profile.frame_min, profile.frame_max), (3, 1, 3));
+        let draw = CLASS_NAMES.iter().position(|&name| name == Instruction::Drw { x: 0, y: 1, n: 5 }.class()).unwrap();
+        assert_eq!(profile.classes[draw], 6);
+        assert_eq!((profile.invalid, profile.idle), (1, 1));
+    }
+}
@@ -0,0 +1,231 @@
+/*
+ * Several CHIP8 instructions behave differently depending on the
+ * interpreter a ROM was written for. The Quirks struct selects one
+ * interpretation for each of them, and Platform provides the presets
+ * of the most common interpreters.
+ */
+
+use crate::machine::{ MEMSIZ, XO_MEMSIZ };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    pub shift_uses_vy: bool,            // 8xy6/8xyE: v[x] = v[y] before shifting
+    pub load_store_increments_i: bool,  // Fx55/Fx65: I += x + 1 after the transfer
+    pub jump_uses_vx: bool,             // Bnnn: pc = v[x] + nnn instead of v[0] + nnn
+    pub vf_reset: bool,                 // 8xy1/8xy2/8xy3: v[F] = 0 after the operation
+    pub clip_sprites: bool,             // Dxyn: clip sprites at the screen edges instead of wrapping
+    pub display_wait: bool,             // Dxyn: wait for the next frame after drawing
+    pub i_overflow_flag: bool,          // Fx1E: v[F] = 1 when I goes past 0xF00
+    pub key_release: bool,              // Fx0A: wait for the key to be released, not just pressed
+}
+
+/// Names accepted by `Quirks::set`, in the same order as the struct fields.
+pub const QUIRK_NAMES: [&str; 8] = [
+    "shift",
+    "load-store",
+    "jump",
+    "vf-reset",
+    "clip",
+    "display-wait",
+    "i-overflow",
+    "key-release",
+];
+
+impl Default for Quirks {
+    /// The behaviour emu8 always had before quirks were configurable.
+    fn default() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+            i_overflow_flag: true,
+            key_release: true,
+        }
+    }
+}
+
+impl Quirks {
+    pub fn cosmac_vip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            vf_reset: true,
+            clip_sprites: true,
+            display_wait: true,
+            i_overflow_flag: false,
+            key_release: true,
+        }
+    }
+
+    /*
+     * CHIP-48 on the HP-48. SUPER-CHIP 1.1 was built on it and behaves the
+     * same for every quirk modelled here, so both presets share these
+     * values: what sets them apart are the SUPER-CHIP instructions, see
+     * `Platform::has_schip`.
+     */
+    pub fn chip48() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+            i_overflow_flag: false,
+            key_release: false,
+        }
+    }
+
+    /// SUPER-CHIP 1.1, the same quirks as CHIP-48.
+    pub fn super_chip() -> Self {
+        Quirks::chip48()
+    }
+
+    pub fn xo_chip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+            i_overflow_flag: false,
+            key_release: true,
+        }
+    }
+
+    /// Overrides a single quirk by name (see `QUIRK_NAMES`).
+    /// Returns false if the name is unknown.
+    pub fn set(&mut self, name: &str, value: bool) -> bool {
+        let quirk = match name {
+            "shift" => &mut self.shift_uses_vy,
+            "load-store" => &mut self.load_store_increments_i,
+            "jump" => &mut self.jump_uses_vx,
+            "vf-reset" => &mut self.vf_reset,
+            "clip" => &mut self.clip_sprites,
+            "display-wait" => &mut self.display_wait,
+            "i-overflow" => &mut self.i_overflow_flag,
+            "key-release" => &mut self.key_release,
+            _ => return false,
+        };
+        *quirk = value;
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Platform {
+    #[default]
+    Emu8,
+    CosmacVip,
+    Chip48,
+    SuperChip,
+    XoChip,
+}
+
+/// Names accepted by `Platform::from_name`.
+pub const PLATFORM_NAMES: [&str; 5] = ["emu8", "vip", "chip48", "schip", "xochip"];
+
+impl Platform {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "emu8" => Some(Platform::Emu8),
+            "vip" | "cosmac-vip" => Some(Platform::CosmacVip),
+            "chip48" | "chip-48" => Some(Platform::Chip48),
+            "schip" | "super-chip" => Some(Platform::SuperChip),
+            "xochip" | "xo-chip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    /// Whether the SUPER-CHIP 1.1 instructions are decoded.
+    pub fn has_schip(self) -> bool {
+        matches!(self, Platform::SuperChip | Platform::XoChip)
+    }
+
+    /// Whether the XO-CHIP instructions are decoded.
+    pub fn has_xo_chip(self) -> bool {
+        self == Platform::XoChip
+    }
+
+    /// Size of the memory bank available for the CPU.
+    pub fn memory_size(self) -> usize {
+        if self.has_xo_chip() { XO_MEMSIZ } else { MEMSIZ }
+    }
+
+    /// Instructions run per 60 Hz frame by default, close to the speed of the original interpreter.
+    pub fn instructions_per_frame(self) -> usize {
+        match self {
+            Platform::Emu8 => 10,
+            Platform::CosmacVip => 15,
+            Platform::Chip48 => 30,
+            Platform::SuperChip => 30,
+            Platform::XoChip => 1000,
+        }
+    }
+
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Emu8 => Quirks::default(),
+            Platform::CosmacVip => Quirks::cosmac_vip(),
+            Platform::Chip48 => Quirks::chip48(),
+            Platform::SuperChip => Quirks::super_chip(),
+            Platform::XoChip => Quirks::xo_chip(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_by_name() {
+        let presets = [
+            ("emu8", Quirks::default()),
+            ("vip", Quirks::cosmac_vip()),
+            ("chip48", Quirks::chip48()),
+            ("schip", Quirks::super_chip()),
+            ("xochip", Quirks::xo_chip()),
+        ];
+        for &(name, quirks) in presets.iter() {
+            assert!(PLATFORM_NAMES.contains(&name));
+            assert_eq!(Platform::from_name(name).map(Platform::quirks), Some(quirks), "{}", name);
+        }
+        assert_eq!(Platform::from_name("chip-8"), None);
+    }
+
+    #[test]
+    fn super_chip_shares_the_chip48_quirks() {
+        assert_eq!(Quirks::super_chip(), Quirks::chip48());
+        assert!(Platform::SuperChip.has_schip() && !Platform::Chip48.has_schip());
+    }
+
+    #[test]
+    fn quirks_set_by_name() {
+        let mut all = Quirks::cosmac_vip();
+        for &name in QUIRK_NAMES.iter() {
+            let (mut on, mut off) = (Quirks::default(), Quirks::default());
+            assert!(on.set(name, true) && off.set(name, false), "{}", name);
+            assert_ne!(on, off, "{}", name);
+            all.set(name, false);
+        }
+        assert!(!Quirks::default().set("wrap", true));
+
+        // Every field has a name.
+        assert_eq!(all, Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+            i_overflow_flag: false,
+            key_release: false,
+        });
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::VecDeque;
+
+use crate::clock::FRAMES_PER_SECOND;
+use crate::state::Snapshot;
+
+// Unchanged runs shorter than this are stored as changed bytes, a new run
+// header costs 8 bytes.
+const MIN_GAP: usize = 8;
+
+/*
+ * Ring buffer of the last frames for playing the game backwards, one
+ * snapshot per emulated frame.
+ *
+ * Only the newest state is kept whole. Every older frame is stored as a
+ * delta that turns the frame after it back into it, so dropping the oldest
+ * frame is just discarding its delta and stepping back is applying the
+ * newest one. A delta is a list of (unchanged bytes, changed bytes, data)
+ * runs over the serialized state, most frames only touch a few registers
+ * and a handful of screen bytes.
+ */
+pub struct Rewind {
+    capacity: usize,            // Frames kept, excluding the newest
+    latest: Option<Vec<u8>>,    // Serialized newest frame
+    deltas: VecDeque<Vec<u8>>,  // Newest at the back
+}
+
+impl Rewind {
+    pub fn new(seconds: usize) -> Self {
+        Rewind {
+            capacity: seconds * FRAMES_PER_SECOND as usize,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Frames that can be stepped back.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    /// Records the state of a new frame.
+    pub fn push(&mut self, snapshot: &Snapshot) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let payload = snapshot.payload();
+        if let Some(previous) = self.latest.take() {
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+            self.deltas.push_back(encode_delta(&payload, &previous));
+        }
+        self.latest = Some(payload);
+    }
+
+    /// Steps one frame back and returns its state, `None` once the oldest frame is reached.
+    pub fn pop(&mut self) -> Option<Snapshot> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.as_mut()?;
+        apply_delta(latest, &delta);
+        Snapshot::from_payload(latest).ok()
+    }
+}
+
+// Runs of changed bytes that turn `from` into `to`.
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    delta.extend_from_slice(&(to.len() as u32).to_le_bytes());
+
+    // A state with another memory size (a loaded state of another platform) is stored whole.
+    if from.len() != to.len() {
+        push_run(&mut delta, 0, to);
+        return delta;
+    }
+
+    let mut position = 0;
+    let mut offset = 0;
+    while offset < to.len() {
+        if from[offset] == to[offset] {
+            offset += 1;
+            continue;
+        }
+
+        let start = offset;
+        let mut end = offset + 1;
+        let mut same = 0;
+        while end < to.len() && same < MIN_GAP {
+            if from[end] == to[end] {
+                same += 1;
+            } else {
+                same = 0;
+            }
+            end += 1;
+        }
+        end -= same;
+
+        push_run(&mut delta, start - position, &to[start..end]);
+        position = end;
+        offset = end;
+    }
+
+    delta
+}
+
+fn push_run(delta: &mut Vec<u8>, skip: usize, bytes: &[u8]) {
+    delta.extend_from_slice(&(skip as u32).to_le_bytes());
+    delta.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    delta.extend_from_slice(bytes);
+}
+
+fn apply_delta(state: &mut Vec<u8>, delta: &[u8]) {
+    let word = |at: usize| u32::from_le_bytes([delta[at], delta[at + 1], delta[at + 2], delta[at + 3]]) as usize;
+
+    state.resize(word(0), 0);
+
+    let mut at = 4;
+    let mut position = 0;
+    while at < delta.len() {
+        let skip = word(at);
+        let count = word(at + 4);
+        at += 8;
+        position += skip;
+        state[position..position + count].copy_from_slice(&delta[at..at + count]);
+        position += count;
+        at += count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::quirks::Platform;
+
+    // Counts in V0, draws its digit at a random row, forever.
+    const PROGRAM: [u8; 10] = [0x70, 0x01, 0xC1, 0x1F, 0xF0, 0x29, 0xD0, 0x15, 0x12, 0x00];
+
+    fn machine(platform: Platform) -> Machine {
+        let mut machine = Machine::new(platform, platform.quirks());
+        machine.load(&PROGRAM).unwrap();
+        machine.set_seed(7);
+        machine
+    }
+
+    #[test]
+    fn frames_come_back_newest_first() {
+        let mut rewind = Rewind::new(1);
+        let mut snapshots = Vec::new();
+
+        // The last frames switch to the larger XO-CHIP memory.
+        for &(platform, frames) in [(Platform::CosmacVip, 65), (Platform::XoChip, 5)].iter() {
+            let mut machine = machine(platform);
+            for _ in 0..frames {
+                machine.run_frame().unwrap();
+                let snapshot = machine.snapshot();
+                rewind.push(&snapshot);
+                snapshots.push(snapshot);
+            }
+        }
+        assert_eq!(rewind.len(), FRAMES_PER_SECOND as usize);
+
+        // The newest frame is the current one, the oldest were evicted.
+        snapshots.pop();
+        for expected in snapshots.iter().rev().take(rewind.len()) {
+            assert!(rewind.pop().as_ref() == Some(expected));
+        }
+        assert!(rewind.is_empty());
+        assert!(rewind.pop().is_none());
+    }
+
+    #[test]
+    fn deltas_only_store_changes() {
+        let from: Vec<u8> = (0..100).collect();
+        let mut to = from.clone();
+        to[10] = 0;
+        to[12] = 0;
+        to[90] = 0;
+        let delta = encode_delta(&from, &to);
+        assert_eq!(delta.len(), 4 + (8 + 3) + (8 + 1));
+
+        let mut state = from;
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, to);
+    }
+}
@@ -0,0 +1,543 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::machine::{ VRAM_WIDTH, VRAM_HEIGHT };
+use crate::quirks::{ Quirks, Platform };
+use crate::random::Generator;
+
+/*
+ * Save state file layout, all numbers little endian:
+ *
+ *   magic "EMU8STATE" | version u16 | payload length u32 | payload | CRC-32 u32
+ *
+ * The CRC-32 covers everything before it. STATE_VERSION is bumped whenever
+ * the payload changes, and loading reads every older version, giving the
+ * fields they lack the values of a machine that just started. Files with a
+ * newer version are rejected.
+ */
+pub const STATE_VERSION: u16 = 6;
+
+const MAGIC: &[u8; 9] = b"EMU8STATE";
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    ChecksumMismatch,
+    Invalid(&'static str),
+    CustomGenerator,                        // The machine has no custom RND source to restore
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::Io(ref e) => write!(f, "{}", e),
+            StateError::BadMagic => write!(f, "not an emu8 save state"),
+            StateError::UnsupportedVersion(version) =>
+                write!(f, "save state version {} is not supported (expected {})", version, STATE_VERSION),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::ChecksumMismatch => write!(f, "save state is corrupted (checksum mismatch)"),
+            StateError::Invalid(what) => write!(f, "save state has an invalid {}", what),
+            StateError::CustomGenerator =>
+                write!(f, "save state uses a custom random source, which must be set before loading it"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> Self {
+        StateError::Io(e)
+    }
+}
+
+/// Every piece of `Machine` state, see `Machine::snapshot` and `Machine::restore`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub platform: Platform,
+    pub quirks: Quirks,
+
+    pub ram: Vec<u8>,
+    pub vram: [[u8; VRAM_WIDTH]; VRAM_HEIGHT],
+    pub hires: bool,
+    pub plane: u8,
+
+    pub pc: usize,
+    pub sp: usize,
+    pub stack: [usize; 16],
+
+    pub v: [u8; 16],
+    pub i: usize,
+
+    pub dt: u8,
+    pub st: u8,
+
+    pub audio_pattern: Option<[u8; 16]>,
+    pub pitch: u8,
+
+    pub input_reg: usize,
+    pub wait_input: bool,
+    pub wait_key: Option<u8>,
+    pub wait_vblank: bool,
+    pub halted: bool,
+
+    pub rpl: [u8; 16],
+
+    pub keys_held: u16,                     // One bit per key, see `Keypad`
+    pub keys_latched: u16,
+
+    pub generator: Generator,
+    pub random_state: u64,
+
+    pub instructions_per_frame: usize,
+    pub frame_cycle: usize,
+    pub cycles: u64,
+    pub frames: u64,
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let payload = self.payload();
+
+        let mut bytes = Vec::with_capacity(payload.len() + 32);
+        bytes.extend_from_slice(MAGIC);
+        put_u16(&mut bytes, STATE_VERSION);
+        put_u32(&mut bytes, payload.len() as u32);
+        bytes.extend_from_slice(&payload);
+        let checksum = crc32(&bytes);
+        put_u32(&mut bytes, checksum);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StateError> {
+        let mut header = Reader { bytes, position: 0 };
+        if header.take(MAGIC.len())? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = header.u16()?;
+        if version == 0 || version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let length = header.u32()? as usize;
+        let end = header.position + length;
+        if bytes.len() < end + 4 {
+            return Err(StateError::Truncated);
+        }
+        let checksum = Reader { bytes, position: end }.u32()?;
+        if crc32(&bytes[..end]) != checksum {
+            return Err(StateError::ChecksumMismatch);
+        }
+
+        Snapshot::read_payload(&bytes[header.position..end], version)
+    }
+
+    // The state without the file header, also used by the rewind buffer.
+    pub(crate) fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(self.ram.len() + VRAM_WIDTH * VRAM_HEIGHT + 256);
+
+        payload.push(platform_id(self.platform));
+        put_u16(&mut payload, quirks_bits(self.quirks));
+
+        put_u32(&mut payload, self.ram.len() as u32);
+        payload.extend_from_slice(&self.ram);
+        for row in self.vram.iter() {
+            payload.extend_from_slice(row);
+        }
+        payload.push(self.hires as u8);
+        payload.push(self.plane);
+
+        put_u16(&mut payload, self.pc as u16);
+        payload.push(self.sp as u8);
+        for &addr in self.stack.iter() {
+            put_u16(&mut payload, addr as u16);
+        }
+
+        payload.extend_from_slice(&self.v);
+        put_u16(&mut payload, self.i as u16);
+
+        payload.push(self.dt);
+        payload.push(self.st);
+
+        payload.push(self.audio_pattern.is_some() as u8);
+        payload.extend_from_slice(&self.audio_pattern.unwrap_or([0; 16]));
+        payload.push(self.pitch);
+
+        payload.push(self.input_reg as u8);
+        payload.push(self.wait_input as u8);
+        payload.push(self.wait_key.unwrap_or(0xFF));
+        payload.push(self.wait_vblank as u8);
+        payload.push(self.halted as u8);
+
+        payload.extend_from_slice(&self.rpl);
+
+        put_u16(&mut payload, self.keys_held);
+        put_u16(&mut payload, self.keys_latched);
+
+        payload.push(generator_id(self.generator));
+        payload.extend_from_slice(&self.random_state.to_le_bytes());
+
+        put_u32(&mut payload, self.instructions_per_frame as u32);
+        put_u32(&mut payload, self.frame_cycle as u32);
+        payload.extend_from_slice(&self.cycles.to_le_bytes());
+        payload.extend_from_slice(&self.frames.to_le_bytes());
+
+        payload
+    }
+
+    pub(crate) fn from_payload(payload: &[u8]) -> Result<Self, StateError> {
+        Snapshot::read_payload(payload, STATE_VERSION)
+    }
+
+    // Reads the payload of a version, fields added since come from a new machine.
+    fn read_payload(payload: &[u8], version: u16) -> Result<Self, StateError> {
+        let mut r = Reader { bytes: payload, position: 0 };
+
+        let platform = platform_from_id(r.u8()?).ok_or(StateError::Invalid("platform"))?;
+        let mut quirks = quirks_from_bits(if version >= 5 { r.u16()? } else { r.u8()? as u16 });
+        if version < 3 {
+            quirks.key_release = platform.quirks().key_release;
+        }
+
+        let ram_len = r.u32()? as usize;
+        if ram_len != platform.memory_size() {
+            return Err(StateError::Invalid("memory size"));
+        }
+        let ram = r.take(ram_len)?.to_vec();
+        let mut vram = [[0; VRAM_WIDTH]; VRAM_HEIGHT];
+        for row in vram.iter_mut() {
+            row.copy_from_slice(r.take(VRAM_WIDTH)?);
+        }
+        let hires = r.bool()?;
+        let plane = r.u8()?;
+
+        let pc = r.u16()? as usize;
+        let sp = r.u8()? as usize;
+        if sp > 16 {
+            return Err(StateError::Invalid("stack pointer"));
+        }
+        let mut stack = [0; 16];
+        for addr in stack.iter_mut() {
+            *addr = r.u16()? as usize;
+        }
+
+        let mut v = [0; 16];
+        v.copy_from_slice(r.take(16)?);
+        let i = r.u16()? as usize;
+
+        let dt = r.u8()?;
+        let st = r.u8()?;
+
+        let has_pattern = r.bool()?;
+        let mut pattern = [0; 16];
+        pattern.copy_from_slice(r.take(16)?);
+        let audio_pattern = if has_pattern { Some(pattern) } else { None };
+        let pitch = r.u8()?;
+
+        let input_reg = r.u8()? as usize & 0x0F;
+        let wait_input = r.bool()?;
+        let wait_key = match if version >= 3 { r.u8()? } else { 0xFF } {
+            0xFF => None,
+            key => Some(key & 0x0F),
+        };
+        let wait_vblank = r.bool()?;
+        let halted = r.bool()?;
+
+        let mut rpl = [0; 16];
+        rpl.copy_from_slice(r.take(16)?);
+
+        let (keys_held, keys_latched) = if version >= 6 { (r.u16()?, r.u16()?) } else { (0, 0) };
+
+        let (generator, random_state) = if version >= 2 {
+            (generator_from_id(r.u8()?).ok_or(StateError::Invalid("random generator"))?, r.u64()?)
+        } else {
+            (Generator::default(), 0)
+        };
+
+        let (instructions_per_frame, frame_cycle, cycles, frames) = if version >= 4 {
+            (r.u32()? as usize, r.u32()? as usize, r.u64()?, r.u64()?)
+        } else {
+            (platform.instructions_per_frame(), 0, 0, 0)
+        };
+        if instructions_per_frame == 0 || frame_cycle >= instructions_per_frame {
+            return Err(StateError::Invalid("frame position"));
+        }
+
+        Ok(Snapshot {
+            platform, quirks, ram, vram, hires, plane, pc, sp, stack, v, i, dt, st,
+            audio_pattern, pitch, input_reg, wait_input, wait_key, wait_vblank, halted, rpl,
+            keys_held, keys_latched, generator, random_state, instructions_per_frame, frame_cycle, cycles, frames,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), StateError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, StateError> {
+        Snapshot::from_bytes(&fs::read(path)?)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        if self.position + count > self.bytes.len() {
+            return Err(StateError::Truncated);
+        }
+        let slice = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, StateError> {
+        let mut word = [0; 8];
+        word.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(word))
+    }
+}
+
+fn put_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn platform_id(platform: Platform) -> u8 {
+    match platform {
+        Platform::Emu8 => 0,
+        Platform::CosmacVip => 1,
+        Platform::Chip48 => 2,
+        Platform::SuperChip => 3,
+        Platform::XoChip => 4,
+    }
+}
+
+pub(crate) fn platform_from_id(id: u8) -> Option<Platform> {
+    match id {
+        0 => Some(Platform::Emu8),
+        1 => Some(Platform::CosmacVip),
+        2 => Some(Platform::Chip48),
+        3 => Some(Platform::SuperChip),
+        4 => Some(Platform::XoChip),
+        _ => None,
+    }
+}
+
+pub(crate) fn generator_id(generator: Generator) -> u8 {
+    match generator {
+        Generator::Xorshift => 0,
+        Generator::VipLike => 1,
+        Generator::Custom => 2,
+    }
+}
+
+pub(crate) fn generator_from_id(id: u8) -> Option<Generator> {
+    match id {
+        0 => Some(Generator::Xorshift),
+        1 => Some(Generator::VipLike),
+        2 => Some(Generator::Custom),
+        _ => None,
+    }
+}
+
+// One bit per quirk in the order of QUIRK_NAMES, with room for more.
+pub(crate) fn quirks_bits(quirks: Quirks) -> u16 {
+    (quirks.shift_uses_vy as u16)
+        | (quirks.load_store_increments_i as u16) << 1
+        | (quirks.jump_uses_vx as u16) << 2
+        | (quirks.vf_reset as u16) << 3
+        | (quirks.clip_sprites as u16) << 4
+        | (quirks.display_wait as u16) << 5
+        | (quirks.i_overflow_flag as u16) << 6
+        | (quirks.key_release as u16) << 7
+}
+
+pub(crate) fn quirks_from_bits(bits: u16) -> Quirks {
+    Quirks {
+        shift_uses_vy: bits & 1 != 0,
+        load_store_increments_i: bits & 1 << 1 != 0,
+        jump_uses_vx: bits & 1 << 2 != 0,
+        vf_reset: bits & 1 << 3 != 0,
+        clip_sprites: bits & 1 << 4 != 0,
+        display_wait: bits & 1 << 5 != 0,
+        i_overflow_flag: bits & 1 << 6 != 0,
+        key_release: bits & 1 << 7 != 0,
+    }
+}
+
+// CRC-32 (IEEE 802.3), bitwise since states are small.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::KeyEvent;
+    use crate::machine::Machine;
+
+    // A machine in the middle of an XO-CHIP program, with a key held.
+    fn running_machine() -> Machine {
+        let mut machine = Machine::new(Platform::XoChip, Platform::XoChip.quirks());
+        machine.load(&[0x00, 0xFF, 0x60, 0x05, 0xC1, 0xFF, 0xA2, 0x00, 0xD0, 0x15, 0x22, 0x0E, 0x12, 0x04, 0x00, 0xEE]).unwrap();
+        machine.set_seed(42);
+        machine.push_key(KeyEvent::Down(5));
+        for _ in 0..3 {
+            machine.run_frame().unwrap();
+        }
+        machine
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let snapshot = running_machine().snapshot();
+        let bytes = snapshot.to_bytes();
+        let loaded = Snapshot::from_bytes(&bytes).unwrap();
+        assert!(loaded == snapshot);
+        assert_eq!(loaded.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn restored_machine_runs_the_same() {
+        let mut machine = running_machine();
+        let snapshot = machine.snapshot();
+        let mut restored = Machine::new(Platform::default(), Quirks::default());
+        restored.restore(&Snapshot::from_bytes(&snapshot.to_bytes()).unwrap()).unwrap();
+        for _ in 0..5 {
+            machine.run_frame().unwrap();
+            restored.run_frame().unwrap();
+        }
+        assert!(restored.snapshot() == machine.snapshot());
+    }
+
+    #[test]
+    fn damaged_files_are_refused() {
+        let bytes = running_machine().snapshot().to_bytes();
+
+        let mut corrupted = bytes.clone();
+        corrupted[40] ^= 1;
+        assert!(matches!(Snapshot::from_bytes(&corrupted), Err(StateError::ChecksumMismatch)));
+        assert!(matches!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]), Err(StateError::Truncated)));
+        assert!(matches!(Snapshot::from_bytes(b"EMU8MOVIE"), Err(StateError::BadMagic)));
+
+        let mut newer = bytes;
+        newer[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(Snapshot::from_bytes(&newer), Err(StateError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn quirk_bits_round_trip() {
+        for &name in crate::quirks::QUIRK_NAMES.iter() {
+            let mut quirks = Quirks::default();
+            quirks.set(name, false);
+            assert_eq!(quirks_from_bits(quirks_bits(quirks)), quirks, "{}", name);
+            quirks.set(name, true);
+            assert_eq!(quirks_from_bits(quirks_bits(quirks)), quirks, "{}", name);
+        }
+    }
+
+    // The payload of an older version, made by dropping the fields it lacks.
+    fn old_payload(snapshot: &Snapshot, version: u16) -> Vec<u8> {
+        let current = snapshot.payload();
+        let end = current.len();
+        let (frames, generator, keys) = (end - 24, end - 33, end - 37);
+        let wait_key = 3 + 4 + snapshot.ram.len() + VRAM_WIDTH * VRAM_HEIGHT + 2 + 3 + 32 + 16 + 2 + 2 + 17 + 1 + 2;
+
+        let mut payload = vec![current[0]];
+        let bits = quirks_bits(snapshot.quirks);
+        if version >= 5 {
+            put_u16(&mut payload, bits);
+        } else {
+            // Before version 3, the 8th bit did not exist.
+            payload.push(if version >= 3 { bits as u8 } else { bits as u8 & 0x7F });
+        }
+        payload.extend_from_slice(&current[3..wait_key]);
+        if version >= 3 {
+            payload.push(current[wait_key]);
+        }
+        payload.extend_from_slice(&current[wait_key + 1..keys]);
+        if version >= 2 {
+            payload.extend_from_slice(&current[generator..frames]);
+        }
+        if version >= 4 {
+            payload.extend_from_slice(&current[frames..]);
+        }
+        payload
+    }
+
+    #[test]
+    fn older_versions_are_read() {
+        let mut snapshot = running_machine().snapshot();
+        snapshot.quirks.key_release = false;
+        snapshot.wait_key = Some(3);
+
+        for version in 1..STATE_VERSION {
+            let payload = old_payload(&snapshot, version);
+            let mut bytes = MAGIC.to_vec();
+            put_u16(&mut bytes, version);
+            put_u32(&mut bytes, payload.len() as u32);
+            bytes.extend_from_slice(&payload);
+            let checksum = crc32(&bytes);
+            put_u32(&mut bytes, checksum);
+
+            let mut expected = snapshot.clone();
+            expected.keys_held = 0;
+            expected.keys_latched = 0;
+            if version < 2 {
+                expected.generator = Generator::default();
+                expected.random_state = 0;
+            }
+            if version < 3 {
+                expected.quirks.key_release = Platform::XoChip.quirks().key_release;
+                expected.wait_key = None;
+            }
+            if version < 4 {
+                expected.instructions_per_frame = Platform::XoChip.instructions_per_frame();
+                expected.frame_cycle = 0;
+                expected.cycles = 0;
+                expected.frames = 0;
+            }
+            let loaded = Snapshot::from_bytes(&bytes).unwrap_or_else(|e| panic!("version {}: {}", version, e));
+            assert!(loaded == expected, "version {}", version);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::{ self, BufWriter, Write };
+use std::path::Path;
+
+use crate::instruction::Instruction;
+use crate::json::Json;
+use crate::machine::Register;
+
+/// An instruction run by the machine and its effects, see `Machine::set_tracing`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub cycle: u64,                         // Machine cycles before the instruction
+    pub frame: u64,
+    pub pc: usize,
+    pub opcode: u16,                        // The first word of F000 nnnn
+    pub registers: Vec<(Register, usize)>,  // Changed registers and their new value, pc excluded, I always for F000 nnnn
+    pub writes: Vec<(usize, u8)>,           // Memory writes, in order
+    pub next_pc: usize,
+}
+
+impl TraceRecord {
+    /// The instruction run, the address of F000 nnnn is the I it loaded.
+    pub fn instruction(&self) -> Option<Instruction> {
+        match Instruction::decode(self.opcode)? {
+            Instruction::LdILong(_) => {
+                let i = self.registers.iter().find(|&&(register, _)| register == Register::I);
+                Some(Instruction::LdILong(i.map(|&(_, value)| value as u16)))
+            }
+            instruction => Some(instruction),
+        }
+    }
+
+    /// The instruction in Cowgod's mnemonics, `??` for an invalid opcode.
+    pub fn mnemonic(&self) -> String {
+        self.instruction().map_or_else(|| "??".to_string(), |instruction| instruction.to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    Json,
+    Binary,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" | "jsonl" => Some(TraceFormat::Json),
+            "binary" | "bin" => Some(TraceFormat::Binary),
+            _ => None,
+        }
+    }
+}
+
+/// The records kept in a trace. Empty lists and `None` keep everything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub addresses: Vec<(usize, usize)>,     // Inclusive ranges of pc
+    pub classes: Vec<String>,               // Names of Instruction::class
+    pub frames: Option<(u64, u64)>,         // Inclusive range
+}
+
+impl TraceFilter {
+    pub fn matches(&self, record: &TraceRecord) -> bool {
+        if !self.addresses.is_empty() && !self.addresses.iter().any(|&(start, end)| record.pc >= start && record.pc <= end) {
+            return false;
+        }
+        if !self.classes.is_empty() {
+            match record.instruction() {
+                Some(instruction) if self.classes.iter().any(|class| class == instruction.class()) => {}
+                _ => return false,
+            }
+        }
+        match self.frames {
+            Some((start, end)) => record.frame >= start && record.frame <= end,
+            None => true,
+        }
+    }
+}
+
+/*
+ * Trace file writer. The JSON Lines format has one object per instruction:
+ *
+ *   {"cycle":120,"frame":12,"pc":522,"opcode":25089,"mnemonic":"LD V2, 01",
+ *    "registers":{"V2":1},"writes":[]}
+ *
+ * The binary format, numbers little endian, is
+ *
+ *   magic "EMU8TRACE" | version u16 | records
+ *
+ * where each record is cycle u64 | frame u64 | pc u16 | opcode u16 |
+ * register count u8 | (register u8, value u16)... | write count u8 |
+ * (address u16, byte u8)... Registers are numbered in the order of
+ * `Register::all`: V0-VF are 0 to 15, then I, PC, SP, DT and ST.
+ */
+pub const TRACE_VERSION: u16 = 1;
+
+const MAGIC: &[u8; 9] = b"EMU8TRACE";
+
+pub struct TraceWriter {
+    out: BufWriter<File>,
+    format: TraceFormat,
+    filter: TraceFilter,
+}
+
+impl TraceWriter {
+    pub fn create(path: &Path, format: TraceFormat, filter: TraceFilter) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        if format == TraceFormat::Binary {
+            out.write_all(MAGIC)?;
+            out.write_all(&TRACE_VERSION.to_le_bytes())?;
+        }
+        Ok(TraceWriter { out, format, filter })
+    }
+
+    /// Writes the records kept by the filter.
+    pub fn write(&mut self, records: &[TraceRecord]) -> io::Result<()> {
+        let filter = &self.filter;
+        for record in records.iter().filter(|record| filter.matches(record)) {
+            match self.format {
+                TraceFormat::Json => writeln!(self.out, "{}", to_json(record))?,
+                TraceFormat::Binary => write_binary(&mut self.out, record)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn to_json(record: &TraceRecord) -> Json {
+    let registers = record.registers.iter()
+        .map(|&(register, value)| (register.to_string(), value.into()))
+        .collect();
+    let writes = record.writes.iter()
+        .map(|&(addr, byte)| Json::from(vec![addr.into(), (byte as usize).into()]))
+        .collect::<Vec<Json>>();
+
+    Json::object(vec![
+        ("cycle", record.cycle.into()),
+        ("frame", record.frame.into()),
+        ("pc", record.pc.into()),
+        ("opcode", (record.opcode as usize).into()),
+        ("mnemonic", record.mnemonic().into()),
+        ("registers", Json::Object(registers)),
+        ("writes", writes.into()),
+    ])
+}
+
+fn write_binary(out: &mut dyn Write, record: &TraceRecord) -> io::Result<()> {
+    out.write_all(&record.cycle.to_le_bytes())?;
+    out.write_all(&record.frame.to_le_bytes())?;
+    out.write_all(&(record.pc as u16).to_le_bytes())?;
+    out.write_all(&record.opcode.to_le_bytes())?;
+
+    out.write_all(&[record.registers.len() as u8])?;
+    for &(register, value) in &record.registers {
+        out.write_all(&[register.index() as u8])?;
+        out.write_all(&(value as u16).to_le_bytes())?;
+    }
+
+    out.write_all(&[record.writes.len() as u8])?;
+    for &(addr, byte) in &record.writes {
+        out.write_all(&(addr as u16).to_le_bytes())?;
+        out.write_all(&[byte])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::quirks::Platform;
+
+    // The records of V5 = 123, I = 300, BCD V5, V5 += 1.
+    fn records() -> Vec<TraceRecord> {
+        let mut machine = Machine::new(Platform::CosmacVip, Platform::CosmacVip.quirks());
+        machine.load(&[0x65, 0x7B, 0xA3, 0x00, 0xF5, 0x33, 0x75, 0x01]).unwrap();
+        machine.set_tracing(true);
+        for _ in 0..4 {
+            machine.step().unwrap();
+        }
+        machine.take_trace()
+    }
+
+    // A little endian number of `count` bytes.
+    fn take(bytes: &mut &[u8], count: usize) -> u64 {
+        let (taken, rest) = bytes.split_at(count);
+        *bytes = rest;
+        taken.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64)
+    }
+
+    // Decodes the binary records, which do not store next_pc.
+    fn read_binary(mut bytes: &[u8]) -> Vec<TraceRecord> {
+        let bytes = &mut bytes;
+        let all = Register::all();
+        let mut records = Vec::new();
+        while !bytes.is_empty() {
+            let (cycle, frame, pc, opcode) = (take(bytes, 8), take(bytes, 8), take(bytes, 2) as usize, take(bytes, 2) as u16);
+            let registers = (0..take(bytes, 1)).map(|_| (all[take(bytes, 1) as usize], take(bytes, 2) as usize)).collect();
+            let writes = (0..take(bytes, 1)).map(|_| (take(bytes, 2) as usize, take(bytes, 1) as u8)).collect();
+            records.push(TraceRecord { cycle, frame, pc, opcode, registers, writes, next_pc: 0 });
+        }
+        records
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let records = records();
+        assert_eq!(records[2].writes, vec![(0x300, 1), (0x301, 2), (0x302, 3)]);
+
+        let mut bytes = Vec::new();
+        for record in &records {
+            write_binary(&mut bytes, record).unwrap();
+        }
+        let expected: Vec<TraceRecord> = records.into_iter().map(|record| TraceRecord { next_pc: 0, ..record }).collect();
+        assert_eq!(read_binary(&bytes), expected);
+    }
+
+    #[test]
+    fn json_lines() {
+        let records = records();
+        assert_eq!(to_json(&records[0]).to_string(),
+            r#"{"cycle":0,"frame":0,"pc":512,"opcode":25979,"mnemonic":"LD V5, 7B","registers":{"V5":123},"writes":[]}"#);
+        assert_eq!(to_json(&records[2]).get("writes").to_string(), "[[768,1],[769,2],[770,3]]");
+    }
+
+    #[test]
+    fn filters() {
+        let records = records();
+        let kept = |filter: TraceFilter| records.iter().filter(|record| filter.matches(record)).count();
+        assert_eq!(kept(TraceFilter::default()), 4);
+        assert_eq!(kept(TraceFilter { addresses: vec![(0x202, 0x204)], ..TraceFilter::default() }), 2);
+        assert_eq!(kept(TraceFilter { frames: Some((1, 2)), ..TraceFilter::default() }), 0);
+    }
+}